@@ -0,0 +1,69 @@
+use criterion::{
+	criterion_group,
+	criterion_main,
+	Criterion,
+	Throughput
+};
+
+use icmp::batch::{
+	send_batch,
+	RecvBatch,
+	BATCH_SIZE
+};
+
+use std::{
+	net::UdpSocket,
+	os::fd::AsRawFd
+};
+
+use socket2::SockAddr;
+
+/// Same size as an echo request with the default payload.
+const DATAGRAM: [u8; 72] = [0x5a; 72];
+
+/// A pair of udp sockets on localhost, so the comparison needs no raw socket privileges.
+fn sockets() -> (UdpSocket, UdpSocket) {
+	let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+	receiver.set_nonblocking(true).unwrap();
+	return (sender, receiver);
+}
+
+fn throughput(c: &mut Criterion) {
+	let mut group = c.benchmark_group("localhost");
+	group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+	
+	let (sender, receiver) = sockets();
+	let dest = receiver.local_addr().unwrap();
+	group.bench_function("send_to_recv_from", |b| b.iter(|| {
+		for _ in 0..BATCH_SIZE {
+			sender.send_to(&DATAGRAM, dest).unwrap();
+		}
+		
+		let mut buffer = [0; 2048];
+		let mut received = 0;
+		while received < BATCH_SIZE {
+			if receiver.recv_from(&mut buffer).is_ok() {
+				received += 1;
+			}
+		}
+	}));
+	
+	let (sender, receiver) = sockets();
+	let dest = SockAddr::from(receiver.local_addr().unwrap());
+	let datagrams: Vec<(&[u8], &SockAddr)> = vec![(&DATAGRAM, &dest); BATCH_SIZE];
+	let mut batch = RecvBatch::new();
+	group.bench_function("send_batch_recv_batch", |b| b.iter(|| {
+		assert_eq!(send_batch(sender.as_raw_fd(), &datagrams).unwrap(), BATCH_SIZE);
+		
+		let mut received = 0;
+		while received < BATCH_SIZE {
+			received += batch.recv(receiver.as_raw_fd()).unwrap();
+		}
+	}));
+	
+	group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use criterion::{
+	black_box,
+	criterion_group,
+	criterion_main,
+	Criterion
+};
+
+use icmp::packet::{
+	icmp::{
+		encode_echo_request,
+		Family,
+		IcmpView,
+		Message
+	},
+	ipv4::{
+		IPV4Packet,
+		Ipv4View
+	}
+};
+
+const PAYLOAD: [u8; 56] = [0x5a; 56];
+
+/// An echo reply the way it comes off a raw socket, IPv4 header included.
+fn reply() -> Vec<u8> {
+	let message = Message::EchoReply { ident: 0x1234, sequence: 7, payload: PAYLOAD.to_vec() };
+	let packet = IPV4Packet::new(0x7f000001, 0x7f000001, message.to_bytes(Family::V4).unwrap());
+	
+	let mut buffer = vec![0; packet.size().unwrap()];
+	packet.encode(&mut buffer).unwrap();
+	return buffer;
+}
+
+fn decode(c: &mut Criterion) {
+	let reply = reply();
+	let mut group = c.benchmark_group("decode_reply");
+	
+	group.bench_function("owned", |b| b.iter(|| {
+		let packet = IPV4Packet::decode(black_box(&reply)).unwrap();
+		let message = Message::decode(Family::V4, &packet.data).unwrap();
+		black_box((packet.from_addr, message.ident(), message.sequence()))
+	}));
+	
+	group.bench_function("view", |b| b.iter(|| {
+		let packet = Ipv4View::new(black_box(&reply)).unwrap();
+		let message = IcmpView::new(packet.payload()).unwrap();
+		black_box((packet.from_addr(), message.ident(), message.sequence()))
+	}));
+	
+	group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+	let mut group = c.benchmark_group("encode_request");
+	
+	group.bench_function("allocating", |b| b.iter(|| {
+		let message = Message::echo_request(black_box(0x1234), black_box(7), PAYLOAD.to_vec());
+		black_box(message.to_bytes(Family::V4).unwrap())
+	}));
+	
+	let mut buffer = [0; 8 + PAYLOAD.len()];
+	group.bench_function("in_place", |b| b.iter(|| {
+		let size = encode_echo_request(Family::V4, &mut buffer, black_box(0x1234), black_box(7), &PAYLOAD).unwrap();
+		black_box(&buffer[..size]);
+	}));
+	
+	group.finish();
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+#![no_main]
+
+use icmp::packet::icmp::{
+	Family,
+	IcmpView
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	let Ok(view) = IcmpView::new(data) else {
+		return;
+	};
+
+	let _ = (view.typ(), view.code(), view.checksum(), view.ident(), view.sequence(), view.body());
+	for family in [Family::V4, Family::V6] {
+		let _ = (view.is_echo_reply(family), view.is_timestamp_reply(family));
+		assert_eq!(view.timestamps(family).is_some(), view.is_timestamp_reply(family));
+		let _ = view.to_message(family);
+	}
+});
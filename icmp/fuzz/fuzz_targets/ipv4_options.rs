@@ -0,0 +1,11 @@
+#![no_main]
+
+use icmp::packet::ipv4::IPV4Option;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	for option in IPV4Option::decode_all(data) {
+		let _ = option.copied();
+		let _ = option.encode();
+	}
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use icmp::packet::ipv4::{
+	IPV4Packet,
+	Ipv4View
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	let Ok(packet) = IPV4Packet::decode(data) else {
+		return;
+	};
+
+	// the owned decode is the view, copied
+	let view = Ipv4View::new(data).unwrap();
+	assert_eq!(packet.options, view.options());
+	assert_eq!(packet.data, view.payload());
+
+	let Ok(size) = packet.size() else {
+		return;
+	};
+	let mut buffer = vec![0; size];
+	let _ = packet.encode(&mut buffer);
+});
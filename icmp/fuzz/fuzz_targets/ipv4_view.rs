@@ -0,0 +1,16 @@
+#![no_main]
+
+use icmp::packet::ipv4::Ipv4View;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	let Ok(view) = Ipv4View::new(data) else {
+		return;
+	};
+
+	// every accessor reads straight from the buffer, none of them may go past it
+	let _ = (view.header_size(), view.tos(), view.tot_len(), view.id(), view.frag_off(), view.ttl(), view.protocol(), view.check());
+	let _ = (view.from_addr(), view.dest_addr(), view.options());
+	assert!(view.header_size() + view.payload().len() <= data.len());
+	assert_eq!(view.option_bytes().len(), view.header_size() - 20);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use icmp::packet::icmp::{
+	Family,
+	Message
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	for family in [Family::V4, Family::V6] {
+		let Ok(message) = Message::decode(family, data) else {
+			continue;
+		};
+
+		// whatever decodes encodes again, to a message that decodes the same
+		let bytes = message.to_bytes(family).unwrap();
+		assert_eq!(bytes.len(), data.len());
+		assert_eq!(Message::decode(family, &bytes).unwrap(), message);
+	}
+});
@@ -0,0 +1,152 @@
+use logger::prelude::*;
+
+use std::{
+	os::fd::RawFd,
+	ptr
+};
+
+use socket2::SockAddr;
+
+/// Most datagrams moved per syscall.
+pub const BATCH_SIZE: usize = 32;
+
+/// Largest datagram `RecvBatch` keeps, anything longer is truncated.
+const RECV_BUFFER_SIZE: usize = 2048;
+
+/// Sends every datagram to its address with as few `sendmmsg` calls as
+/// possible. Returns how many were sent, which is less than `datagrams.len()`
+/// only when the kernel stopped early, for example because the socket buffer
+/// is full.
+pub fn send_batch(fd: RawFd, datagrams: &[(&[u8], &SockAddr)]) -> Result<usize, String> {
+	let mut sent = 0;
+
+	for chunk in datagrams.chunks(BATCH_SIZE) {
+		let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+		let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+		for (i, (datagram, addr)) in chunk.iter().enumerate() {
+			iovecs[i] = libc::iovec {
+				iov_base: datagram.as_ptr() as *mut libc::c_void,
+				iov_len: datagram.len(),
+			};
+
+			// the kernel only reads through these pointers, they stay valid until sendmmsg returns
+			headers[i].msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+			headers[i].msg_hdr.msg_namelen = addr.len();
+			headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+			headers[i].msg_hdr.msg_iovlen = 1;
+		}
+
+		let ret = loop {
+			let ret = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), chunk.len() as libc::c_uint, 0) };
+			if ret < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+				continue;
+			}
+			break ret;
+		};
+
+		if ret < 0 {
+			let e = std::io::Error::last_os_error();
+			if sent > 0 {
+				debug!(desc = e.to_string(), sent, "sendmmsg stopped early");
+				return Ok(sent);
+			}
+
+			error!(desc = e.to_string(), "sendmmsg failed");
+			return Err("sendmmsg failed".to_string());
+		}
+
+		sent += ret as usize;
+		if (ret as usize) < chunk.len() {
+			break;
+		}
+	}
+
+	trace!(sent);
+	return Ok(sent);
+}
+
+/// Receive buffers for `recvmmsg`, allocated once and reused for every batch.
+pub struct RecvBatch {
+	buffers: Vec<[u8; RECV_BUFFER_SIZE]>,
+	lengths: [usize; BATCH_SIZE],
+	count: usize,
+}
+
+impl Default for RecvBatch {
+	fn default() -> Self {
+		return Self::new();
+	}
+}
+
+impl RecvBatch {
+	pub fn new() -> Self {
+		return Self {
+			buffers: vec![[0; RECV_BUFFER_SIZE]; BATCH_SIZE],
+			lengths: [0; BATCH_SIZE],
+			count: 0,
+		};
+	}
+
+	/// Reads every datagram already queued on the socket, up to `BATCH_SIZE`,
+	/// without blocking. Returns how many were read.
+	pub fn recv(&mut self, fd: RawFd) -> Result<usize, String> {
+		let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+		let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+		for (i, buffer) in self.buffers.iter_mut().enumerate() {
+			iovecs[i] = libc::iovec {
+				iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+				iov_len: buffer.len(),
+			};
+			headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+			headers[i].msg_hdr.msg_iovlen = 1;
+		}
+
+		self.count = 0;
+		let ret = unsafe {
+			libc::recvmmsg(fd, headers.as_mut_ptr(), BATCH_SIZE as libc::c_uint, libc::MSG_DONTWAIT, ptr::null_mut())
+		};
+
+		if ret < 0 {
+			let e = std::io::Error::last_os_error();
+			if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted) {
+				return Ok(0);
+			}
+
+			error!(desc = e.to_string(), "recvmmsg failed");
+			return Err("recvmmsg failed".to_string());
+		}
+
+		self.count = ret as usize;
+		for (length, header) in self.lengths.iter_mut().zip(headers.iter()).take(self.count) {
+			*length = header.msg_len as usize;
+		}
+
+		trace!(received = self.count);
+		return Ok(self.count);
+	}
+
+	/// Empties the batch so it can be filled with `push`.
+	pub fn clear(&mut self) {
+		self.count = 0;
+	}
+
+	/// Adds a datagram, truncated to the buffer size. Returns `false` when the batch is full.
+	pub fn push(&mut self, datagram: &[u8]) -> bool {
+		if self.count == BATCH_SIZE {
+			return false;
+		}
+
+		let length = datagram.len().min(RECV_BUFFER_SIZE);
+		self.buffers[self.count][..length].copy_from_slice(&datagram[..length]);
+		self.lengths[self.count] = length;
+		self.count += 1;
+		return true;
+	}
+
+	/// The datagrams read by the last `recv`, or added with `push`.
+	pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+		return self.buffers.iter().zip(self.lengths.iter()).take(self.count).map(|(b, l)| &b[..*l]);
+	}
+}
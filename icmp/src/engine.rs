@@ -0,0 +1,602 @@
+use crate::{
+	batch::{
+		RecvBatch,
+		BATCH_SIZE
+	},
+	ms_of_day,
+	ms_since_midnight,
+	packet::{self, icmp, ipv4},
+	transport::Transport,
+	PingReply,
+	RawHeader,
+	Timestamps,
+};
+use logger::prelude::*;
+
+use std::{
+	collections::HashMap,
+	net::{
+		IpAddr,
+		Ipv4Addr
+	},
+	os::fd::{
+		AsRawFd,
+		FromRawFd,
+		OwnedFd
+	},
+	sync::{
+		mpsc::{
+			channel,
+			Receiver,
+			Sender,
+			TryRecvError
+		},
+		Arc,
+		Mutex
+	},
+	thread::JoinHandle,
+	time::{
+		Duration,
+		Instant,
+		SystemTime
+	}
+};
+
+use util::cancel::{
+	CancelToken,
+	Wait
+};
+
+/// How often the receiver thread wakes up to expire probes.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Random payload carried by every echo request.
+const PAYLOAD_SIZE: usize = 64;
+
+/// How long a timed out probe is remembered so a late reply can still be recognized.
+const LATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Which ICMP request the engine sends.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ProbeKind {
+	/// Echo request (type 8), what ping normally sends.
+	Echo,
+	/// Timestamp request (type 13), the reply carries the remote clock.
+	Timestamp,
+}
+
+pub enum ProbeEvent {
+	Reply(PingReply),
+	/// A reply for a probe that was already reported as timed out.
+	Late(PingReply),
+	Timeout {
+		dest: IpAddr,
+		sequence: u16,
+	},
+}
+
+struct Probe {
+	sent: Instant,
+	tos: u8,
+	deadline: Instant,
+	timed_out: bool,
+}
+
+type Table = Arc<Mutex<HashMap<(IpAddr, u16), Probe>>>;
+
+/// Sending half of the event channel. Every event also bumps an eventfd, so
+/// a caller can wait for events and its cancel token in a single poll.
+struct EventSender {
+	tx: Sender<ProbeEvent>,
+	ready: Arc<OwnedFd>,
+}
+
+impl EventSender {
+	/// False once the engine is gone.
+	fn send(&self, event: ProbeEvent) -> bool {
+		if self.tx.send(event).is_err() {
+			return false;
+		}
+
+		let one = 1u64;
+		if unsafe { libc::write(self.ready.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) } != 8 {
+			error!(desc = std::io::Error::last_os_error().to_string(), "could not signal event");
+		}
+		return true;
+	}
+}
+
+/// Sends echo requests from the caller's thread while a background thread
+/// reads replies, matches them against the table of outstanding probes and
+/// reports replies and timeouts as events. Any number of probes, to any
+/// number of targets, can be in flight at once.
+pub struct ProbeEngine {
+	transport: Arc<dyn Transport>,
+	ident: u16,
+	sequence: u16,
+	rand: fastrand::Rng,
+	timeout: Duration,
+	tos: u8,
+	kind: ProbeKind,
+	source: Option<Ipv4Addr>,
+	raw_header: Option<RawHeader>,
+	/// Reused for every probe, so sending does not allocate.
+	buffer: [u8; 8 + PAYLOAD_SIZE],
+
+	table: Table,
+	events: Receiver<ProbeEvent>,
+	/// Readable while events may be queued.
+	ready: Arc<OwnedFd>,
+	stop: CancelToken,
+	receiver: Option<JoinHandle<()>>,
+}
+
+impl ProbeEngine {
+	/// `source` is the address the socket is bound to, if any, and is reported
+	/// as the local address of every reply.
+	pub fn new(transport: Arc<dyn Transport>, timeout: Duration, source: Option<Ipv4Addr>) -> Result<Self, String> {
+		let tos = transport.tos();
+		let mut rand = fastrand::Rng::new();
+		let ident = rand.u16(..);
+		let table: Table = Arc::new(Mutex::new(HashMap::new()));
+		let stop = CancelToken::new()?;
+		let (tx, rx) = channel();
+		debug!(ident, "starting probe engine");
+
+		let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+		if fd < 0 {
+			let e = std::io::Error::last_os_error();
+			error!(desc = e.to_string(), "could not create eventfd");
+			return Err("could not create eventfd".to_string());
+		}
+		let ready = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+
+		let receiver = {
+			let transport = transport.clone();
+			let table = table.clone();
+			let stop = stop.clone();
+			let tx = EventSender { tx, ready: ready.clone() };
+			std::thread::spawn(move || receive_loop(transport, ident, source, table, tx, stop))
+		};
+
+		return Ok(Self {
+			transport,
+			ident,
+			sequence: 1,
+			rand,
+			timeout,
+			tos,
+			kind: ProbeKind::Echo,
+			source,
+			raw_header: None,
+			buffer: [0; 8 + PAYLOAD_SIZE],
+
+			table,
+			events: rx,
+			ready,
+			stop,
+			receiver: Some(receiver),
+		});
+	}
+
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = timeout;
+	}
+
+	pub fn set_kind(&mut self, kind: ProbeKind) {
+		self.kind = kind;
+	}
+
+	/// With a template every probe is sent with a header built from it, the
+	/// socket must have IP_HDRINCL set.
+	pub fn set_raw_header(&mut self, header: Option<RawHeader>) {
+		self.raw_header = header;
+	}
+
+	/// Sets the TOS byte on the sending socket; probes sent afterwards carry it.
+	pub fn set_tos(&mut self, tos: u8) -> Result<(), String> {
+		if tos != self.tos {
+			self.transport.set_tos(tos)?;
+			self.tos = tos;
+		}
+		return Ok(());
+	}
+
+	/// Sends one request to `addr` and returns its sequence number.
+	pub fn send(&mut self, addr: IpAddr) -> Result<u16, String> {
+		let sequence = self.sequence;
+		self.sequence = self.sequence.wrapping_add(1);
+
+		let size = encode_probe(self.kind, &mut self.rand, self.ident, sequence, &mut self.buffer)?;
+
+		let datagrams = match &self.raw_header {
+			Some(header) => Some(build_datagrams(header, self.source, self.tos, addr, &self.buffer[..size])?),
+			None => None,
+		};
+		// hold the table while sending so the receiver cannot see the reply before the probe is recorded
+		let mut table = match self.table.lock() {
+			Ok(t) => t,
+			Err(e) => {
+				error!(desc = e.to_string(), "probe table poisoned");
+				return Err("probe table poisoned".to_string());
+			}
+		};
+
+		let sent = Instant::now();
+		match &datagrams {
+			Some(datagrams) => {
+				for datagram in datagrams {
+					self.send_datagram(datagram, addr)?;
+				}
+			}
+			None => self.send_datagram(&self.buffer[..size], addr)?,
+		}
+
+		table.insert((addr, sequence), Probe {
+			sent,
+			tos: self.tos,
+			deadline: sent + self.timeout,
+			timed_out: false,
+		});
+
+		return Ok(sequence);
+	}
+
+	/// Sends one request to each address, batching them into as few syscalls
+	/// as possible, and returns their sequence numbers in the same order. If
+	/// the kernel stops accepting packets midway, only the probes that went
+	/// out are returned.
+	pub fn send_many(&mut self, addrs: &[IpAddr]) -> Result<Vec<u16>, String> {
+		// fragments and custom headers go out one by one
+		if self.raw_header.is_some() {
+			return addrs.iter().map(|addr| self.send(*addr)).collect();
+		}
+
+		let mut sequences = Vec::with_capacity(addrs.len());
+		let mut buffers = [[0; 8 + PAYLOAD_SIZE]; BATCH_SIZE];
+		let mut sizes = [0; BATCH_SIZE];
+
+		for chunk in addrs.chunks(BATCH_SIZE) {
+			let first = self.sequence;
+			for (buffer, size) in buffers.iter_mut().zip(sizes.iter_mut()).take(chunk.len()) {
+				let sequence = self.sequence;
+				self.sequence = self.sequence.wrapping_add(1);
+				*size = encode_probe(self.kind, &mut self.rand, self.ident, sequence, buffer)?;
+			}
+
+			let datagrams: Vec<(&[u8], IpAddr)> = buffers.iter().zip(sizes.iter()).zip(chunk.iter()).map(|((b, s), a)| (&b[..*s], *a)).collect();
+
+			// same as in send, the table is held until every probe is recorded
+			let mut table = match self.table.lock() {
+				Ok(t) => t,
+				Err(e) => {
+					error!(desc = e.to_string(), "probe table poisoned");
+					return Err("probe table poisoned".to_string());
+				}
+			};
+
+			let sent = Instant::now();
+			let count = self.transport.send_batch(&datagrams)?;
+			debug!(probes_sent = count);
+
+			for (i, addr) in chunk.iter().enumerate().take(count) {
+				let sequence = first.wrapping_add(i as u16);
+				table.insert((*addr, sequence), Probe {
+					sent,
+					tos: self.tos,
+					deadline: sent + self.timeout,
+					timed_out: false,
+				});
+				sequences.push(sequence);
+			}
+
+			if count < chunk.len() {
+				break;
+			}
+		}
+
+		return Ok(sequences);
+	}
+
+	fn send_datagram(&self, datagram: &[u8], dest: IpAddr) -> Result<(), String> {
+		let bytes = self.transport.send_to(datagram, dest)?;
+		debug!(bytes_sent = bytes);
+
+		return Ok(());
+	}
+
+	/// Waits up to `timeout` for the next reply or timeout event.
+	pub fn recv_event(&self, timeout: Duration) -> Option<ProbeEvent> {
+		return self.events.recv_timeout(timeout).ok();
+	}
+
+	/// Waits for the next reply or timeout event, polling the event queue and
+	/// `cancel` together so cancelling takes effect right away.
+	pub fn wait_event(&self, cancel: &CancelToken) -> Result<ProbeEvent, String> {
+		loop {
+			// reset before looking, an event queued after the check makes the fd readable again
+			let mut counter = 0u64;
+			unsafe { libc::read(self.ready.as_raw_fd(), &mut counter as *mut u64 as *mut libc::c_void, 8) };
+
+			match self.events.try_recv() {
+				Ok(event) => return Ok(event),
+				Err(TryRecvError::Disconnected) => return Err("probe engine stopped".to_string()),
+				Err(TryRecvError::Empty) => {}
+			}
+
+			if cancel.wait_readable(self.ready.as_raw_fd(), None)? == Wait::Cancelled {
+				return Err("stop signal".to_string());
+			}
+		}
+	}
+
+	/// Number of probes still waiting for a reply or their timeout.
+	pub fn in_flight(&self) -> usize {
+		return match self.table.lock() {
+			Ok(t) => t.values().filter(|p| !p.timed_out).count(),
+			Err(_) => 0,
+		};
+	}
+}
+
+impl Drop for ProbeEngine {
+	fn drop(&mut self) {
+		self.stop.cancel();
+		if let Some(receiver) = self.receiver.take() {
+			let _ = receiver.join();
+		}
+	}
+}
+
+fn receive_loop(transport: Arc<dyn Transport>, ident: u16, source: Option<Ipv4Addr>, table: Table, tx: EventSender, stop: CancelToken) {
+	let mut batch = RecvBatch::new();
+
+	loop {
+		match transport.wait_readable(&stop, POLL_INTERVAL) {
+			Ok(Wait::Cancelled) => return,
+			Ok(Wait::TimedOut) => {}
+			// drain everything that is queued, a batch at a time, before expiring probes again
+			Ok(Wait::Ready) => loop {
+				let count = match transport.recv_batch(&mut batch) {
+					Ok(c) => c,
+					Err(_) => {
+						if !stop.sleep(POLL_INTERVAL) {
+							return;
+						}
+						break;
+					}
+				};
+
+				let received = Instant::now();
+				let arrived = SystemTime::now();
+				for datagram in batch.iter() {
+					debug!(bytes_recv = datagram.len());
+					if let Some(event) = match_reply(datagram, received, arrived, ident, source, &table) && !tx.send(event) {
+						return;
+					}
+				}
+
+				if count < BATCH_SIZE {
+					break;
+				}
+			},
+			Err(_) => {
+				if !stop.sleep(POLL_INTERVAL) {
+					return;
+				}
+			}
+		}
+
+		for event in expire(&table) {
+			if !tx.send(event) {
+				return;
+			}
+		}
+	}
+}
+
+/// Wraps an encoded ICMP message in IPv4 headers built from `header`, one
+/// datagram per fragment.
+pub(crate) fn build_datagrams(header: &RawHeader, source: Option<Ipv4Addr>, tos: u8, addr: IpAddr, message: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+	let dest = match addr {
+		IpAddr::V4(a) => a,
+		IpAddr::V6(_) => return Err("raw headers are ipv4 only".to_string()),
+	};
+
+	// a zero source is filled in by the kernel
+	let mut packet = ipv4::IPV4Packet::new(source.map_or(0, |s| s.to_bits()), dest.to_bits(), Vec::from(message));
+	packet.tos = tos;
+	packet.ttl = header.ttl;
+	packet.id = header.id;
+	packet.options = header.options.clone();
+	if header.dont_fragment {
+		packet.frag_off |= ipv4::FLAG_DONT_FRAGMENT;
+	}
+
+	let packets = match header.fragment_size {
+		Some(size) => {
+			if packet.id == 0 {
+				packet.id = fastrand::u16(1..);
+			}
+			packet.fragment(size)?
+		}
+		None => vec![packet],
+	};
+
+	let mut datagrams = Vec::with_capacity(packets.len());
+	for packet in packets {
+		let mut buffer = vec![0; packet.size()?];
+		if let Err(e) = packet.encode(&mut buffer) {
+			error!(desc = e.to_string(), "could not encode ip header");
+			return Err("could not encode ip header".to_string());
+		}
+		datagrams.push(buffer);
+	}
+
+	return Ok(datagrams);
+}
+
+/// Encodes the request for `kind` into `buffer` and returns its size.
+fn encode_probe(kind: ProbeKind, rand: &mut fastrand::Rng, ident: u16, sequence: u16, buffer: &mut [u8]) -> Result<usize, String> {
+	let size = match kind {
+		ProbeKind::Echo => {
+			let mut payload = [0; PAYLOAD_SIZE];
+			rand.fill(&mut payload);
+			icmp::encode_echo_request(icmp::Family::V4, buffer, ident, sequence, &payload)
+		}
+		ProbeKind::Timestamp => icmp::encode_timestamp_request(buffer, ident, sequence, ms_since_midnight()),
+	};
+
+	return match size {
+		Ok(s) => Ok(s),
+		Err(e) => {
+			error!(desc = e.to_string(), "could not encode packet");
+			Err("could not encode packet".to_string())
+		}
+	};
+}
+
+/// Reads an IPv4 datagram carrying an echo or timestamp reply with the given ident, in place.
+pub(crate) fn parse_reply(buffer: &[u8], ident: u16) -> Option<(ipv4::Ipv4View<'_>, icmp::IcmpView<'_>)> {
+	let ipv4_packet = match ipv4::Ipv4View::new(buffer) {
+		Ok(p) => p,
+		Err(e) => {
+			debug!(desc = e.to_string(), "could not decode packet");
+			return None;
+		}
+	};
+
+	let reply = icmp::IcmpView::new(ipv4_packet.payload()).ok()?;
+	if packet::checksum(ipv4_packet.payload()) != 0 {
+		debug!(sequence = reply.sequence(), "dropping reply with bad checksum");
+		return None;
+	}
+
+	// our own requests show up here too when pinging a local address
+	let is_reply = reply.is_echo_reply(icmp::Family::V4) || reply.is_timestamp_reply(icmp::Family::V4);
+	if !is_reply || reply.ident() != ident {
+		return None;
+	}
+
+	return Some((ipv4_packet, reply));
+}
+
+/// Builds the reply from a matched datagram that was received at `arrived`.
+/// Only allocates when the header carries IP options.
+pub(crate) fn make_reply(ipv4_packet: &ipv4::Ipv4View, reply: &icmp::IcmpView, elapsed: Duration, arrived: SystemTime, sent_tos: u8, local_addr: Option<Ipv4Addr>) -> PingReply {
+	let dest_addr = Ipv4Addr::from_bits(ipv4_packet.dest_addr());
+
+	return PingReply {
+		elapsed,
+		sequence: reply.sequence(),
+
+		from_addr: Ipv4Addr::from_bits(ipv4_packet.from_addr()),
+		dest_addr,
+		local_addr: local_addr.unwrap_or(dest_addr),
+
+		sent_tos,
+		tos: ipv4_packet.tos(),
+
+		ttl: ipv4_packet.ttl(),
+		tot_len: ipv4_packet.tot_len(),
+		id: ipv4_packet.id(),
+		bytes: ipv4_packet.payload().len(),
+
+		ip_options: ipv4_packet.options().into_iter().map(Into::into).collect(),
+		timestamps: reply.timestamps(icmp::Family::V4).map(|(originate, receive, transmit)| Timestamps {
+			originate,
+			receive,
+			transmit,
+			arrived: ms_of_day(arrived),
+		}),
+	};
+}
+
+fn match_reply(buffer: &[u8], received: Instant, arrived: SystemTime, ident: u16, source: Option<Ipv4Addr>, table: &Table) -> Option<ProbeEvent> {
+	let (ipv4_packet, reply) = parse_reply(buffer, ident)?;
+
+	let from_addr = Ipv4Addr::from_bits(ipv4_packet.from_addr());
+	let probe = table.lock().ok()?.remove(&(IpAddr::V4(from_addr), reply.sequence()))?;
+	let reply = make_reply(&ipv4_packet, &reply, received - probe.sent, arrived, probe.tos, source);
+
+	return Some(if probe.timed_out { ProbeEvent::Late(reply) } else { ProbeEvent::Reply(reply) });
+}
+
+fn expire(table: &Table) -> Vec<ProbeEvent> {
+	let mut table = match table.lock() {
+		Ok(t) => t,
+		Err(_) => return Vec::new(),
+	};
+
+	let now = Instant::now();
+	let mut expired = Vec::new();
+	for ((dest, sequence), probe) in table.iter_mut() {
+		if !probe.timed_out && now >= probe.deadline {
+			probe.timed_out = true;
+			expired.push((probe.sent, *dest, *sequence));
+		}
+	}
+
+	table.retain(|_, p| !p.timed_out || now < p.deadline + LATE_WINDOW);
+
+	// report in the order the probes were sent
+	expired.sort_by_key(|(sent, _, _)| *sent);
+	return expired.into_iter().map(|(_, dest, sequence)| ProbeEvent::Timeout { dest, sequence }).collect();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DEST: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+	fn message() -> Vec<u8> {
+		let mut buffer = [0; 8 + PAYLOAD_SIZE];
+		let size = encode_probe(ProbeKind::Echo, &mut fastrand::Rng::with_seed(1), 7, 1, &mut buffer).unwrap();
+		return Vec::from(&buffer[..size]);
+	}
+
+	fn decode_all(datagrams: &[Vec<u8>]) -> Vec<ipv4::IPV4Packet> {
+		return datagrams.iter().map(|d| ipv4::IPV4Packet::decode(d).unwrap()).collect();
+	}
+
+	#[test]
+	fn fragments_share_one_nonzero_id() {
+		let header = RawHeader { fragment_size: Some(16), ..RawHeader::default() };
+		let message = message();
+
+		let fragments = decode_all(&build_datagrams(&header, None, 0x28, DEST.into(), &message).unwrap());
+		assert_eq!(fragments.len(), message.len().div_ceil(16));
+		assert_ne!(fragments[0].id, 0);
+		assert!(fragments.iter().all(|f| f.id == fragments[0].id && f.tos == 0x28 && f.dest_addr == DEST.to_bits()));
+
+		let data: Vec<u8> = fragments.iter().flat_map(|f| f.data.clone()).collect();
+		assert_eq!(data, message);
+		assert_eq!(packet::checksum(&data), 0);
+	}
+
+	#[test]
+	fn fragments_keep_a_given_id() {
+		let header = RawHeader { id: 0x4242, fragment_size: Some(24), ..RawHeader::default() };
+
+		let fragments = decode_all(&build_datagrams(&header, Some(Ipv4Addr::LOCALHOST), 0, DEST.into(), &message()).unwrap());
+		assert!(fragments.iter().all(|f| f.id == 0x4242 && f.from_addr == Ipv4Addr::LOCALHOST.to_bits()));
+	}
+
+	#[test]
+	fn dont_fragment_cannot_be_split() {
+		let header = RawHeader { dont_fragment: true, fragment_size: Some(16), ..RawHeader::default() };
+		assert!(build_datagrams(&header, None, 0, DEST.into(), &message()).is_err());
+
+		// without a fragment size the probe goes out whole, with the flag set
+		let header = RawHeader { dont_fragment: true, ..RawHeader::default() };
+		let datagrams = decode_all(&build_datagrams(&header, None, 0, DEST.into(), &message()).unwrap());
+		assert_eq!(datagrams.len(), 1);
+		assert_eq!(datagrams[0].frag_off, ipv4::FLAG_DONT_FRAGMENT);
+	}
+
+	#[test]
+	fn raw_headers_are_ipv4_only() {
+		assert!(build_datagrams(&RawHeader::default(), None, 0, "2001:db8::1".parse().unwrap(), &message()).is_err());
+	}
+}
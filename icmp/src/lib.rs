@@ -1,23 +1,40 @@
 pub mod prelude;
-mod packet;
+pub mod stats;
+pub mod sketch;
+pub mod engine;
+pub mod schedule;
+pub mod session;
+pub mod batch;
+pub mod transport;
+pub mod pcap;
+pub mod replay;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+pub mod packet;
 
-use packet::{icmp, ipv4};
+use engine::{ProbeEngine, ProbeEvent, ProbeKind};
+use packet::ipv4;
+use transport::{CaptureTransport, SocketTransport, Transport};
 use logger::prelude::*;
 
 use std::{
-	io::Read, net::{
+	net::{
 		IpAddr,
 		Ipv4Addr,
 		SocketAddr
 	},
+	os::fd::AsRawFd,
+	path::PathBuf,
 	str::FromStr,
-	sync::mpsc::Receiver,
+	sync::Arc,
 	time::{
 		Duration,
-		Instant
+		SystemTime,
+		UNIX_EPOCH
 	}
 };
 
+use util::cancel::CancelToken;
 use socket2::{
 	Socket,
 	Domain,
@@ -25,160 +42,687 @@ use socket2::{
 	Protocol
 };
 
+/// Where the ICMP socket is bound before the first probe goes out.
+#[derive(Clone, Default, Debug)]
+pub struct SocketOptions {
+	/// Local address the probes are sent from.
+	pub source: Option<IpAddr>,
+	/// Interface the socket is bound to with SO_BINDTODEVICE.
+	pub interface: Option<String>,
+	/// Firewall mark set with SO_MARK, for policy routing.
+	pub mark: Option<u32>,
+	/// TOS byte (DSCP and ECN bits) set on outgoing probes.
+	pub tos: Option<u8>,
+	/// IP option asking the routers along the path to record themselves.
+	pub record: Option<RecordOption>,
+	/// Write the IPv4 header ourselves (IP_HDRINCL) from this template.
+	pub raw_header: Option<RawHeader>,
+}
+
+impl SocketOptions {
+	/// Rejects combinations a socket cannot be opened with.
+	pub(crate) fn check(&self) -> Result<(), String> {
+		if let Some(source) = self.source && !source.is_ipv4() {
+			return Err("source address must be ipv4".to_string());
+		}
+		if self.record.is_some() && self.raw_header.is_some() {
+			return Err("ip options have to be set on the raw header".to_string());
+		}
+		
+		return Ok(());
+	}
+	
+	/// The source address, if the socket is bound to one.
+	pub(crate) fn source_v4(&self) -> Option<Ipv4Addr> {
+		return match self.source {
+			Some(IpAddr::V4(a)) => Some(a),
+			_ => None,
+		};
+	}
+}
+
+/// IPv4 header fields for probes sent with IP_HDRINCL, where the header is
+/// built here instead of by the kernel. Socket level TTL and IP options do
+/// not apply on this path, they are taken from the template instead.
+#[derive(Clone, Debug)]
+pub struct RawHeader {
+	pub ttl: u8,
+	/// IP id, 0 lets the kernel pick one. A fragmented probe always gets a
+	/// nonzero id, since the kernel would pick a different one per fragment.
+	pub id: u16,
+	pub dont_fragment: bool,
+	/// Splits every probe into fragments carrying at most this many bytes of
+	/// ICMP data, rounded down to a multiple of 8.
+	pub fragment_size: Option<usize>,
+	pub options: Vec<ipv4::IPV4Option>,
+}
+
+impl Default for RawHeader {
+	fn default() -> Self {
+		return Self {
+			ttl: 64,
+			id: 0,
+			dont_fragment: false,
+			fragment_size: None,
+			options: Vec::new(),
+		};
+	}
+}
+
+/// IP header options that can be set on outgoing probes, like ping's -R and -T.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordOption {
+	/// Record Route, up to 9 addresses.
+	Route,
+	/// Timestamp option with only timestamps, up to 9.
+	Timestamp,
+	/// Timestamp option with address and timestamp pairs, up to 4.
+	TimestampAndAddress,
+}
+
+impl RecordOption {
+	/// Option bytes for IP_OPTIONS, padded to the 40 byte maximum.
+	fn encode(&self) -> [u8; 40] {
+		let mut bytes = [0; 40];
+		match self {
+			RecordOption::Route => {
+				bytes[0] = ipv4::OPTION_NOP;
+				bytes[1..4].copy_from_slice(&[ipv4::OPTION_RECORD_ROUTE, 39, 4]);
+			}
+			RecordOption::Timestamp => {
+				bytes[0..4].copy_from_slice(&[ipv4::OPTION_TIMESTAMP, 40, 5, ipv4::TIMESTAMP_ONLY]);
+			}
+			RecordOption::TimestampAndAddress => {
+				bytes[0..4].copy_from_slice(&[ipv4::OPTION_TIMESTAMP, 36, 5, ipv4::TIMESTAMP_AND_ADDRESS]);
+			}
+		}
+		
+		return bytes;
+	}
+	
+	/// The option as it goes out in the header of a probe, empty slots included.
+	fn header_option(&self) -> ipv4::IPV4Option {
+		let bytes = self.encode();
+		let start = if bytes[0] == ipv4::OPTION_NOP { 1 } else { 0 };
+		let length = bytes[start + 1] as usize;
+		return ipv4::IPV4Option::Other { kind: bytes[start], data: bytes[start + 2..start + length].to_vec() };
+	}
+}
+
+/// An IP option found on a reply.
+#[derive(Clone, Debug)]
+pub enum IpOption {
+	RecordRoute(Vec<Ipv4Addr>),
+	/// Timestamps are in ms since midnight UTC. `overflow` counts the hops
+	/// that could not record because the option was full.
+	Timestamp {
+		entries: Vec<(Option<Ipv4Addr>, u32)>,
+		overflow: u8,
+	},
+	Other {
+		kind: u8,
+		data: Vec<u8>,
+	},
+}
+
+impl From<ipv4::IPV4Option> for IpOption {
+	fn from(option: ipv4::IPV4Option) -> Self {
+		return match option {
+			ipv4::IPV4Option::RecordRoute(route) => IpOption::RecordRoute(route.into_iter().map(Ipv4Addr::from_bits).collect()),
+			ipv4::IPV4Option::Timestamp { entries, overflow } => IpOption::Timestamp {
+				entries: entries.into_iter().map(|(a, t)| (a.map(Ipv4Addr::from_bits), t)).collect(),
+				overflow,
+			},
+			ipv4::IPV4Option::Other { kind, data } => IpOption::Other { kind, data },
+		};
+	}
+}
+
+/// TTL of every probe sent without a raw header.
+const SOCKET_TTL: u8 = 255;
+
+pub(crate) fn open_socket(options: &SocketOptions) -> Result<Socket, String> {
+	let socket = match Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)) {
+		Ok(s) => s,
+		Err(e) => {
+			error!(desc = e.to_string(), "could not open socket");
+			return Err("could not open socket".to_string());
+		}
+	};
+	
+	if let Err(e) = socket.set_ttl(SOCKET_TTL as u32) {
+		error!(desc = e.to_string(), "could not set socket ttl");
+		return Err("could not set socket ttl".to_string());
+	}
+	assert!(socket.ttl().is_ok());
+	trace!(ttl = socket.ttl().unwrap());
+	
+	if let Some(interface) = &options.interface {
+		if let Err(e) = socket.bind_device(Some(interface.as_bytes())) {
+			error!(desc = e.to_string(), interface, "could not bind socket to interface");
+			return Err("could not bind socket to interface".to_string());
+		}
+		trace!(interface);
+	}
+	
+	if let Some(mark) = options.mark {
+		if let Err(e) = socket.set_mark(mark) {
+			error!(desc = e.to_string(), mark, "could not set socket mark");
+			return Err("could not set socket mark".to_string());
+		}
+		trace!(mark);
+	}
+	
+	if let Some(tos) = options.tos {
+		set_tos(&socket, tos)?;
+	}
+	
+	if options.raw_header.is_some() {
+		if let Err(e) = socket.set_header_included_v4(true) {
+			error!(desc = e.to_string(), "could not set IP_HDRINCL");
+			return Err("could not set IP_HDRINCL".to_string());
+		}
+		trace!(header_included = true);
+	}
+	
+	if let Some(record) = options.record {
+		let bytes = record.encode();
+		let ret = unsafe {
+			libc::setsockopt(
+				socket.as_raw_fd(),
+				libc::IPPROTO_IP,
+				libc::IP_OPTIONS,
+				bytes.as_ptr() as *const libc::c_void,
+				bytes.len() as libc::socklen_t,
+			)
+		};
+		
+		if ret != 0 {
+			let e = std::io::Error::last_os_error();
+			error!(desc = e.to_string(), ?record, "could not set ip options");
+			return Err("could not set ip options".to_string());
+		}
+		trace!(?record);
+	}
+	
+	if let Some(source) = options.source {
+		if let Err(e) = socket.bind(&SocketAddr::new(source, 0).into()) {
+			error!(desc = e.to_string(), source = source.to_string(), "could not bind socket to source address");
+			return Err("could not bind socket to source address".to_string());
+		}
+		trace!(source = source.to_string());
+	}
+	
+	return Ok(socket);
+}
+
+pub(crate) fn set_tos(socket: &Socket, tos: u8) -> Result<(), String> {
+	if let Err(e) = socket.set_tos(tos as u32) {
+		error!(desc = e.to_string(), tos, "could not set socket tos");
+		return Err("could not set socket tos".to_string());
+	}
+	trace!(tos);
+	
+	return Ok(());
+}
+
 pub struct PingReply {
 	pub elapsed: Duration,
 	pub sequence: u16,
 	
 	pub from_addr: Ipv4Addr,
 	pub dest_addr: Ipv4Addr,
+	/// Local address the probe left from. This is the bound source address when
+	/// one was set, otherwise the address the reply was sent back to.
+	pub local_addr: Ipv4Addr,
+	
+	/// TOS byte the probe was sent with.
+	pub sent_tos: u8,
+	/// TOS byte seen on the reply. A difference to `sent_tos` means the
+	/// marking was rewritten somewhere along the path, or by the target.
+	pub tos: u8,
+	
+	pub ttl: u8,
+	/// Total length of the reply datagram, IP header included.
+	pub tot_len: u16,
+	pub id: u16,
+	/// Size of the ICMP message, without the IP header.
+	pub bytes: usize,
+	
+	/// Remote clock readings, for timestamp probes.
+	pub timestamps: Option<Timestamps>,
+	/// IP options carried by the reply, such as a recorded route.
+	pub ip_options: Vec<IpOption>,
+}
+
+const MS_PER_DAY: u32 = 86_400_000;
+
+/// Milliseconds since midnight UTC, the clock ICMP timestamp messages use.
+pub(crate) fn ms_since_midnight() -> u32 {
+	return ms_of_day(SystemTime::now());
+}
+
+/// `time` as milliseconds since midnight UTC.
+pub(crate) fn ms_of_day(time: SystemTime) -> u32 {
+	let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	return (since_epoch.as_millis() % MS_PER_DAY as u128) as u32;
+}
+
+/// Difference `a - b` between two times of day, taking a wrap at midnight into account.
+fn day_diff(a: u32, b: u32) -> i64 {
+	let d = (a as i64 - b as i64).rem_euclid(MS_PER_DAY as i64);
+	return if d > MS_PER_DAY as i64 / 2 { d - MS_PER_DAY as i64 } else { d };
+}
+
+/// The four clock readings of an ICMP timestamp exchange, in milliseconds
+/// since midnight UTC. `originate` and `arrived` come from the local clock,
+/// `receive` and `transmit` from the remote one.
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamps {
+	pub originate: u32,
+	pub receive: u32,
+	pub transmit: u32,
+	pub arrived: u32,
+}
+
+impl Timestamps {
+	/// Hosts set the high bit when their clock is not synchronized to UTC,
+	/// the offset is then meaningless.
+	pub fn is_standard(&self) -> bool {
+		return self.receive & 0x8000_0000 == 0 && self.transmit & 0x8000_0000 == 0;
+	}
+	
+	/// One-way delay to the target in ms, skewed by the clock offset.
+	pub fn outbound(&self) -> i64 {
+		return day_diff(self.receive, self.originate);
+	}
+	
+	/// One-way delay back from the target in ms, skewed by the clock offset.
+	pub fn inbound(&self) -> i64 {
+		return day_diff(self.arrived, self.transmit);
+	}
+	
+	/// Estimated offset of the remote clock in ms, positive when it runs ahead.
+	/// Assumes the path is symmetric, like NTP does.
+	pub fn offset(&self) -> f64 {
+		return (self.outbound() - self.inbound()) as f64 / 2.0;
+	}
+}
+
+impl PingReply {
+	/// The TTL the target most likely sent the reply with: the smallest common
+	/// default that is not below the TTL it arrived with.
+	pub fn initial_ttl(&self) -> u8 {
+		return [32, 64, 128, 255].into_iter().find(|t| *t >= self.ttl).unwrap_or(255);
+	}
+	
+	/// Estimated number of hops the reply took, assuming `initial_ttl`.
+	pub fn hops(&self) -> u8 {
+		return self.initial_ttl() - self.ttl;
+	}
+	
+	/// Rough guess at the kind of system that replied, based on `initial_ttl`.
+	pub fn os_guess(&self) -> &'static str {
+		return match self.initial_ttl() {
+			32 => "old windows",
+			64 => "linux/unix",
+			128 => "windows",
+			_ => "network device",
+		};
+	}
+}
+
+/// Configures a `Pinger` before its socket is opened.
+pub struct PingerBuilder {
+	host: String,
+	options: SocketOptions,
+	kind: ProbeKind,
+	timeout: Duration,
+	transport: Option<Arc<dyn Transport>>,
+	pcap: Option<PathBuf>,
+	cancel: Option<CancelToken>,
+}
+
+impl PingerBuilder {
+	pub fn new<S: Into<String>>(host: S) -> Self {
+		return Self {
+			host: host.into(),
+			options: SocketOptions::default(),
+			kind: ProbeKind::Echo,
+			timeout: Duration::from_secs(2),
+			transport: None,
+			pcap: None,
+			cancel: None,
+		};
+	}
+	
+	pub fn source(mut self, source: Option<IpAddr>) -> Self {
+		self.options.source = source;
+		return self;
+	}
+	
+	pub fn interface(mut self, interface: Option<String>) -> Self {
+		self.options.interface = interface;
+		return self;
+	}
+	
+	pub fn mark(mut self, mark: Option<u32>) -> Self {
+		self.options.mark = mark;
+		return self;
+	}
+	
+	pub fn tos(mut self, tos: Option<u8>) -> Self {
+		self.options.tos = tos;
+		return self;
+	}
+	
+	pub fn record(mut self, record: Option<RecordOption>) -> Self {
+		self.options.record = record;
+		return self;
+	}
+	
+	pub fn raw_header(mut self, header: Option<RawHeader>) -> Self {
+		self.options.raw_header = header;
+		return self;
+	}
+	
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		return self;
+	}
+	
+	pub fn kind(mut self, kind: ProbeKind) -> Self {
+		self.kind = kind;
+		return self;
+	}
+	
+	/// Sends and receives through `transport` instead of opening a raw socket,
+	/// the socket options are then ignored.
+	pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+		self.transport = Some(transport);
+		return self;
+	}
+	
+	/// Writes every probe and every datagram read from the socket to a pcap file at `path`.
+	pub fn pcap(mut self, path: Option<PathBuf>) -> Self {
+		self.pcap = path;
+		return self;
+	}
+	
+	/// Gives up resolving the host, now and on every `reresolve`, as soon as `cancel` fires.
+	pub fn cancel_token(mut self, cancel: CancelToken) -> Self {
+		self.cancel = Some(cancel);
+		return self;
+	}
+	
+	/// Resolves the host and opens the socket with the configured bindings.
+	pub fn build(self) -> Result<Pinger, String> {
+		self.options.check()?;
+		
+		let cancel = match self.cancel {
+			Some(c) => c,
+			None => CancelToken::new()?,
+		};
+		let mut pinger = Pinger::with_cancel(self.host, cancel)?;
+		pinger.options = self.options;
+		pinger.transport = self.transport;
+		pinger.pcap = self.pcap;
+		pinger.set_timeout(self.timeout);
+		pinger.set_kind(self.kind);
+		pinger.init_socket()?;
+		return Ok(pinger);
+	}
 }
 
 pub struct Pinger {
+	host: String,
 	addr: IpAddr,
-	sequence: u16,
-	
-	socket: Option<Socket>,
-	rand: fastrand::Rng,
+	options: SocketOptions,
+	kind: ProbeKind,
 	
+	engine: Option<ProbeEngine>,
+	transport: Option<Arc<dyn Transport>>,
+	pcap: Option<PathBuf>,
 	timeout: Duration,
+	// interrupts hostname resolution
+	cancel: CancelToken,
 }
 
 impl Pinger {
 	pub fn new<S: Into<String>>(addr: S) -> Result<Self, String> {
+		return Self::with_cancel(addr, CancelToken::new()?);
+	}
+	
+	fn with_cancel<S: Into<String>>(addr: S, cancel: CancelToken) -> Result<Self, String> {
 		let timeout = Duration::from_secs(2);
 		debug!(timeout_secs = timeout.as_secs_f32());
 		
-		let addr = addr.into();
-		let addr = match IpAddr::from_str(&addr) {
-			Ok(a) => a,
-			Err(e) => {
-				if let Ok(o) = util::dns_lookup(addr + ":0") {
-					o
-				} else {
-					return Err(e.to_string());
-				}
-			}
-		};
+		let host = addr.into();
+		let addr = Self::resolve(&host, &cancel)?;
 
 		return Ok(Self {
+			host,
 			addr,
-			sequence: 1,
-			socket: None,
-			rand: fastrand::Rng::new(),
+			options: SocketOptions::default(),
+			kind: ProbeKind::Echo,
+			engine: None,
+			transport: None,
+			pcap: None,
 			timeout,
+			cancel,
 		});
 	}
 	
-	pub fn get_dest(&self) -> String {
-		return self.addr.to_string();
-	}
-	
-	pub fn init_socket(&mut self) -> Result<(), String> {
-		let socket = match Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)) {
-			Ok(s) => s,
+	fn resolve(host: &str, cancel: &CancelToken) -> Result<IpAddr, String> {
+		return match IpAddr::from_str(host) {
+			Ok(a) => Ok(a),
 			Err(e) => {
-				error!(desc = e.to_string(), "could not open socket");
-				return Err("could not open socket".to_string());
+				if let Ok(o) = util::dns_lookup(host.to_string() + ":0", cancel) {
+					Ok(o)
+				} else {
+					Err(e.to_string())
+				}
 			}
 		};
-		
-		if let Err(e) = socket.set_ttl(255) {
-			error!(desc = e.to_string(), "could not set socket ttl");
-			return Err("could not set socket ttl".to_string());
+	}
+	
+	/// Resolves the hostname again, switching to the new address if it changed.
+	/// Returns the previous address when a switch happened.
+	pub fn reresolve(&mut self) -> Result<Option<IpAddr>, String> {
+		let addr = Self::resolve(&self.host, &self.cancel)?;
+		if addr == self.addr {
+			return Ok(None);
 		}
-		assert!(socket.ttl().is_ok());
-		trace!(ttl = socket.ttl().unwrap());
 		
-		self.socket = Some(socket);
+		debug!(old = self.addr.to_string(), new = addr.to_string(), "target address changed");
+		let old = self.addr;
+		self.addr = addr;
+		return Ok(Some(old));
+	}
+	
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		debug!(timeout_secs = timeout.as_secs_f32());
+		self.timeout = timeout;
+		if let Some(engine) = self.engine.as_mut() {
+			engine.set_timeout(timeout);
+		}
+	}
+	
+	pub fn set_kind(&mut self, kind: ProbeKind) {
+		debug!(?kind);
+		self.kind = kind;
+		if let Some(engine) = self.engine.as_mut() {
+			engine.set_kind(kind);
+		}
+	}
+	
+	pub fn get_kind(&self) -> ProbeKind {
+		return self.kind;
+	}
+	
+	pub fn get_addr(&self) -> IpAddr {
+		return self.addr;
+	}
+	
+	pub fn get_dest(&self) -> String {
+		return self.addr.to_string();
+	}
+	
+	pub fn builder<S: Into<String>>(host: S) -> PingerBuilder {
+		return PingerBuilder::new(host);
+	}
+	
+	pub fn get_options(&self) -> &SocketOptions {
+		return &self.options;
+	}
+	
+	/// Changes the TOS byte used for the following probes.
+	pub fn set_tos(&mut self, tos: u8) -> Result<(), String> {
+		if let Some(engine) = self.engine.as_mut() {
+			engine.set_tos(tos)?;
+		}
+		self.options.tos = Some(tos);
 		return Ok(());
 	}
 	
-	pub fn ping<T>(&mut self, rx: &Receiver<T>) -> Result<PingReply, String> {
-		if self.socket.is_none() {
-			return Err("invalid socket".to_string());
+	/// Changes the header template used for the following probes. Only
+	/// works if the socket was opened with a raw header.
+	pub fn set_raw_header(&mut self, header: RawHeader) -> Result<(), String> {
+		if self.options.raw_header.is_none() {
+			return Err("socket was not opened with IP_HDRINCL".to_string());
 		}
-		let socket = self.socket.as_mut().unwrap();
-
-		let mut packet = icmp::ICMPPacket {
-			typ: 8,
-			code: 0,
-			checksum: 0,
-			
-			ident: self.rand.u16(..),
-			sequence: self.sequence,
-			payload: ([0; 64]).into_iter().map(|_| self.rand.u8(..)).collect(),
-		};
-		
-		packet.checksum = packet.calculate_checksum();
-		trace!(packet.checksum, packet.ident, packet.sequence);
 		
-		let mut buffer = [0; 72];
-		if let Err(e) = packet.encode(&mut buffer) {
-			error!(desc = e.to_string(), "could not encode packet");
-			return Err("could not encode packet".to_string());
+		if let Some(engine) = self.engine.as_mut() {
+			engine.set_raw_header(Some(header.clone()));
 		}
-		let addr = SocketAddr::new(self.addr, 0);
+		self.options.raw_header = Some(header);
+		return Ok(());
+	}
+	
+	pub fn init_socket(&mut self) -> Result<(), String> {
+		let mut transport: Arc<dyn Transport> = match &self.transport {
+			Some(t) => t.clone(),
+			None => Arc::new(SocketTransport::new(open_socket(&self.options)?)),
+		};
+		let source = self.options.source_v4();
 		
-		let start_time = Instant::now();
-		let bytes = match socket.send_to(&buffer, &addr.into()) {
-			Ok(b) => b,
-			Err(e) => {
-				error!(desc = e.to_string(), "Socket::send_to");
-				return Err("Socket::send_to".to_string());
-			}
+		if let Some(path) = &self.pcap {
+			let writer = pcap::PcapWriter::create(path)?;
+			let options = self.options.record.map_or(Vec::new(), |r| vec![r.header_option()]);
+			transport = Arc::new(CaptureTransport::new(transport, writer, source, SOCKET_TTL, options));
+		}
+		let mut engine = ProbeEngine::new(transport, self.timeout, source)?;
+		engine.set_kind(self.kind);
+		engine.set_raw_header(self.options.raw_header.clone());
+		self.engine = Some(engine);
+		return Ok(());
+	}
+	
+	/// Sends one echo request without waiting for the reply, returning its sequence number.
+	/// The outcome is reported later through `recv_event`.
+	pub fn send(&mut self) -> Result<u16, String> {
+		return match self.engine.as_mut() {
+			Some(engine) => engine.send(self.addr),
+			None => Err("invalid socket".to_string()),
 		};
-		debug!(bytes_sent = bytes);
-		self.sequence += 1;
-
-		let mut elapsed = Duration::from_secs(0);
+	}
+	
+	/// Sends `count` echo requests at once, batched into as few syscalls as
+	/// possible. Returns the sequence numbers of the ones the kernel took.
+	pub fn send_many(&mut self, count: usize) -> Result<Vec<u16>, String> {
+		return match self.engine.as_mut() {
+			Some(engine) => engine.send_many(&vec![self.addr; count]),
+			None => Err("invalid socket".to_string()),
+		};
+	}
+	
+	pub fn recv_event(&self, timeout: Duration) -> Option<ProbeEvent> {
+		return self.engine.as_ref()?.recv_event(timeout);
+	}
+	
+	pub fn in_flight(&self) -> usize {
+		return self.engine.as_ref().map_or(0, |e| e.in_flight());
+	}
+	
+	/// Sends one echo request and blocks until it is answered, times out or `cancel` fires.
+	pub fn ping(&mut self, cancel: &CancelToken) -> Result<PingReply, String> {
+		let sequence = self.send()?;
+		let addr = self.addr;
+		let engine = match self.engine.as_ref() {
+			Some(e) => e,
+			None => return Err("invalid socket".to_string()),
+		};
+		
 		loop {
-			if rx.try_recv().is_ok() {
-				return Err("stop signal".to_string());
-			}
-			
-			if let Err(e) = socket.set_read_timeout(Some(self.timeout - elapsed)) {
-				error!(desc = e.to_string(), "could not set socket read timeout");
-				return Err("could not set socket read timeout".to_string());
-			}
-			
-			let mut buffer: [u8; 2048] = [0; 2048];
-			let bytes = match socket.read(&mut buffer) {
-				Ok(b) => b,
-				Err(e) => {
-					error!(desc = e.to_string(), "could not read from socket");
-					return Err("could not read from socket".to_string());
+			match engine.wait_event(cancel)? {
+				ProbeEvent::Reply(reply) if reply.sequence == sequence && IpAddr::V4(reply.from_addr) == addr => {
+					return Ok(reply);
 				}
-			};
-			
-			debug!(bytes_recv = bytes);
-			
-			let ipv4_packet = match ipv4::IPV4Packet::decode(&buffer) {
-				Ok(p) => p,
-				Err(e) => {
-					error!(desc = e.to_string(), "could not decode packet");
-					return Err("could not decode packet".to_string());
+				ProbeEvent::Timeout { dest, sequence: s } if s == sequence && dest == addr => {
+					return Err("timeout".to_string());
 				}
-			};
-
-			let reply = match icmp::ICMPPacket::decode(&ipv4_packet.data) {
-				Ok(r) => r,
-				Err(_) => continue,
-			};
-
-			if reply.ident == packet.ident && reply.sequence == packet.sequence {
-				return Ok(PingReply {
-					elapsed: start_time.elapsed(),
-					sequence: reply.sequence,
-					
-					from_addr: Ipv4Addr::from_bits(ipv4_packet.from_addr),
-					dest_addr: Ipv4Addr::from_bits(ipv4_packet.dest_addr),
-				});
-			}
-			
-			elapsed = start_time.elapsed();
-			if elapsed >= self.timeout {
-				return Err("timeout".to_string());
+				_ => {}
 			}
 		}
 	}
 }
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use packet::icmp::{
+		Family,
+		IcmpView,
+		Message
+	};
+	
+	const DAY: u32 = MS_PER_DAY;
+	
+	#[test]
+	fn day_diff_wraps_at_midnight() {
+		assert_eq!(day_diff(1_500, 1_000), 500);
+		assert_eq!(day_diff(1_000, 1_500), -500);
+		// just after midnight minus just before it
+		assert_eq!(day_diff(100, DAY - 100), 200);
+		assert_eq!(day_diff(DAY - 100, 100), -200);
+		// more than half a day apart is read as the other direction
+		assert_eq!(day_diff(DAY / 2 + 1, 0), -(DAY as i64 / 2 - 1));
+	}
+	
+	#[test]
+	fn timestamps_across_midnight() {
+		// sent 10 ms before midnight, back 20 ms after it, the remote clock runs 10.5 ms ahead
+		let ts = Timestamps { originate: DAY - 10, receive: 15, transmit: 16, arrived: 20 };
+		
+		assert!(ts.is_standard());
+		assert_eq!(ts.outbound(), 25);
+		assert_eq!(ts.inbound(), 4);
+		assert_eq!(ts.offset(), 10.5);
+	}
+	
+	#[test]
+	fn non_standard_clock() {
+		let ts = Timestamps { originate: 1_000, receive: 1_010, transmit: 1_011, arrived: 1_020 };
+		
+		assert!(ts.is_standard());
+		assert!(!Timestamps { receive: 0x8000_0000 | 1_010, ..ts }.is_standard());
+		assert!(!Timestamps { transmit: 0x8000_0000 | 1_011, ..ts }.is_standard());
+	}
+	
+	#[test]
+	fn reply_arrives_when_it_was_received() {
+		let message = Message::TimestampReply { ident: 7, sequence: 1, originate: 1_000, receive: 1_010, transmit: 1_011, trailing: Vec::new() };
+		let icmp_bytes = message.to_bytes(Family::V4).unwrap();
+		let packet = ipv4::IPV4Packet::new(Ipv4Addr::new(192, 0, 2, 1).to_bits(), Ipv4Addr::LOCALHOST.to_bits(), icmp_bytes);
+		let mut datagram = vec![0; packet.size().unwrap()];
+		packet.encode(&mut datagram).unwrap();
+		
+		let ipv4_packet = ipv4::Ipv4View::new(&datagram).unwrap();
+		let icmp_message = IcmpView::new(ipv4_packet.payload()).unwrap();
+		// a day and 1.02 s after the epoch, whatever the clock says now
+		let arrived = UNIX_EPOCH + Duration::from_millis(DAY as u64 + 1_020);
+		
+		let reply = engine::make_reply(&ipv4_packet, &icmp_message, Duration::from_millis(20), arrived, 0, None);
+		let ts = reply.timestamps.unwrap();
+		assert_eq!((ts.originate, ts.receive, ts.transmit, ts.arrived), (1_000, 1_010, 1_011, 1_020));
+	}
+}
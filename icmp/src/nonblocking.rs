@@ -0,0 +1,469 @@
+use crate::{
+	engine::{
+		build_datagrams,
+		make_reply,
+		parse_reply
+	},
+	open_socket,
+	packet::icmp,
+	schedule::{
+		Mode,
+		Scheduler
+	},
+	session::ProbeOutcome,
+	stats::Statistics,
+	PingReply,
+	SocketOptions,
+};
+use logger::prelude::*;
+
+use std::{
+	collections::VecDeque,
+	io::Read,
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		SocketAddr
+	},
+	str::FromStr,
+	time::{
+		Duration,
+		Instant,
+		SystemTime
+	}
+};
+
+use futures_util::Stream;
+use socket2::Socket;
+use tokio::io::{
+	unix::AsyncFd,
+	Interest
+};
+
+/// Async counterpart of `crate::Pinger`. The socket is registered with the
+/// tokio reactor, so pinging never blocks a runtime thread, and dropping the
+/// `ping` future is all it takes to cancel it.
+pub struct Pinger {
+	host: String,
+	addr: IpAddr,
+	ident: u16,
+	sequence: u16,
+	rand: fastrand::Rng,
+
+	options: SocketOptions,
+	socket: Option<AsyncFd<Socket>>,
+	timeout: Duration,
+}
+
+impl Pinger {
+	pub async fn new<S: Into<String>>(addr: S) -> Result<Self, String> {
+		let timeout = Duration::from_secs(2);
+		debug!(timeout_secs = timeout.as_secs_f32());
+
+		let host = addr.into();
+		let addr = resolve(&host).await?;
+
+		let mut rand = fastrand::Rng::new();
+		return Ok(Self {
+			host,
+			addr,
+			ident: rand.u16(..),
+			sequence: 1,
+			rand,
+
+			options: SocketOptions::default(),
+			socket: None,
+			timeout,
+		});
+	}
+
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		debug!(timeout_secs = timeout.as_secs_f32());
+		self.timeout = timeout;
+	}
+
+	/// Bindings and header settings for the socket, only taken into account
+	/// by the next `init_socket`.
+	pub fn set_options(&mut self, options: SocketOptions) {
+		debug!(?options);
+		self.options = options;
+	}
+
+	pub fn get_options(&self) -> &SocketOptions {
+		return &self.options;
+	}
+
+	pub fn get_addr(&self) -> IpAddr {
+		return self.addr;
+	}
+
+	pub fn get_dest(&self) -> String {
+		return self.addr.to_string();
+	}
+
+	/// Resolves the hostname again, switching to the new address if it changed.
+	/// Returns the previous address when a switch happened.
+	pub async fn reresolve(&mut self) -> Result<Option<IpAddr>, String> {
+		let addr = resolve(&self.host).await?;
+		if addr == self.addr {
+			return Ok(None);
+		}
+
+		debug!(old = self.addr.to_string(), new = addr.to_string(), "target address changed");
+		let old = self.addr;
+		self.addr = addr;
+		return Ok(Some(old));
+	}
+
+	/// Must be called from within a tokio runtime.
+	pub fn init_socket(&mut self) -> Result<(), String> {
+		self.options.check()?;
+		let socket = open_socket(&self.options)?;
+
+		if let Err(e) = socket.set_nonblocking(true) {
+			error!(desc = e.to_string(), "could not set socket nonblocking");
+			return Err("could not set socket nonblocking".to_string());
+		}
+
+		// SAFETY: the AsyncFd owns the socket, so its fd stays open and unchanged for as long as it is registered
+		self.socket = match unsafe { AsyncFd::register(socket) } {
+			Ok(s) => Some(s),
+			Err(e) => {
+				error!(desc = e.to_string(), "could not register socket");
+				return Err("could not register socket".to_string());
+			}
+		};
+
+		return Ok(());
+	}
+
+	pub async fn ping(&mut self) -> Result<PingReply, String> {
+		let (sequence, start_time) = self.send_echo().await?;
+		return self.wait_reply(sequence, start_time).await;
+	}
+	
+	async fn send_echo(&mut self) -> Result<(u16, Instant), String> {
+		let socket = match self.socket.as_ref() {
+			Some(s) => s,
+			None => return Err("invalid socket".to_string()),
+		};
+
+		let sequence = self.sequence;
+		self.sequence = self.sequence.wrapping_add(1);
+
+		let mut payload = [0; 64];
+		self.rand.fill(&mut payload);
+
+		let mut buffer = [0; 72];
+		if let Err(e) = icmp::encode_echo_request(icmp::Family::V4, &mut buffer, self.ident, sequence, &payload) {
+			error!(desc = e.to_string(), "could not encode packet");
+			return Err("could not encode packet".to_string());
+		}
+		let datagrams = match &self.options.raw_header {
+			Some(header) => build_datagrams(header, self.options.source_v4(), self.options.tos.unwrap_or(0), self.addr, &buffer)?,
+			None => vec![Vec::from(buffer)],
+		};
+		let addr = SocketAddr::new(self.addr, 0).into();
+
+		let start_time = Instant::now();
+		for datagram in &datagrams {
+			let bytes = match socket.async_io(Interest::WRITABLE, |s| s.send_to(datagram, &addr)).await {
+				Ok(b) => b,
+				Err(e) => {
+					error!(desc = e.to_string(), "Socket::send_to");
+					return Err("Socket::send_to".to_string());
+				}
+			};
+			debug!(bytes_sent = bytes);
+		}
+
+		return Ok((sequence, start_time));
+	}
+
+	async fn wait_reply(&self, sequence: u16, start_time: Instant) -> Result<PingReply, String> {
+		let socket = match self.socket.as_ref() {
+			Some(s) => s,
+			None => return Err("invalid socket".to_string()),
+		};
+
+		let ident = self.ident;
+		let dest = self.addr;
+		let source = self.options.source_v4();
+		let sent_tos = self.options.tos.unwrap_or(0);
+		let wait_reply = async {
+			let mut buffer: [u8; 2048] = [0; 2048];
+			loop {
+				let bytes = match socket.async_io(Interest::READABLE, |mut s| s.read(&mut buffer)).await {
+					Ok(b) => b,
+					Err(e) => {
+						error!(desc = e.to_string(), "could not read from socket");
+						return Err("could not read from socket".to_string());
+					}
+				};
+				debug!(bytes_recv = bytes);
+				let arrived = SystemTime::now();
+
+				let (ipv4_packet, reply) = match parse_reply(&buffer[..bytes], ident) {
+					Some(r) => r,
+					None => continue,
+				};
+
+				let from_addr = Ipv4Addr::from_bits(ipv4_packet.from_addr());
+				if reply.sequence() == sequence && IpAddr::V4(from_addr) == dest {
+					return Ok(make_reply(&ipv4_packet, &reply, start_time.elapsed(), arrived, sent_tos, source));
+				}
+			}
+		};
+
+		let remaining = self.timeout.saturating_sub(start_time.elapsed());
+		return match tokio::time::timeout(remaining, wait_reply).await {
+			Ok(r) => r,
+			Err(_) => Err("timeout".to_string()),
+		};
+	}
+}
+
+/// Async counterpart of `crate::session::PingSession`. Probes are sent one at
+/// a time; `next` yields the same outcomes and `into_stream` turns the session
+/// into a `Stream`.
+pub struct PingSession {
+	pinger: Pinger,
+	scheduler: Scheduler,
+	mode: Mode,
+	interval: Duration,
+
+	count: Option<u64>,
+	deadline: Option<Duration>,
+	reresolve: Option<Duration>,
+
+	started: Option<Instant>,
+	last_resolve: Instant,
+	sent: u64,
+	awaiting: Option<(u16, Instant)>,
+	stats: Vec<(IpAddr, Statistics)>,
+	pending: VecDeque<ProbeOutcome>,
+}
+
+impl PingSession {
+	/// The pinger must already have its socket initialized.
+	pub fn new(pinger: Pinger) -> Self {
+		let mode = Mode::Fixed;
+		let interval = Duration::from_secs(1);
+		let stats = vec![(pinger.get_addr(), Statistics::new())];
+
+		return Self {
+			pinger,
+			scheduler: Scheduler::new(mode, interval),
+			mode,
+			interval,
+
+			count: None,
+			deadline: None,
+			reresolve: None,
+
+			started: None,
+			last_resolve: Instant::now(),
+			sent: 0,
+			awaiting: None,
+			stats,
+			pending: VecDeque::new(),
+		};
+	}
+
+	pub fn count(mut self, count: Option<u64>) -> Self {
+		self.count = count;
+		return self;
+	}
+
+	pub fn interval(mut self, interval: Duration) -> Self {
+		self.interval = interval;
+		self.scheduler = Scheduler::new(self.mode, interval);
+		return self;
+	}
+
+	pub fn mode(mut self, mode: Mode) -> Self {
+		self.mode = mode;
+		self.scheduler = Scheduler::new(mode, self.interval);
+		return self;
+	}
+
+	/// Stops the session once this much time has passed since the first probe.
+	pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+		self.deadline = deadline;
+		return self;
+	}
+
+	/// Resolves the hostname again on this schedule and follows address changes.
+	pub fn reresolve(mut self, every: Option<Duration>) -> Self {
+		self.reresolve = every;
+		return self;
+	}
+
+	pub fn pinger(&self) -> &Pinger {
+		return &self.pinger;
+	}
+
+	/// Statistics so far, one entry per address the target resolved to.
+	pub fn statistics(&self) -> &[(IpAddr, Statistics)] {
+		return &self.stats;
+	}
+
+	fn stats_for(&mut self, addr: IpAddr) -> &mut Statistics {
+		let i = match self.stats.iter().position(|(a, _)| *a == addr) {
+			Some(i) => i,
+			None => {
+				self.stats.push((addr, Statistics::new()));
+				self.stats.len() - 1
+			}
+		};
+
+		return &mut self.stats[i].1;
+	}
+
+	pub async fn next(&mut self) -> Option<ProbeOutcome> {
+		if let Some(outcome) = self.pending.pop_front() {
+			return Some(outcome);
+		}
+
+		let started = *self.started.get_or_insert_with(Instant::now);
+		let remaining = match self.deadline {
+			Some(d) => d.checked_sub(started.elapsed())?,
+			None => Duration::MAX,
+		};
+
+		return tokio::time::timeout(remaining, self.step()).await.ok().flatten();
+	}
+
+	async fn step(&mut self) -> Option<ProbeOutcome> {
+		if let Some((sequence, start_time)) = self.awaiting.take() {
+			let dest = self.pinger.get_addr();
+			return Some(match self.pinger.wait_reply(sequence, start_time).await {
+				Ok(reply) => {
+					self.stats_for(dest).add_reply(reply.elapsed);
+					self.scheduler.probe_done(true);
+					ProbeOutcome::Reply(reply)
+				}
+				Err(_) => {
+					debug!(sequence, dest = dest.to_string(), "probe timed out");
+					self.scheduler.probe_done(false);
+					ProbeOutcome::Timeout { dest, sequence }
+				}
+			});
+		}
+
+		if self.count.is_some_and(|c| self.sent >= c) {
+			return None;
+		}
+		tokio::time::sleep(self.scheduler.until_due()).await;
+
+		if let Some(every) = self.reresolve && self.last_resolve.elapsed() >= every {
+			self.last_resolve = Instant::now();
+
+			trace!("Pinger::reresolve");
+			match self.pinger.reresolve().await {
+				Ok(Some(old)) => {
+					let new = self.pinger.get_addr();
+					self.stats_for(new);
+					self.pending.push_back(ProbeOutcome::AddressChanged { old, new });
+				}
+				Ok(None) => {}
+				Err(e) => warn!(desc = e, "re-resolving failed, keeping old address"),
+			}
+		}
+
+		let dest = self.pinger.get_addr();
+		self.sent += 1;
+		self.stats_for(dest).add_sent();
+		self.scheduler.sent();
+
+		trace!("Pinger::send_echo");
+		let outcome = match self.pinger.send_echo().await {
+			Ok((sequence, start_time)) => {
+				self.awaiting = Some((sequence, start_time));
+				ProbeOutcome::Sent { dest, sequence, tos: self.pinger.get_options().tos.unwrap_or(0) }
+			}
+			Err(error) => ProbeOutcome::SendFailed { dest, error },
+		};
+		self.pending.push_back(outcome);
+		return self.pending.pop_front();
+	}
+
+	pub fn into_stream(self) -> impl Stream<Item = ProbeOutcome> {
+		return futures_util::stream::unfold(self, |mut session| async move {
+			let outcome = session.next().await?;
+			return Some((outcome, session));
+		});
+	}
+}
+
+async fn resolve(host: &str) -> Result<IpAddr, String> {
+	return match IpAddr::from_str(host) {
+		Ok(a) => Ok(a),
+		Err(e) => {
+			if let Ok(o) = util::dns_lookup_async(host.to_string() + ":0").await {
+				Ok(o)
+			} else {
+				Err(e.to_string())
+			}
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::session::tests::Event;
+
+	use futures_util::StreamExt;
+
+	const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+	async fn pinger() -> Pinger {
+		let mut pinger = Pinger::new("127.0.0.1").await.unwrap();
+		pinger.set_timeout(Duration::from_secs(1));
+		pinger.init_socket().unwrap();
+		return pinger;
+	}
+
+	#[tokio::test]
+	async fn ping() {
+		let mut pinger = pinger().await;
+
+		let reply = pinger.ping().await.unwrap();
+		assert_eq!(IpAddr::V4(reply.from_addr), LOCALHOST);
+		assert_eq!(reply.sequence, 1);
+		assert_eq!(pinger.ping().await.unwrap().sequence, 2);
+	}
+
+	#[tokio::test]
+	async fn options_reach_the_socket() {
+		let mut pinger = Pinger::new("127.0.0.1").await.unwrap();
+		pinger.set_options(SocketOptions { tos: Some(0x28), ..SocketOptions::default() });
+		pinger.init_socket().unwrap();
+
+		let reply = pinger.ping().await.unwrap();
+		assert_eq!((reply.sent_tos, reply.tos), (0x28, 0x28));
+	}
+
+	#[tokio::test]
+	async fn session() {
+		let mut session = PingSession::new(pinger().await).count(Some(2)).interval(Duration::from_millis(10));
+
+		let mut events = Vec::new();
+		while let Some(outcome) = session.next().await {
+			events.push(Event::from(&outcome));
+		}
+
+		assert_eq!(events, vec![Event::Sent(LOCALHOST, 1), Event::Reply(LOCALHOST, 1), Event::Sent(LOCALHOST, 2), Event::Reply(LOCALHOST, 2)]);
+		assert_eq!((session.statistics()[0].1.transmitted, session.statistics()[0].1.received), (2, 2));
+	}
+
+	#[tokio::test]
+	async fn stream_stops_at_the_deadline() {
+		let session = PingSession::new(pinger().await).interval(Duration::from_millis(50)).deadline(Some(Duration::from_millis(120)));
+
+		let events: Vec<Event> = session.into_stream().map(|o| Event::from(&o)).collect().await;
+		assert!(matches!(events.first(), Some(Event::Sent(LOCALHOST, 1))));
+		assert!(events.len() < 10, "{:?}", events);
+	}
+}
@@ -1,98 +1,638 @@
 use logger::prelude::*;
-use super::sum_big_endian_words;
+use super::checksum;
 
-use std::io::Write;
 use byteorder::{BigEndian, ByteOrder};
 
-pub struct ICMPPacket {
-	pub typ: u8,
-	pub code: u8,
-	pub checksum: u16,
-	pub ident: u16,
-	pub sequence: u16,
-	pub payload: Vec<u8>,
+pub const ECHO_REPLY: u8 = 0;
+pub const DEST_UNREACHABLE: u8 = 3;
+pub const REDIRECT: u8 = 5;
+pub const ECHO_REQUEST: u8 = 8;
+pub const TIME_EXCEEDED: u8 = 11;
+pub const PARAMETER_PROBLEM: u8 = 12;
+pub const TIMESTAMP_REQUEST: u8 = 13;
+pub const TIMESTAMP_REPLY: u8 = 14;
+
+pub const V6_DEST_UNREACHABLE: u8 = 1;
+pub const V6_PACKET_TOO_BIG: u8 = 2;
+pub const V6_TIME_EXCEEDED: u8 = 3;
+pub const V6_PARAMETER_PROBLEM: u8 = 4;
+pub const V6_ECHO_REQUEST: u8 = 128;
+pub const V6_ECHO_REPLY: u8 = 129;
+
+/// Which ICMP the bytes on the wire are. The same message has different
+/// type numbers in ICMP and ICMPv6, and some only exist in one of them.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Family {
+	V4,
+	V6,
 }
 
-impl ICMPPacket {
-	pub fn calculate_checksum(&self) -> u16 {
-		// First sum the pseudo header
-		let mut sum = 0u32;
-		
-		// Then sum the len of the message bytes and then the message bytes starting
-		// with the message type field and with the checksum field set to 0.
-		let bytes = self.get_bytes(false);
-		sum += sum_big_endian_words(&bytes);
-		
-		// handle the carry
-		while sum >> 16 != 0 {
-			sum = (sum >> 16) + (sum & 0xFFFF);
-		}
-		return !sum as u16;
-	}
-	
-	pub fn get_bytes(&self, with_checksum: bool) -> Vec<u8> {
-		let mut bytes = Vec::new();
-		bytes.push(self.typ);
-		bytes.push(self.code);
-		let mut buf = vec![0; 2];
-		BigEndian::write_u16(&mut buf, if with_checksum { self.checksum } else { 0 });
-		bytes.append(&mut buf);
-		
-		bytes.append(&mut {
-			let mut bytes = Vec::with_capacity(20);
-			let mut buf = vec![0; 2];
-			BigEndian::write_u16(&mut buf, self.ident);
-			bytes.append(&mut buf);
-			buf.resize(2, 0);
-			BigEndian::write_u16(&mut buf, self.sequence);
-			bytes.append(&mut buf);
-			bytes.extend_from_slice(&self.payload);
-			bytes
-		});
-		
-		// bytes.append(&mut self.message.get_bytes());
-		return bytes;
-	}
+/// A decoded ICMP or ICMPv6 message. Error messages keep the start of the
+/// datagram that caused them in `original`.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Message {
+	EchoRequest {
+		ident: u16,
+		sequence: u16,
+		payload: Vec<u8>,
+	},
+	EchoReply {
+		ident: u16,
+		sequence: u16,
+		payload: Vec<u8>,
+	},
+	/// `rest` is the second header word, which carries the next-hop MTU of
+	/// a fragmentation needed message in its low 16 bits.
+	DestUnreachable {
+		code: u8,
+		rest: [u8; 4],
+		original: Vec<u8>,
+	},
+	/// ICMPv6 only.
+	PacketTooBig {
+		mtu: u32,
+		original: Vec<u8>,
+	},
+	TimeExceeded {
+		code: u8,
+		rest: [u8; 4],
+		original: Vec<u8>,
+	},
+	/// ICMP only.
+	Redirect {
+		code: u8,
+		gateway: u32,
+		original: Vec<u8>,
+	},
+	/// `pointer` is a single byte in ICMP, followed by the three bytes in
+	/// `rest`, and a full word in ICMPv6, where `rest` is always zero.
+	ParameterProblem {
+		code: u8,
+		pointer: u32,
+		rest: [u8; 3],
+		original: Vec<u8>,
+	},
+	/// ICMP only. Times are milliseconds since midnight UTC (RFC 792).
+	/// `trailing` holds whatever follows the three times.
+	TimestampRequest {
+		ident: u16,
+		sequence: u16,
+		originate: u32,
+		receive: u32,
+		transmit: u32,
+		trailing: Vec<u8>,
+	},
+	/// ICMP only.
+	TimestampReply {
+		ident: u16,
+		sequence: u16,
+		originate: u32,
+		receive: u32,
+		transmit: u32,
+		trailing: Vec<u8>,
+	},
+	/// Anything else, kept as is so it can be encoded again unchanged.
+	Unknown {
+		typ: u8,
+		code: u8,
+		header: [u8; 4],
+		body: Vec<u8>,
+	},
+}
 
-	pub fn encode(&self, buffer: &mut [u8]) -> Result<(), String> {
-		buffer[0] = self.typ;
-		buffer[1] = self.code;
+impl Message {
+	pub fn echo_request(ident: u16, sequence: u16, payload: Vec<u8>) -> Self {
+		return Message::EchoRequest { ident, sequence, payload };
+	}
+	
+	/// `originate` is the current time in milliseconds since midnight UTC.
+	pub fn timestamp_request(ident: u16, sequence: u16, originate: u32) -> Self {
+		return Message::TimestampRequest {
+			ident,
+			sequence,
+			originate,
+			receive: 0,
+			transmit: 0,
+			trailing: Vec::new(),
+		};
+	}
+	
+	/// Type and code for the given family, or None if the message does not exist there.
+	pub fn type_code(&self, family: Family) -> Option<(u8, u8)> {
+		let v4 = family == Family::V4;
+		return match self {
+			Message::EchoRequest { .. } => Some((if v4 { ECHO_REQUEST } else { V6_ECHO_REQUEST }, 0)),
+			Message::EchoReply { .. } => Some((if v4 { ECHO_REPLY } else { V6_ECHO_REPLY }, 0)),
+			Message::DestUnreachable { code, .. } => Some((if v4 { DEST_UNREACHABLE } else { V6_DEST_UNREACHABLE }, *code)),
+			Message::PacketTooBig { .. } if !v4 => Some((V6_PACKET_TOO_BIG, 0)),
+			Message::TimeExceeded { code, .. } => Some((if v4 { TIME_EXCEEDED } else { V6_TIME_EXCEEDED }, *code)),
+			Message::Redirect { code, .. } if v4 => Some((REDIRECT, *code)),
+			Message::ParameterProblem { code, .. } => Some((if v4 { PARAMETER_PROBLEM } else { V6_PARAMETER_PROBLEM }, *code)),
+			Message::TimestampRequest { .. } if v4 => Some((TIMESTAMP_REQUEST, 0)),
+			Message::TimestampReply { .. } if v4 => Some((TIMESTAMP_REPLY, 0)),
+			Message::Unknown { typ, code, .. } => Some((*typ, *code)),
+			_ => None,
+		};
+	}
+	
+	pub fn ident(&self) -> Option<u16> {
+		return match self {
+			Message::EchoRequest { ident, .. }
+			| Message::EchoReply { ident, .. }
+			| Message::TimestampRequest { ident, .. }
+			| Message::TimestampReply { ident, .. } => Some(*ident),
+			_ => None,
+		};
+	}
+	
+	pub fn sequence(&self) -> Option<u16> {
+		return match self {
+			Message::EchoRequest { sequence, .. }
+			| Message::EchoReply { sequence, .. }
+			| Message::TimestampRequest { sequence, .. }
+			| Message::TimestampReply { sequence, .. } => Some(*sequence),
+			_ => None,
+		};
+	}
+	
+	/// Size of the encoded message in bytes, header included.
+	pub fn size(&self) -> usize {
+		return 8 + match self {
+			Message::EchoRequest { payload, .. } | Message::EchoReply { payload, .. } => payload.len(),
+			Message::DestUnreachable { original, .. }
+			| Message::PacketTooBig { original, .. }
+			| Message::TimeExceeded { original, .. }
+			| Message::Redirect { original, .. }
+			| Message::ParameterProblem { original, .. } => original.len(),
+			Message::TimestampRequest { trailing, .. } | Message::TimestampReply { trailing, .. } => 12 + trailing.len(),
+			Message::Unknown { body, .. } => body.len(),
+		};
+	}
+	
+	/// Writes the message into `buffer` and returns how many bytes were used.
+	/// The ICMP checksum is filled in. ICMPv6 leaves it zero, because it covers
+	/// a pseudo header only the kernel knows, and raw ICMPv6 sockets always
+	/// compute it on send.
+	pub fn encode(&self, family: Family, buffer: &mut [u8]) -> Result<usize, String> {
+		let (typ, code) = match self.type_code(family) {
+			Some(t) => t,
+			None => return Err("message does not exist in this icmp version".to_string()),
+		};
 		
-		buffer[2] = (self.checksum >> 8) as u8;
-		buffer[3] = (self.checksum & 0xff) as u8;
+		let size = self.size();
+		if buffer.len() < size {
+			return Err("buffer too small".to_string());
+		}
+		let buffer = &mut buffer[..size];
 		
-		buffer[4] = (self.ident >> 8) as u8;
-		buffer[5] = self.ident as u8;
-		buffer[6] = (self.sequence >> 8) as u8;
-		buffer[7] = self.sequence as u8;
+		buffer[0] = typ;
+		buffer[1] = code;
+		buffer[2] = 0;
+		buffer[3] = 0;
 		
-		if let Err(e) = (&mut buffer[8..]).write_all(self.payload.as_slice()) {
-			return Err(e.to_string());
+		let (header, body) = buffer[4..].split_at_mut(4);
+		match self {
+			Message::EchoRequest { ident, sequence, payload } | Message::EchoReply { ident, sequence, payload } => {
+				BigEndian::write_u16(&mut header[0..2], *ident);
+				BigEndian::write_u16(&mut header[2..4], *sequence);
+				body.copy_from_slice(payload);
+			}
+			Message::DestUnreachable { rest, original, .. } | Message::TimeExceeded { rest, original, .. } => {
+				header.copy_from_slice(rest);
+				body.copy_from_slice(original);
+			}
+			Message::PacketTooBig { mtu, original } => {
+				BigEndian::write_u32(header, *mtu);
+				body.copy_from_slice(original);
+			}
+			Message::Redirect { gateway, original, .. } => {
+				BigEndian::write_u32(header, *gateway);
+				body.copy_from_slice(original);
+			}
+			Message::ParameterProblem { pointer, rest, original, .. } => {
+				match family {
+					Family::V4 => {
+						if *pointer > u8::MAX as u32 {
+							return Err("pointer does not fit in one byte".to_string());
+						}
+						header[0] = *pointer as u8;
+						header[1..4].copy_from_slice(rest);
+					}
+					Family::V6 => {
+						if *rest != [0; 3] {
+							return Err("icmpv6 pointer takes the whole word".to_string());
+						}
+						BigEndian::write_u32(header, *pointer);
+					}
+				}
+				body.copy_from_slice(original);
+			}
+			Message::TimestampRequest { ident, sequence, originate, receive, transmit, trailing }
+			| Message::TimestampReply { ident, sequence, originate, receive, transmit, trailing } => {
+				BigEndian::write_u16(&mut header[0..2], *ident);
+				BigEndian::write_u16(&mut header[2..4], *sequence);
+				BigEndian::write_u32(&mut body[0..4], *originate);
+				BigEndian::write_u32(&mut body[4..8], *receive);
+				BigEndian::write_u32(&mut body[8..12], *transmit);
+				body[12..].copy_from_slice(trailing);
+			}
+			Message::Unknown { header: h, body: b, .. } => {
+				header.copy_from_slice(h);
+				body.copy_from_slice(b);
+			}
 		}
 		
-		return Ok(());
+		if family == Family::V4 {
+			let checksum = checksum(buffer);
+			BigEndian::write_u16(&mut buffer[2..4], checksum);
+		}
+		
+		trace!(typ, code, size);
+		return Ok(size);
+	}
+	
+	/// Encodes into a new buffer.
+	pub fn to_bytes(&self, family: Family) -> Result<Vec<u8>, String> {
+		let mut buffer = vec![0; self.size()];
+		self.encode(family, &mut buffer)?;
+		return Ok(buffer);
 	}
 	
-	pub fn decode(buffer: &[u8]) -> Result<Self, String> {
-		if buffer.as_ref().len() < 8 {
+	/// Decodes a message without verifying its checksum. Callers reading ICMP
+	/// off a raw socket must check it with `packet::checksum` first, as the
+	/// engine does; ICMPv6 checksums cover a pseudo header and are checked by
+	/// the kernel.
+	pub fn decode(family: Family, buffer: &[u8]) -> Result<Self, String> {
+		if buffer.len() < 8 {
 			return Err("invalid size".to_string());
 		}
 		
-		trace!(type = buffer[0], code = buffer[1]);		
-		if buffer[0] != 0 || buffer[1] != 0 {
+		let (typ, code) = (buffer[0], buffer[1]);
+		trace!(type = typ, code);
+		
+		let header = &buffer[4..8];
+		let body = &buffer[8..];
+		let ident = BigEndian::read_u16(&header[0..2]);
+		let sequence = BigEndian::read_u16(&header[2..4]);
+		let word = BigEndian::read_u32(header);
+		let rest = [header[0], header[1], header[2], header[3]];
+		
+		let message = match (family, typ) {
+			(Family::V4, ECHO_REQUEST) | (Family::V6, V6_ECHO_REQUEST) => Message::EchoRequest {
+				ident,
+				sequence,
+				payload: Vec::from(body),
+			},
+			(Family::V4, ECHO_REPLY) | (Family::V6, V6_ECHO_REPLY) => Message::EchoReply {
+				ident,
+				sequence,
+				payload: Vec::from(body),
+			},
+			(Family::V4, DEST_UNREACHABLE) | (Family::V6, V6_DEST_UNREACHABLE) => Message::DestUnreachable {
+				code,
+				rest,
+				original: Vec::from(body),
+			},
+			(Family::V6, V6_PACKET_TOO_BIG) => Message::PacketTooBig {
+				mtu: word,
+				original: Vec::from(body),
+			},
+			(Family::V4, TIME_EXCEEDED) | (Family::V6, V6_TIME_EXCEEDED) => Message::TimeExceeded {
+				code,
+				rest,
+				original: Vec::from(body),
+			},
+			(Family::V4, REDIRECT) => Message::Redirect {
+				code,
+				gateway: word,
+				original: Vec::from(body),
+			},
+			(Family::V4, PARAMETER_PROBLEM) | (Family::V6, V6_PARAMETER_PROBLEM) => Message::ParameterProblem {
+				code,
+				pointer: if family == Family::V4 { header[0] as u32 } else { word },
+				rest: if family == Family::V4 { [header[1], header[2], header[3]] } else { [0; 3] },
+				original: Vec::from(body),
+			},
+			(Family::V4, TIMESTAMP_REQUEST | TIMESTAMP_REPLY) => {
+				if body.len() < 12 {
+					return Err("invalid size".to_string());
+				}
+				
+				let (originate, receive, transmit) = (
+					BigEndian::read_u32(&body[0..4]),
+					BigEndian::read_u32(&body[4..8]),
+					BigEndian::read_u32(&body[8..12]),
+				);
+				let trailing = Vec::from(&body[12..]);
+				
+				if typ == TIMESTAMP_REQUEST {
+					Message::TimestampRequest { ident, sequence, originate, receive, transmit, trailing }
+				} else {
+					Message::TimestampReply { ident, sequence, originate, receive, transmit, trailing }
+				}
+			}
+			_ => Message::Unknown {
+				typ,
+				code,
+				header: [header[0], header[1], header[2], header[3]],
+				body: Vec::from(body),
+			},
+		};
+		
+		// echo and timestamp messages only have code 0, anything else is not the message we think it is
+		if message.type_code(family).is_some_and(|(_, c)| c != code) {
 			return Err("invalid packet".to_string());
 		}
 		
-		let ident = (u16::from(buffer[4]) << 8) + u16::from(buffer[5]);
-		let sequence = (u16::from(buffer[6]) << 8) + u16::from(buffer[7]);
+		return Ok(message);
+	}
+}
+
+/// Borrowed view of an ICMP or ICMPv6 message, read in place from the
+/// receive buffer. Only the fixed 8 byte header is validated up front.
+#[derive(Clone, Copy)]
+pub struct IcmpView<'a> {
+	buffer: &'a [u8],
+}
+
+impl<'a> IcmpView<'a> {
+	pub fn new(buffer: &'a [u8]) -> Result<Self, String> {
+		if buffer.len() < 8 {
+			return Err("invalid size".to_string());
+		}
+		
+		return Ok(Self { buffer });
+	}
+	
+	pub fn typ(&self) -> u8 {
+		return self.buffer[0];
+	}
+	
+	pub fn code(&self) -> u8 {
+		return self.buffer[1];
+	}
+	
+	pub fn checksum(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[2..4]);
+	}
+	
+	/// Only meaningful for echo and timestamp messages.
+	pub fn ident(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[4..6]);
+	}
+	
+	/// Only meaningful for echo and timestamp messages.
+	pub fn sequence(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[6..8]);
+	}
+	
+	/// Everything after the 8 byte header: the echo payload, the timestamps or
+	/// the original datagram of an error message.
+	pub fn body(&self) -> &'a [u8] {
+		return &self.buffer[8..];
+	}
+	
+	pub fn is_echo_reply(&self, family: Family) -> bool {
+		let typ = if family == Family::V4 { ECHO_REPLY } else { V6_ECHO_REPLY };
+		return self.typ() == typ && self.code() == 0;
+	}
+	
+	pub fn is_timestamp_reply(&self, family: Family) -> bool {
+		return family == Family::V4 && self.typ() == TIMESTAMP_REPLY && self.code() == 0 && self.body().len() >= 12;
+	}
+	
+	/// Originate, receive and transmit times of a timestamp reply.
+	pub fn timestamps(&self, family: Family) -> Option<(u32, u32, u32)> {
+		if !self.is_timestamp_reply(family) {
+			return None;
+		}
 		
-		return Ok(ICMPPacket {
-			typ: buffer[0],
-			code: buffer[1],
-			checksum: 0,
-			ident,
-			sequence,
-			payload: Vec::from(&buffer[8..]),
-		});
+		let body = self.body();
+		return Some((
+			BigEndian::read_u32(&body[0..4]),
+			BigEndian::read_u32(&body[4..8]),
+			BigEndian::read_u32(&body[8..12]),
+		));
+	}
+	
+	/// Decodes into an owned `Message`.
+	pub fn to_message(&self, family: Family) -> Result<Message, String> {
+		return Message::decode(family, self.buffer);
+	}
+}
+
+/// Writes an echo request straight into `buffer` without building a
+/// `Message` first, and returns how many bytes were used.
+pub fn encode_echo_request(family: Family, buffer: &mut [u8], ident: u16, sequence: u16, payload: &[u8]) -> Result<usize, String> {
+	let typ = if family == Family::V4 { ECHO_REQUEST } else { V6_ECHO_REQUEST };
+	let size = 8 + payload.len();
+	if buffer.len() < size {
+		return Err("buffer too small".to_string());
+	}
+	
+	buffer[8..size].copy_from_slice(payload);
+	return Ok(finish_header(family, &mut buffer[..size], typ, ident, sequence));
+}
+
+/// Writes a timestamp request straight into `buffer` and returns how many bytes were used.
+pub fn encode_timestamp_request(buffer: &mut [u8], ident: u16, sequence: u16, originate: u32) -> Result<usize, String> {
+	if buffer.len() < 20 {
+		return Err("buffer too small".to_string());
+	}
+	
+	BigEndian::write_u32(&mut buffer[8..12], originate);
+	buffer[12..20].fill(0);
+	return Ok(finish_header(Family::V4, &mut buffer[..20], TIMESTAMP_REQUEST, ident, sequence));
+}
+
+/// Fills in the header of an echo style message whose body is already in place.
+fn finish_header(family: Family, buffer: &mut [u8], typ: u8, ident: u16, sequence: u16) -> usize {
+	buffer[0] = typ;
+	buffer[1] = 0;
+	buffer[2] = 0;
+	buffer[3] = 0;
+	BigEndian::write_u16(&mut buffer[4..6], ident);
+	BigEndian::write_u16(&mut buffer[6..8], sequence);
+	
+	if family == Family::V4 {
+		let checksum = checksum(buffer);
+		BigEndian::write_u16(&mut buffer[2..4], checksum);
+	}
+	
+	return buffer.len();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::{
+		collection::vec,
+		prelude::*
+	};
+	
+	fn messages(family: Family) -> Vec<Message> {
+		let original = vec![0x45, 0, 0, 28, 0x12, 0x34, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2, 8, 0, 0xf7, 0xff, 0, 0, 0, 0];
+		let mut messages = vec![
+			Message::echo_request(0x1234, 7, vec![1, 2, 3, 4, 5]),
+			Message::EchoReply { ident: 0x1234, sequence: 7, payload: Vec::new() },
+			Message::DestUnreachable { code: 4, rest: [0, 0, 0x05, 0x78], original: original.clone() },
+			Message::TimeExceeded { code: 1, rest: [0, 7, 0, 0], original: original.clone() },
+			Message::Unknown { typ: 200, code: 9, header: [1, 2, 3, 4], body: vec![5, 6, 7] },
+		];
+		
+		match family {
+			Family::V4 => messages.extend([
+				Message::Redirect { code: 1, gateway: 0x0a000001, original: original.clone() },
+				Message::ParameterProblem { code: 0, pointer: 20, rest: [1, 2, 3], original: original.clone() },
+				Message::timestamp_request(1, 2, 3_600_000),
+				Message::TimestampReply { ident: 1, sequence: 2, originate: 3, receive: 4, transmit: 5, trailing: vec![0xaa; 4] },
+			]),
+			Family::V6 => messages.extend([
+				Message::PacketTooBig { mtu: 1280, original: original.clone() },
+				Message::ParameterProblem { code: 1, pointer: 0x10000, rest: [0; 3], original },
+			]),
+		}
+		
+		return messages;
+	}
+	
+	#[test]
+	fn round_trip() {
+		for family in [Family::V4, Family::V6] {
+			for message in messages(family) {
+				let bytes = message.to_bytes(family).unwrap();
+				assert_eq!(bytes.len(), message.size());
+				assert_eq!(Message::decode(family, &bytes).unwrap(), message, "{:?}", family);
+				
+				if family == Family::V4 {
+					assert_eq!(checksum(&bytes), 0);
+				}
+			}
+		}
+	}
+	
+	#[test]
+	fn view_matches_message() {
+		for family in [Family::V4, Family::V6] {
+			for message in messages(family) {
+				let bytes = message.to_bytes(family).unwrap();
+				let view = IcmpView::new(&bytes).unwrap();
+				
+				assert_eq!(Some((view.typ(), view.code())), message.type_code(family));
+				assert_eq!(view.to_message(family).unwrap(), message);
+				if let (Some(ident), Some(sequence)) = (message.ident(), message.sequence()) {
+					assert_eq!((view.ident(), view.sequence()), (ident, sequence));
+				}
+			}
+		}
+	}
+	
+	#[test]
+	fn frag_needed_keeps_next_hop_mtu() {
+		let mut bytes = vec![DEST_UNREACHABLE, 4, 0, 0, 0, 0, 0x05, 0x78, 0x45, 0, 0, 20];
+		let sum = checksum(&bytes);
+		BigEndian::write_u16(&mut bytes[2..4], sum);
+		
+		let message = Message::decode(Family::V4, &bytes).unwrap();
+		assert!(matches!(&message, Message::DestUnreachable { rest, .. } if BigEndian::read_u16(&rest[2..4]) == 1400));
+		assert_eq!(message.to_bytes(Family::V4).unwrap(), bytes);
+	}
+	
+	#[test]
+	fn packet_too_big_keeps_mtu() {
+		let bytes = vec![V6_PACKET_TOO_BIG, 0, 0, 0, 0, 0, 0x05, 0x00, 0x60, 0, 0, 0];
+		
+		let message = Message::decode(Family::V6, &bytes).unwrap();
+		assert!(matches!(&message, Message::PacketTooBig { mtu: 1280, .. }));
+		assert_eq!(message.to_bytes(Family::V6).unwrap(), bytes);
 	}
-}
\ No newline at end of file
+	
+	#[test]
+	fn encodes_in_place_like_message() {
+		let payload = [0x5a; 56];
+		let mut buffer = [0; 64];
+		
+		for family in [Family::V4, Family::V6] {
+			let size = encode_echo_request(family, &mut buffer, 0xbeef, 3, &payload).unwrap();
+			assert_eq!(&buffer[..size], Message::echo_request(0xbeef, 3, payload.to_vec()).to_bytes(family).unwrap());
+		}
+		
+		let size = encode_timestamp_request(&mut buffer, 0xbeef, 3, 1234).unwrap();
+		assert_eq!(&buffer[..size], Message::timestamp_request(0xbeef, 3, 1234).to_bytes(Family::V4).unwrap());
+	}
+	
+	#[test]
+	fn rejects_what_cannot_be_encoded() {
+		let original = Vec::new();
+		
+		assert!(Message::ParameterProblem { code: 0, pointer: 256, rest: [0; 3], original: original.clone() }.to_bytes(Family::V4).is_err());
+		assert!(Message::ParameterProblem { code: 0, pointer: 1, rest: [1, 0, 0], original: original.clone() }.to_bytes(Family::V6).is_err());
+		assert!(Message::PacketTooBig { mtu: 1280, original }.to_bytes(Family::V4).is_err());
+		assert!(Message::timestamp_request(1, 1, 0).to_bytes(Family::V6).is_err());
+		assert!(Message::echo_request(1, 1, vec![0; 8]).encode(Family::V4, &mut [0; 15]).is_err());
+	}
+	
+	fn body() -> impl Strategy<Value = Vec<u8>> {
+		return vec(any::<u8>(), 0..64);
+	}
+	
+	/// Any message that decodes back to itself in `family`: echo and timestamp
+	/// messages have code 0, and unknown ones a type the decoder does not know.
+	fn message(family: Family) -> BoxedStrategy<Message> {
+		let known: &[u8] = match family {
+			Family::V4 => &[ECHO_REPLY, DEST_UNREACHABLE, REDIRECT, ECHO_REQUEST, TIME_EXCEEDED, PARAMETER_PROBLEM, TIMESTAMP_REQUEST, TIMESTAMP_REPLY],
+			Family::V6 => &[V6_DEST_UNREACHABLE, V6_PACKET_TOO_BIG, V6_TIME_EXCEEDED, V6_PARAMETER_PROBLEM, V6_ECHO_REQUEST, V6_ECHO_REPLY],
+		};
+		
+		let both = prop_oneof![
+			(any::<u16>(), any::<u16>(), body()).prop_map(|(ident, sequence, payload)| Message::EchoRequest { ident, sequence, payload }),
+			(any::<u16>(), any::<u16>(), body()).prop_map(|(ident, sequence, payload)| Message::EchoReply { ident, sequence, payload }),
+			(any::<u8>(), any::<[u8; 4]>(), body()).prop_map(|(code, rest, original)| Message::DestUnreachable { code, rest, original }),
+			(any::<u8>(), any::<[u8; 4]>(), body()).prop_map(|(code, rest, original)| Message::TimeExceeded { code, rest, original }),
+			(any::<u8>().prop_filter("known type", move |t| !known.contains(t)), any::<u8>(), any::<[u8; 4]>(), body())
+				.prop_map(|(typ, code, header, body)| Message::Unknown { typ, code, header, body }),
+		];
+		
+		return match family {
+			Family::V4 => prop_oneof![
+				both,
+				(any::<u8>(), any::<u32>(), body()).prop_map(|(code, gateway, original)| Message::Redirect { code, gateway, original }),
+				(any::<u8>(), any::<u8>(), any::<[u8; 3]>(), body())
+					.prop_map(|(code, pointer, rest, original)| Message::ParameterProblem { code, pointer: pointer as u32, rest, original }),
+				(any::<u16>(), any::<u16>(), any::<[u32; 3]>(), body())
+					.prop_map(|(ident, sequence, [originate, receive, transmit], trailing)| Message::TimestampRequest { ident, sequence, originate, receive, transmit, trailing }),
+				(any::<u16>(), any::<u16>(), any::<[u32; 3]>(), body())
+					.prop_map(|(ident, sequence, [originate, receive, transmit], trailing)| Message::TimestampReply { ident, sequence, originate, receive, transmit, trailing }),
+			].boxed(),
+			Family::V6 => prop_oneof![
+				both,
+				(any::<u32>(), body()).prop_map(|(mtu, original)| Message::PacketTooBig { mtu, original }),
+				(any::<u8>(), any::<u32>(), body()).prop_map(|(code, pointer, original)| Message::ParameterProblem { code, pointer, rest: [0; 3], original }),
+			].boxed(),
+		};
+	}
+	
+	fn round_trips(family: Family, message: &Message) -> Result<(), TestCaseError> {
+		let bytes = message.to_bytes(family).unwrap();
+		prop_assert_eq!(bytes.len(), message.size());
+		prop_assert_eq!(&Message::decode(family, &bytes).unwrap(), message);
+		prop_assert_eq!(&IcmpView::new(&bytes).unwrap().to_message(family).unwrap(), message);
+		
+		if family == Family::V4 {
+			prop_assert_eq!(checksum(&bytes), 0);
+		}
+		return Ok(());
+	}
+	
+	proptest! {
+		#[test]
+		fn v4_messages_round_trip(message in message(Family::V4)) {
+			round_trips(Family::V4, &message)?;
+		}
+		
+		#[test]
+		fn v6_messages_round_trip(message in message(Family::V6)) {
+			round_trips(Family::V6, &message)?;
+		}
+	}
+}
@@ -1,20 +1,182 @@
 use logger::prelude::*;
+use super::checksum;
 
-#[derive(PartialEq)]
+use byteorder::{BigEndian, ByteOrder};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum IPV4Protocol {
 	Icmp,
 }
 
 impl IPV4Protocol {
-	fn decode(data: u8) -> Option<Self> {
+	pub(crate) fn decode(data: u8) -> Option<Self> {
 		return match data {
 			1 => Some(IPV4Protocol::Icmp),
 			_ => None,
 		};
 	}
+	
+	fn encode(&self) -> u8 {
+		return match self {
+			IPV4Protocol::Icmp => 1,
+		};
+	}
+}
+
+/// Bits of `frag_off`, the low 13 bits are the fragment offset in units of 8 bytes.
+pub const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+pub const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+pub const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+const MAX_OPTIONS_SIZE: usize = 40;
+
+pub const OPTION_END: u8 = 0;
+pub const OPTION_NOP: u8 = 1;
+pub const OPTION_RECORD_ROUTE: u8 = 7;
+pub const OPTION_TIMESTAMP: u8 = 68;
+
+/// Flag of a timestamp option, says what each slot holds.
+pub const TIMESTAMP_ONLY: u8 = 0;
+pub const TIMESTAMP_AND_ADDRESS: u8 = 1;
+
+/// An IP header option as found on a received packet.
+#[derive(PartialEq, Clone, Debug)]
+pub enum IPV4Option {
+	/// Addresses recorded so far, in the order the packet passed them.
+	RecordRoute(Vec<u32>),
+	/// Recorded timestamps in ms since midnight UTC, each with the address
+	/// that stamped it when the option asked for one. `overflow` counts the
+	/// hops that could not record because the option was full.
+	Timestamp {
+		entries: Vec<(Option<u32>, u32)>,
+		overflow: u8,
+	},
+	Other {
+		kind: u8,
+		data: Vec<u8>,
+	},
+}
+
+impl IPV4Option {
+	/// Parses the option bytes between the fixed header and the payload.
+	pub fn decode_all(buffer: &[u8]) -> Vec<Self> {
+		let mut options = Vec::new();
+		let mut rest = buffer;
+		
+		while let Some(&kind) = rest.first() {
+			match kind {
+				OPTION_END => break,
+				OPTION_NOP => {
+					rest = &rest[1..];
+					continue;
+				}
+				_ => {}
+			}
+			
+			let len = match rest.get(1) {
+				Some(&l) if l >= 2 && (l as usize) <= rest.len() => l as usize,
+				_ => {
+					debug!(kind, "truncated ip option");
+					break;
+				}
+			};
+			
+			options.push(Self::decode(kind, &rest[2..len]));
+			rest = &rest[len..];
+		}
+		
+		return options;
+	}
+	
+	/// Encodes the option. Recorded routes and timestamps are written as full,
+	/// with the pointer past the last entry; use `Other` to leave free slots.
+	/// Fails when the option would not fit in an IP header.
+	pub fn encode(&self) -> Result<Vec<u8>, String> {
+		let (kind, data) = match self {
+			IPV4Option::RecordRoute(route) => {
+				if 3 + 4 * route.len() > MAX_OPTIONS_SIZE {
+					return Err("record route option too long".to_string());
+				}
+				
+				let mut data = vec![4 + 4 * route.len() as u8];
+				for addr in route {
+					data.extend_from_slice(&addr.to_be_bytes());
+				}
+				(OPTION_RECORD_ROUTE, data)
+			}
+			IPV4Option::Timestamp { entries, overflow } => {
+				let with_address = entries.iter().any(|(a, _)| a.is_some());
+				let slot = if with_address { 8 } else { 4 };
+				let flag = if with_address { TIMESTAMP_AND_ADDRESS } else { TIMESTAMP_ONLY };
+				
+				if 4 + slot * entries.len() > MAX_OPTIONS_SIZE {
+					return Err("timestamp option too long".to_string());
+				}
+				// the overflow counter is the high nibble of the flags byte
+				if *overflow > 0x0f {
+					return Err("timestamp overflow does not fit in 4 bits".to_string());
+				}
+				
+				let mut data = vec![5 + (slot * entries.len()) as u8, (overflow << 4) | flag];
+				for (addr, ms) in entries {
+					if with_address {
+						data.extend_from_slice(&addr.unwrap_or(0).to_be_bytes());
+					}
+					data.extend_from_slice(&ms.to_be_bytes());
+				}
+				(OPTION_TIMESTAMP, data)
+			}
+			IPV4Option::Other { kind, data } => (*kind, data.clone()),
+		};
+		
+		if 2 + data.len() > MAX_OPTIONS_SIZE {
+			return Err("ip option too long".to_string());
+		}
+		
+		let mut bytes = vec![kind, 2 + data.len() as u8];
+		bytes.extend_from_slice(&data);
+		return Ok(bytes);
+	}
+	
+	/// Whether the option has to be repeated in every fragment.
+	pub fn copied(&self) -> bool {
+		return match self {
+			IPV4Option::Other { kind, .. } => kind & 0x80 != 0,
+			_ => false,
+		};
+	}
+	
+	/// `data` is the option without its kind and length bytes.
+	fn decode(kind: u8, data: &[u8]) -> Self {
+		let word = |b: &[u8]| ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32);
+		
+		return match (kind, data) {
+			(OPTION_RECORD_ROUTE, [pointer, slots @ ..]) => {
+				// the pointer is 1-based from the start of the option and points at the first free slot
+				let filled = (*pointer as usize).saturating_sub(4).min(slots.len());
+				IPV4Option::RecordRoute(slots[..filled].chunks_exact(4).map(word).collect())
+			}
+			(OPTION_TIMESTAMP, [pointer, flags, slots @ ..]) => {
+				let filled = (*pointer as usize).saturating_sub(5).min(slots.len());
+				let entries = match flags & 0x0f {
+					TIMESTAMP_ONLY => slots[..filled].chunks_exact(4).map(|c| (None, word(c))).collect(),
+					_ => slots[..filled].chunks_exact(8).map(|c| (Some(word(&c[..4])), word(&c[4..]))).collect(),
+				};
+				
+				IPV4Option::Timestamp {
+					entries,
+					overflow: flags >> 4,
+				}
+			}
+			_ => IPV4Option::Other {
+				kind,
+				data: Vec::from(data),
+			},
+		};
+	}
 }
 
-#[allow(dead_code)]
+#[derive(PartialEq, Clone, Debug)]
 pub struct IPV4Packet {
 	pub version: u8,
 	pub ihl: u8,
@@ -30,11 +192,169 @@ pub struct IPV4Packet {
 	pub from_addr: u32,
 	pub dest_addr: u32,
 	
+	pub options: Vec<IPV4Option>,
 	pub data: Vec<u8>,
 }
 
 impl IPV4Packet {
+	/// An ICMP datagram with the usual defaults. Length, header length and
+	/// checksum are filled in by `encode`.
+	pub fn new(from_addr: u32, dest_addr: u32, data: Vec<u8>) -> Self {
+		return Self {
+			version: 4,
+			ihl: 5,
+			
+			tos: 0,
+			tot_len: 0,
+			id: 0,
+			frag_off: 0,
+			ttl: 64,
+			protocol: IPV4Protocol::Icmp,
+			check: 0,
+			
+			from_addr,
+			dest_addr,
+			
+			options: Vec::new(),
+			data,
+		};
+	}
+	
+	fn encode_options(&self) -> Result<Vec<u8>, String> {
+		let mut bytes = Vec::new();
+		for option in &self.options {
+			bytes.extend(option.encode()?);
+		}
+		// pad with end of options to a multiple of 4 bytes
+		bytes.resize(bytes.len().div_ceil(4) * 4, OPTION_END);
+		
+		if bytes.len() > MAX_OPTIONS_SIZE {
+			return Err("ip options too long".to_string());
+		}
+		return Ok(bytes);
+	}
+	
+	/// Size of the encoded datagram in bytes.
+	pub fn size(&self) -> Result<usize, String> {
+		return Ok(20 + self.encode_options()?.len() + self.data.len());
+	}
+	
+	/// Writes the datagram into `buffer` and returns how many bytes were used.
+	/// `ihl`, `tot_len` and `check` are computed, the other fields are taken as they are.
+	pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, String> {
+		let options = self.encode_options()?;
+		let header_size = 20 + options.len();
+		let size = header_size + self.data.len();
+		
+		if size > u16::MAX as usize {
+			return Err("datagram too long".to_string());
+		}
+		if buffer.len() < size {
+			return Err("buffer too small".to_string());
+		}
+		
+		buffer[0] = (4 << 4) | (header_size / 4) as u8;
+		buffer[1] = self.tos;
+		BigEndian::write_u16(&mut buffer[2..4], size as u16);
+		BigEndian::write_u16(&mut buffer[4..6], self.id);
+		BigEndian::write_u16(&mut buffer[6..8], self.frag_off);
+		buffer[8] = self.ttl;
+		buffer[9] = self.protocol.encode();
+		buffer[10] = 0;
+		buffer[11] = 0;
+		BigEndian::write_u32(&mut buffer[12..16], self.from_addr);
+		BigEndian::write_u32(&mut buffer[16..20], self.dest_addr);
+		buffer[20..header_size].copy_from_slice(&options);
+		
+		let check = checksum(&buffer[..header_size]);
+		BigEndian::write_u16(&mut buffer[10..12], check);
+		
+		buffer[header_size..size].copy_from_slice(&self.data);
+		
+		trace!(header_size, size, id = self.id, frag_off = self.frag_off);
+		return Ok(size);
+	}
+	
+	/// Splits the datagram into fragments that carry at most `size` bytes of
+	/// data each. `size` is rounded down to a multiple of 8, as fragment offsets
+	/// require. Only options marked as copied go into the later fragments.
+	pub fn fragment(&self, size: usize) -> Result<Vec<Self>, String> {
+		let size = size / 8 * 8;
+		if size == 0 {
+			return Err("fragment size must be at least 8 bytes".to_string());
+		}
+		if self.frag_off & FLAG_DONT_FRAGMENT != 0 {
+			return Err("datagram has the don't fragment flag set".to_string());
+		}
+		
+		let base = (self.frag_off & FRAGMENT_OFFSET_MASK) as usize * 8;
+		let last_is_final = self.frag_off & FLAG_MORE_FRAGMENTS == 0;
+		let chunks: Vec<&[u8]> = self.data.chunks(size).collect();
+		
+		let mut fragments = Vec::with_capacity(chunks.len());
+		for (i, chunk) in chunks.iter().enumerate() {
+			let offset = base + i * size;
+			if offset / 8 > FRAGMENT_OFFSET_MASK as usize {
+				return Err("fragment offset out of range".to_string());
+			}
+			
+			let mut fragment = self.clone();
+			fragment.data = Vec::from(*chunk);
+			fragment.frag_off = (offset / 8) as u16;
+			if i + 1 < chunks.len() || !last_is_final {
+				fragment.frag_off |= FLAG_MORE_FRAGMENTS;
+			}
+			if i > 0 {
+				fragment.options.retain(|o| o.copied());
+			}
+			fragments.push(fragment);
+		}
+		
+		return Ok(fragments);
+	}
+	
+	/// Decodes into an owned packet, copying the options and payload. Use
+	/// `Ipv4View` to read a packet in place.
 	pub fn decode(buffer: &[u8]) -> Result<Self, String> {
+		let view = Ipv4View::new(buffer)?;
+		let protocol = match IPV4Protocol::decode(view.protocol()) {
+			Some(p) => p,
+			None => {
+				return Err("invalid ipv4 protocol".to_string());
+			}
+		};
+		
+		return Ok(Self {
+			version: 4,
+			ihl: (view.header_size / 4) as u8,
+			tos: view.tos(),
+			tot_len: view.tot_len(),
+			id: view.id(),
+			frag_off: view.frag_off(),
+			ttl: view.ttl(),
+			protocol,
+			check: view.check(),
+			
+			from_addr: view.from_addr(),
+			dest_addr: view.dest_addr(),
+			
+			options: view.options(),
+			data: Vec::from(view.payload()),
+		});
+	}
+}
+
+/// Borrowed view of an IPv4 datagram. Validates the header once in `new`,
+/// after that every accessor reads straight from the buffer.
+#[derive(Clone, Copy)]
+pub struct Ipv4View<'a> {
+	buffer: &'a [u8],
+	header_size: usize,
+	size: usize,
+}
+
+impl<'a> Ipv4View<'a> {
+	pub fn new(buffer: &'a [u8]) -> Result<Self, String> {
 		if buffer.len() < 20 {
 			return Err("invalid ipv4 header".to_string());
 		}
@@ -48,32 +368,221 @@ impl IPV4Packet {
 			return Err("invalid version".to_string());
 		}
 		
-		if buffer.len() < header_size {
+		if header_size < 20 || buffer.len() < header_size {
 			return Err("invalid header size".to_string());
 		}
 		
-		let protocol = match IPV4Protocol::decode(buffer[9]) {
-			Some(p) => p,
-			None => {
-				return Err("invalid ipv4 protocol".to_string());
-			}
-		};
+		// trust the total length only when it fits what was received
+		let tot_len = BigEndian::read_u16(&buffer[2..4]) as usize;
+		let size = if tot_len >= header_size && tot_len <= buffer.len() { tot_len } else { buffer.len() };
 		
 		return Ok(Self {
-			version,
-			ihl: byte0 & 0x0f,
-			tos: buffer[1],
-			tot_len: ((buffer[2] as u16) << 8) | (buffer[3] as u16),
-			id: ((buffer[4] as u16) << 8) | (buffer[5] as u16),
-			frag_off: ((buffer[6] as u16) << 8) | (buffer[7] as u16),
-			ttl: buffer[8],
-			protocol,
-			check: ((buffer[10] as u16) << 8) | (buffer[11] as u16),
+			buffer,
+			header_size,
+			size,
+		});
+	}
+	
+	pub fn header_size(&self) -> usize {
+		return self.header_size;
+	}
+	
+	pub fn tos(&self) -> u8 {
+		return self.buffer[1];
+	}
+	
+	pub fn tot_len(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[2..4]);
+	}
+	
+	pub fn id(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[4..6]);
+	}
+	
+	pub fn frag_off(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[6..8]);
+	}
+	
+	pub fn ttl(&self) -> u8 {
+		return self.buffer[8];
+	}
+	
+	pub fn protocol(&self) -> u8 {
+		return self.buffer[9];
+	}
+	
+	pub fn check(&self) -> u16 {
+		return BigEndian::read_u16(&self.buffer[10..12]);
+	}
+	
+	pub fn from_addr(&self) -> u32 {
+		return BigEndian::read_u32(&self.buffer[12..16]);
+	}
+	
+	pub fn dest_addr(&self) -> u32 {
+		return BigEndian::read_u32(&self.buffer[16..20]);
+	}
+	
+	/// Raw option bytes, empty for a plain 20 byte header.
+	pub fn option_bytes(&self) -> &'a [u8] {
+		return &self.buffer[20..self.header_size];
+	}
+	
+	/// Decoded options. Only allocates when the header carries any.
+	pub fn options(&self) -> Vec<IPV4Option> {
+		return IPV4Option::decode_all(self.option_bytes());
+	}
+	
+	pub fn payload(&self) -> &'a [u8] {
+		return &self.buffer[self.header_size..self.size];
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::{
+		collection::vec,
+		prelude::*
+	};
+	
+	/// Options that decode back to themselves: full recorded routes and
+	/// timestamps that either all carry an address or none do, and other
+	/// kinds the decoder does not parse.
+	fn option() -> impl Strategy<Value = IPV4Option> {
+		return prop_oneof![
+			vec(any::<u32>(), 0..=9).prop_map(IPV4Option::RecordRoute),
+			(vec(any::<u32>(), 0..=9), 0..=0x0fu8)
+				.prop_map(|(times, overflow)| IPV4Option::Timestamp { entries: times.into_iter().map(|t| (None, t)).collect(), overflow }),
+			(vec((any::<u32>(), any::<u32>()), 1..=4), 0..=0x0fu8)
+				.prop_map(|(entries, overflow)| IPV4Option::Timestamp { entries: entries.into_iter().map(|(a, t)| (Some(a), t)).collect(), overflow }),
+			(any::<u8>().prop_filter("parsed kind", |k| ![OPTION_END, OPTION_NOP, OPTION_RECORD_ROUTE, OPTION_TIMESTAMP].contains(k)), vec(any::<u8>(), 0..=38))
+				.prop_map(|(kind, data)| IPV4Option::Other { kind, data }),
+		];
+	}
+	
+	fn packet() -> impl Strategy<Value = IPV4Packet> {
+		let header = (any::<u8>(), any::<u16>(), any::<u16>(), any::<u8>(), any::<u32>(), any::<u32>());
+		return (header, vec(option(), 0..=3), vec(any::<u8>(), 0..128))
+			.prop_map(|((tos, id, frag_off, ttl, from_addr, dest_addr), options, data)| IPV4Packet {
+				tos,
+				id,
+				frag_off,
+				ttl,
+				options,
+				..IPV4Packet::new(from_addr, dest_addr, data)
+			})
+			.prop_filter("options fit in the header", |p| p.size().is_ok());
+	}
+	
+	fn datagram(size: usize) -> IPV4Packet {
+		return IPV4Packet::new(0x0a000001, 0x0a000002, (0..size).map(|i| i as u8).collect());
+	}
+	
+	/// Puts the fragments' data back together by offset, checking that they leave no gaps.
+	fn reassemble(fragments: &[IPV4Packet]) -> Vec<u8> {
+		let mut data = Vec::new();
+		for fragment in fragments {
+			assert_eq!((fragment.frag_off & FRAGMENT_OFFSET_MASK) as usize * 8, data.len());
+			data.extend_from_slice(&fragment.data);
+		}
+		return data;
+	}
+	
+	#[test]
+	fn fragment_offsets_and_flags() {
+		let packet = datagram(100);
+		// rounded down to 24 bytes per fragment
+		let fragments = packet.fragment(30).unwrap();
+		
+		let offsets: Vec<u16> = fragments.iter().map(|f| f.frag_off & FRAGMENT_OFFSET_MASK).collect();
+		assert_eq!(offsets, vec![0, 3, 6, 9, 12]);
+		let sizes: Vec<usize> = fragments.iter().map(|f| f.data.len()).collect();
+		assert_eq!(sizes, vec![24, 24, 24, 24, 4]);
+		
+		let (last, rest) = fragments.split_last().unwrap();
+		assert!(rest.iter().all(|f| f.frag_off & FLAG_MORE_FRAGMENTS != 0));
+		assert_eq!(last.frag_off & FLAG_MORE_FRAGMENTS, 0);
+		assert_eq!(reassemble(&fragments), packet.data);
+	}
+	
+	#[test]
+	fn fragmenting_a_fragment() {
+		// a middle fragment keeps its offset and more fragments flag on its last piece
+		let packet = IPV4Packet { frag_off: FLAG_MORE_FRAGMENTS | 4, ..datagram(16) };
+		let fragments = packet.fragment(8).unwrap();
+		
+		let frag_offs: Vec<u16> = fragments.iter().map(|f| f.frag_off).collect();
+		assert_eq!(frag_offs, vec![FLAG_MORE_FRAGMENTS | 4, FLAG_MORE_FRAGMENTS | 5]);
+	}
+	
+	#[test]
+	fn only_copied_options_repeat() {
+		let loose_source_route = IPV4Option::Other { kind: 0x83, data: vec![4, 0, 0, 0, 0, 0] };
+		let packet = IPV4Packet {
+			options: vec![IPV4Option::RecordRoute(vec![0x0a000003]), loose_source_route.clone()],
+			..datagram(40)
+		};
+		let fragments = packet.fragment(16).unwrap();
+		
+		assert_eq!(fragments[0].options, packet.options);
+		for fragment in &fragments[1..] {
+			assert_eq!(fragment.options, vec![loose_source_route.clone()]);
+		}
+	}
+	
+	#[test]
+	fn fragments_survive_encoding() {
+		let packet = IPV4Packet { id: 0x1234, ..datagram(100) };
+		
+		let mut fragments = Vec::new();
+		for fragment in packet.fragment(32).unwrap() {
+			let mut buffer = vec![0; fragment.size().unwrap()];
+			fragment.encode(&mut buffer).unwrap();
+			fragments.push(IPV4Packet::decode(&buffer).unwrap());
+		}
+		
+		assert!(fragments.iter().all(|f| f.id == 0x1234));
+		assert_eq!(reassemble(&fragments), packet.data);
+	}
+	
+	#[test]
+	fn fragment_errors() {
+		assert!(datagram(100).fragment(7).is_err());
+		
+		let dont_fragment = IPV4Packet { frag_off: FLAG_DONT_FRAGMENT, ..datagram(100) };
+		assert_eq!(dont_fragment.fragment(32).err(), Some("datagram has the don't fragment flag set".to_string()));
+	}
+	
+	proptest! {
+		#[test]
+		fn option_round_trip(option in option()) {
+			let bytes = option.encode().unwrap();
+			prop_assert_eq!(bytes[1] as usize, bytes.len());
+			prop_assert_eq!(IPV4Option::decode_all(&bytes), vec![option]);
+		}
+		
+		#[test]
+		fn packet_round_trip(packet in packet()) {
+			let mut buffer = vec![0; packet.size().unwrap()];
+			let size = packet.encode(&mut buffer).unwrap();
+			prop_assert_eq!(size, buffer.len());
 			
-			from_addr: ((buffer[12] as u32) << 24) | ((buffer[13] as u32) << 16) | ((buffer[14] as u32) << 8) | (buffer[15] as u32),
-			dest_addr: ((buffer[16] as u32) << 24) | ((buffer[17] as u32) << 16) | ((buffer[18] as u32) << 8) | (buffer[19] as u32),
+			let decoded = IPV4Packet::decode(&buffer).unwrap();
+			let header_size = size - packet.data.len();
+			prop_assert_eq!(checksum(&buffer[..header_size]), 0);
 			
-			data: Vec::from(&buffer[header_size..])
-		});
+			// only the fields encode computes differ from what went in
+			prop_assert_eq!(&decoded, &IPV4Packet {
+				ihl: (header_size / 4) as u8,
+				tot_len: size as u16,
+				check: BigEndian::read_u16(&buffer[10..12]),
+				..packet.clone()
+			});
+			
+			let view = Ipv4View::new(&buffer).unwrap();
+			prop_assert_eq!(view.options(), packet.options);
+			prop_assert_eq!(view.payload(), &packet.data[..]);
+		}
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,238 @@
+use logger::prelude::*;
+
+use std::{
+	fs::File,
+	io::{
+		BufReader,
+		BufWriter,
+		ErrorKind,
+		Read,
+		Write
+	},
+	path::Path,
+	time::{
+		Duration,
+		SystemTime,
+		UNIX_EPOCH
+	}
+};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+/// Magic of a pcap file with microsecond timestamps.
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+
+/// Magic of a pcap file with nanosecond timestamps.
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Packets start directly with the IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Linux cooked capture, what `tcpdump -i any` writes.
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const LINKTYPE_IPV4: u32 = 228;
+
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const SNAPLEN: u32 = 65535;
+
+/// Writes datagrams to a classic pcap file, readable by tcpdump and wireshark.
+pub struct PcapWriter<W: Write> {
+	writer: W,
+}
+
+impl PcapWriter<BufWriter<File>> {
+	/// Creates or truncates the file at `path`.
+	pub fn create(path: &Path) -> Result<Self, String> {
+		let file = match File::create(path) {
+			Ok(f) => f,
+			Err(e) => {
+				error!(desc = e.to_string(), path = path.display().to_string(), "could not create pcap file");
+				return Err("could not create pcap file".to_string());
+			}
+		};
+
+		return Self::new(BufWriter::new(file));
+	}
+}
+
+impl<W: Write> PcapWriter<W> {
+	/// Writes the file header to `writer`.
+	pub fn new(mut writer: W) -> Result<Self, String> {
+		let mut header = [0; 24];
+		LittleEndian::write_u32(&mut header[0..4], MAGIC_NANOS);
+		LittleEndian::write_u16(&mut header[4..6], 2);
+		LittleEndian::write_u16(&mut header[6..8], 4);
+		// timezone offset and timestamp accuracy stay zero
+		LittleEndian::write_u32(&mut header[16..20], SNAPLEN);
+		LittleEndian::write_u32(&mut header[20..24], LINKTYPE_RAW);
+
+		if let Err(e) = writer.write_all(&header) {
+			error!(desc = e.to_string(), "could not write pcap header");
+			return Err("could not write pcap header".to_string());
+		}
+
+		return Ok(Self { writer });
+	}
+
+	/// Appends one IP datagram seen at `time`.
+	pub fn write(&mut self, time: SystemTime, datagram: &[u8]) -> Result<(), String> {
+		let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+		let captured = datagram.len().min(SNAPLEN as usize);
+
+		let mut header = [0; 16];
+		LittleEndian::write_u32(&mut header[0..4], since_epoch.as_secs() as u32);
+		LittleEndian::write_u32(&mut header[4..8], since_epoch.subsec_nanos());
+		LittleEndian::write_u32(&mut header[8..12], captured as u32);
+		LittleEndian::write_u32(&mut header[12..16], datagram.len() as u32);
+
+		if let Err(e) = self.writer.write_all(&header).and_then(|_| self.writer.write_all(&datagram[..captured])) {
+			error!(desc = e.to_string(), "could not write pcap record");
+			return Err("could not write pcap record".to_string());
+		}
+
+		return Ok(());
+	}
+
+	pub fn flush(&mut self) -> Result<(), String> {
+		if let Err(e) = self.writer.flush() {
+			error!(desc = e.to_string(), "could not flush pcap file");
+			return Err("could not flush pcap file".to_string());
+		}
+		return Ok(());
+	}
+}
+
+/// Reads the IPv4 datagrams out of a classic pcap file, as written by
+/// `PcapWriter`, tcpdump or wireshark. Raw IP, ethernet and linux cooked
+/// captures are understood.
+pub struct PcapReader<R: Read> {
+	reader: R,
+	big_endian: bool,
+	nanos: bool,
+	linktype: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+	pub fn open(path: &Path) -> Result<Self, String> {
+		let file = match File::open(path) {
+			Ok(f) => f,
+			Err(e) => {
+				error!(desc = e.to_string(), path = path.display().to_string(), "could not open pcap file");
+				return Err("could not open pcap file".to_string());
+			}
+		};
+
+		return Self::new(BufReader::new(file));
+	}
+}
+
+impl<R: Read> PcapReader<R> {
+	/// Reads and checks the file header.
+	pub fn new(mut reader: R) -> Result<Self, String> {
+		let mut header = [0; 24];
+		if let Err(e) = reader.read_exact(&mut header) {
+			error!(desc = e.to_string(), "could not read pcap header");
+			return Err("could not read pcap header".to_string());
+		}
+
+		let (big_endian, nanos) = match (LittleEndian::read_u32(&header[0..4]), BigEndian::read_u32(&header[0..4])) {
+			(MAGIC_MICROS, _) => (false, false),
+			(MAGIC_NANOS, _) => (false, true),
+			(_, MAGIC_MICROS) => (true, false),
+			(_, MAGIC_NANOS) => (true, true),
+			_ => return Err("not a pcap file (pcapng is not supported)".to_string()),
+		};
+
+		let mut pcap = Self {
+			reader,
+			big_endian,
+			nanos,
+			linktype: 0,
+		};
+		pcap.linktype = pcap.read_u32(&header[20..24]) & 0x0fff_ffff;
+
+		if ![LINKTYPE_ETHERNET, LINKTYPE_RAW, LINKTYPE_LINUX_SLL, LINKTYPE_IPV4, LINKTYPE_LINUX_SLL2].contains(&pcap.linktype) {
+			error!(linktype = pcap.linktype, "unsupported pcap link type");
+			return Err("unsupported pcap link type".to_string());
+		}
+
+		return Ok(pcap);
+	}
+
+	fn read_u32(&self, bytes: &[u8]) -> u32 {
+		return if self.big_endian { BigEndian::read_u32(bytes) } else { LittleEndian::read_u32(bytes) };
+	}
+
+	/// The next IPv4 datagram and when it was captured, skipping everything
+	/// else. `None` at the end of the file, a record cut short by a killed
+	/// capture counts as the end too.
+	pub fn next_datagram(&mut self) -> Result<Option<(SystemTime, Vec<u8>)>, String> {
+		loop {
+			let mut header = [0; 16];
+			match self.reader.read_exact(&mut header) {
+				Ok(_) => {}
+				Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+				Err(e) => {
+					error!(desc = e.to_string(), "could not read pcap record");
+					return Err("could not read pcap record".to_string());
+				}
+			}
+
+			let seconds = self.read_u32(&header[0..4]) as u64;
+			let fraction = self.read_u32(&header[4..8]);
+			let captured = self.read_u32(&header[8..12]) as usize;
+			if captured > SNAPLEN as usize * 4 {
+				return Err("corrupt pcap record length".to_string());
+			}
+
+			let mut data = vec![0; captured];
+			match self.reader.read_exact(&mut data) {
+				Ok(_) => {}
+				Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+					warn!("pcap file ends in the middle of a record");
+					return Ok(None);
+				}
+				Err(e) => {
+					error!(desc = e.to_string(), "could not read pcap record");
+					return Err("could not read pcap record".to_string());
+				}
+			}
+
+			let fraction = if self.nanos { Duration::from_nanos(fraction as u64) } else { Duration::from_micros(fraction as u64) };
+			let time = UNIX_EPOCH + Duration::from_secs(seconds) + fraction;
+
+			if let Some(start) = self.ipv4_start(&data) {
+				data.drain(..start);
+				return Ok(Some((time, data)));
+			}
+		}
+	}
+
+	/// Where the IPv4 header starts in a record, if it holds one.
+	fn ipv4_start(&self, data: &[u8]) -> Option<usize> {
+		let (ethertype, start) = match self.linktype {
+			LINKTYPE_RAW | LINKTYPE_IPV4 => return data.first().is_some_and(|b| b >> 4 == 4).then_some(0),
+			LINKTYPE_ETHERNET => {
+				let mut start = 12;
+				// skip any number of vlan tags
+				while data.len() >= start + 2 && BigEndian::read_u16(&data[start..start + 2]) == ETHERTYPE_VLAN {
+					start += 4;
+				}
+				(data.get(start..start + 2).map(BigEndian::read_u16)?, start + 2)
+			}
+			LINKTYPE_LINUX_SLL => (data.get(14..16).map(BigEndian::read_u16)?, 16),
+			LINKTYPE_LINUX_SLL2 => (data.get(0..2).map(BigEndian::read_u16)?, 20),
+			_ => return None,
+		};
+
+		return (ethertype == ETHERTYPE_IPV4 && start < data.len()).then_some(start);
+	}
+}
@@ -1 +1,18 @@
-pub use crate::Pinger;
\ No newline at end of file
+pub use crate::{
+	Pinger,
+	PingerBuilder,
+	IpOption,
+	RecordOption,
+	RawHeader,
+	SocketOptions,
+	engine::{
+		ProbeEvent,
+		ProbeKind,
+	},
+	schedule::Mode,
+	session::{
+		PingSession,
+		ProbeOutcome,
+	},
+	stats::Statistics,
+};
\ No newline at end of file
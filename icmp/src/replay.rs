@@ -0,0 +1,249 @@
+use crate::{
+	engine::make_reply,
+	packet::{
+		icmp::{self, IcmpView},
+		ipv4::{self, Ipv4View}
+	},
+	pcap::PcapReader,
+	session::ProbeOutcome,
+	stats::Statistics,
+};
+use logger::prelude::*;
+
+use std::{
+	collections::{
+		HashMap,
+		VecDeque
+	},
+	io::Read,
+	net::{
+		IpAddr,
+		Ipv4Addr
+	},
+	time::{
+		Duration,
+		SystemTime
+	}
+};
+
+struct Probe {
+	sent: SystemTime,
+	tos: u8,
+	timed_out: bool,
+}
+
+/// Requests seen so far, by target, ident and sequence.
+type Table = HashMap<(Ipv4Addr, u16, u16), Probe>;
+
+/// Runs a capture through the same decoders a live run uses, pairing echo and
+/// timestamp requests with their replies, and yields the outcomes a
+/// `PingSession` would have produced. Time is the capture's clock, so a
+/// replay always gives the same result.
+pub struct Replay<R: Read> {
+	reader: PcapReader<R>,
+	timeout: Duration,
+
+	table: Table,
+	stats: Vec<(IpAddr, Statistics)>,
+	pending: VecDeque<ProbeOutcome>,
+	finished: bool,
+}
+
+impl<R: Read> Replay<R> {
+	pub fn new(reader: PcapReader<R>) -> Self {
+		return Self {
+			reader,
+			timeout: Duration::from_secs(2),
+
+			table: HashMap::new(),
+			stats: Vec::new(),
+			pending: VecDeque::new(),
+			finished: false,
+		};
+	}
+
+	/// How long after its request a reply still counts, later ones are reported as late.
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		return self;
+	}
+
+	/// Statistics so far, one entry per target in the order they first appear.
+	pub fn statistics(&self) -> &[(IpAddr, Statistics)] {
+		return &self.stats;
+	}
+
+	fn stats_for(&mut self, addr: IpAddr) -> &mut Statistics {
+		let i = match self.stats.iter().position(|(a, _)| *a == addr) {
+			Some(i) => i,
+			None => {
+				self.stats.push((addr, Statistics::new()));
+				self.stats.len() - 1
+			}
+		};
+
+		return &mut self.stats[i].1;
+	}
+
+	/// Reports every probe still waiting at `now` whose timeout has passed, in the order they were sent.
+	fn expire(&mut self, now: Option<SystemTime>) {
+		let mut expired = Vec::new();
+		for ((dest, _, sequence), probe) in self.table.iter_mut() {
+			let due = now.is_none_or(|now| probe.sent.checked_add(self.timeout).is_some_and(|d| now >= d));
+			if !probe.timed_out && due {
+				probe.timed_out = true;
+				expired.push((probe.sent, *dest, *sequence));
+			}
+		}
+
+		expired.sort_by_key(|(sent, _, _)| *sent);
+		for (_, dest, sequence) in expired {
+			debug!(sequence, dest = dest.to_string(), "probe timed out");
+			self.pending.push_back(ProbeOutcome::Timeout { dest: dest.into(), sequence });
+		}
+	}
+
+	fn process(&mut self, time: SystemTime, datagram: &[u8]) {
+		let ipv4_packet = match Ipv4View::new(datagram) {
+			Ok(p) => p,
+			Err(e) => {
+				debug!(desc = e.to_string(), "could not decode packet");
+				return;
+			}
+		};
+
+		// only whole datagrams carry a complete icmp message
+		if ipv4::IPV4Protocol::decode(ipv4_packet.protocol()).is_none() || ipv4_packet.frag_off() & (ipv4::FLAG_MORE_FRAGMENTS | ipv4::FRAGMENT_OFFSET_MASK) != 0 {
+			return;
+		}
+
+		let message = match IcmpView::new(ipv4_packet.payload()) {
+			Ok(m) => m,
+			Err(e) => {
+				debug!(desc = e.to_string(), "could not decode icmp message");
+				return;
+			}
+		};
+
+		let from_addr = Ipv4Addr::from_bits(ipv4_packet.from_addr());
+		let dest_addr = Ipv4Addr::from_bits(ipv4_packet.dest_addr());
+
+		match message.typ() {
+			icmp::ECHO_REQUEST | icmp::TIMESTAMP_REQUEST => {
+				let key = (dest_addr, message.ident(), message.sequence());
+				// a capture on loopback sees every request twice
+				if self.table.get(&key).is_some_and(|p| !p.timed_out) {
+					return;
+				}
+
+				self.table.insert(key, Probe { sent: time, tos: ipv4_packet.tos(), timed_out: false });
+				self.stats_for(dest_addr.into()).add_sent();
+				self.pending.push_back(ProbeOutcome::Sent { dest: dest_addr.into(), sequence: message.sequence(), tos: ipv4_packet.tos() });
+			}
+			_ if message.is_echo_reply(icmp::Family::V4) || message.is_timestamp_reply(icmp::Family::V4) => {
+				let Some(probe) = self.table.remove(&(from_addr, message.ident(), message.sequence())) else {
+					debug!(sequence = message.sequence(), from = from_addr.to_string(), "reply without a request");
+					return;
+				};
+
+				let elapsed = time.duration_since(probe.sent).unwrap_or_default();
+				let reply = make_reply(&ipv4_packet, &message, elapsed, time, probe.tos, None);
+
+				if probe.timed_out {
+					self.pending.push_back(ProbeOutcome::Late(reply));
+				} else {
+					self.stats_for(from_addr.into()).add_reply(elapsed);
+					self.pending.push_back(ProbeOutcome::Reply(reply));
+				}
+			}
+			_ => {}
+		}
+	}
+}
+
+impl<R: Read> Iterator for Replay<R> {
+	type Item = ProbeOutcome;
+
+	fn next(&mut self) -> Option<ProbeOutcome> {
+		while self.pending.is_empty() && !self.finished {
+			match self.reader.next_datagram() {
+				Ok(Some((time, datagram))) => {
+					self.expire(Some(time));
+					self.process(time, &datagram);
+				}
+				Ok(None) => {
+					// whatever is still waiting was never answered in the capture
+					self.expire(None);
+					self.finished = true;
+				}
+				Err(e) => {
+					error!(desc = e, "stopping replay");
+					self.expire(None);
+					self.finished = true;
+				}
+			}
+		}
+
+		return self.pending.pop_front();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::session::tests::Event;
+
+	/// A loopback capture: a reply, a probe that was never answered, a request
+	/// captured twice with its reply, and a reply that came in after the timeout.
+	const CAPTURE: &[u8] = include_bytes!("../tests/data/replay.pcap");
+
+	#[test]
+	fn outcomes_and_statistics() {
+		let mut replay = Replay::new(PcapReader::new(CAPTURE).unwrap());
+		let events: Vec<Event> = replay.by_ref().map(|o| Event::from(&o)).collect();
+
+		let lo = IpAddr::V4(Ipv4Addr::LOCALHOST);
+		assert_eq!(events, vec![
+			Event::Sent(lo, 1),
+			Event::Reply(lo, 1),
+			Event::Sent(lo, 2),
+			Event::Sent(lo, 3),
+			Event::Reply(lo, 3),
+			Event::Timeout(lo, 2),
+			Event::Sent(lo, 4),
+			Event::Timeout(lo, 4),
+			Event::Late(lo, 4),
+		]);
+
+		let [(addr, stats)] = replay.statistics() else {
+			panic!("expected a single target");
+		};
+		assert_eq!(*addr, lo);
+		assert_eq!((stats.transmitted, stats.received), (4, 2));
+		assert_eq!(stats.loss_percent(), 50.0);
+		assert_eq!(stats.min(), Some(Duration::from_millis(12)));
+		assert_eq!(stats.max(), Some(Duration::from_millis(30)));
+		assert_eq!(stats.avg(), Some(Duration::from_millis(21)));
+	}
+
+	#[test]
+	fn late_reply_is_timed_against_its_request() {
+		let replay = Replay::new(PcapReader::new(CAPTURE).unwrap());
+		let late: Vec<Duration> = replay.filter_map(|o| match o {
+			ProbeOutcome::Late(reply) => Some(reply.elapsed),
+			_ => None,
+		}).collect();
+
+		assert_eq!(late, vec![Duration::from_millis(2500)]);
+	}
+
+	#[test]
+	fn longer_timeout_turns_the_late_reply_into_a_reply() {
+		let mut replay = Replay::new(PcapReader::new(CAPTURE).unwrap()).timeout(Duration::from_secs(3));
+		let events: Vec<Event> = replay.by_ref().map(|o| Event::from(&o)).collect();
+
+		let lo = IpAddr::V4(Ipv4Addr::LOCALHOST);
+		assert_eq!(events.last(), Some(&Event::Reply(lo, 4)));
+		assert_eq!(replay.statistics()[0].1.received, 3);
+	}
+}
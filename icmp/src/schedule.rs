@@ -0,0 +1,66 @@
+use std::time::{
+	Duration,
+	Instant
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+	/// Send on a fixed cadence, independent of how long replies take.
+	Fixed,
+	/// Send the next probe as soon as the previous one is answered.
+	Adaptive,
+	/// Like adaptive, but sends at least every interval (10 ms by default) even without replies.
+	Flood,
+}
+
+pub struct Scheduler {
+	mode: Mode,
+	interval: Duration,
+	next: Instant,
+}
+
+impl Scheduler {
+	pub fn new(mode: Mode, interval: Duration) -> Self {
+		return Self {
+			mode,
+			interval,
+			next: Instant::now(),
+		};
+	}
+
+	/// Changes the interval, bringing the next probe forward if it would now be too far out.
+	pub fn set_interval(&mut self, interval: Duration) {
+		self.interval = interval;
+		self.next = self.next.min(Instant::now() + interval);
+	}
+
+	/// Time left until the next probe is due, zero if it is due now.
+	pub fn until_due(&self) -> Duration {
+		return self.next.saturating_duration_since(Instant::now());
+	}
+
+	/// Records that a probe was just sent and schedules the next one.
+	pub fn sent(&mut self) {
+		let now = Instant::now();
+
+		// keep the cadence when slightly late, but never try to catch up on missed probes
+		self.next = if self.mode == Mode::Fixed && now.saturating_duration_since(self.next) < self.interval {
+			self.next + self.interval
+		} else {
+			now + self.interval
+		};
+	}
+
+	/// Lets adaptive modes send again right after a probe finished.
+	pub fn probe_done(&mut self, replied: bool) {
+		if self.mode == Mode::Fixed || !replied {
+			return;
+		}
+
+		let min = match self.mode {
+			Mode::Adaptive => self.interval.min(Duration::from_millis(200)),
+			_ => Duration::ZERO,
+		};
+		self.next = self.next.min(Instant::now() + min);
+	}
+}
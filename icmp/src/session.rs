@@ -0,0 +1,565 @@
+use crate::{
+	engine::ProbeEvent,
+	schedule::{
+		Mode,
+		Scheduler
+	},
+	stats::Statistics,
+	Pinger,
+	PingReply,
+};
+use logger::prelude::*;
+use util::cancel::CancelToken;
+
+use std::{
+	collections::VecDeque,
+	net::IpAddr,
+	time::{
+		Duration,
+		Instant
+	}
+};
+
+/// Upper bound on how long `next` blocks before re-checking the cancel token and the schedule.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub enum ProbeOutcome {
+	Sent {
+		dest: IpAddr,
+		sequence: u16,
+		tos: u8,
+	},
+	SendFailed {
+		dest: IpAddr,
+		error: String,
+	},
+	Reply(PingReply),
+	/// A reply for a probe that was already reported as timed out.
+	Late(PingReply),
+	Timeout {
+		dest: IpAddr,
+		sequence: u16,
+	},
+	AddressChanged {
+		old: IpAddr,
+		new: IpAddr,
+	},
+}
+
+/// Runs a series of probes with a `Pinger`, yielding every send, reply and
+/// timeout as it happens and keeping per-address statistics along the way.
+pub struct PingSession {
+	pinger: Pinger,
+	mode: Mode,
+	interval: Duration,
+	scheduler: Scheduler,
+
+	count: Option<u64>,
+	deadline: Option<Duration>,
+	reresolve: Option<Duration>,
+	tos_classes: Vec<u8>,
+	preload: u64,
+
+	started: Option<Instant>,
+	last_resolve: Instant,
+	sent: u64,
+	stats: Vec<(IpAddr, Statistics)>,
+	pending: VecDeque<ProbeOutcome>,
+
+	cancel: Option<CancelToken>,
+	paused: bool,
+	finished: bool,
+}
+
+impl PingSession {
+	/// The pinger must already have its socket initialized.
+	pub fn new(pinger: Pinger) -> Self {
+		let mode = Mode::Fixed;
+		let interval = Duration::from_secs(1);
+		let stats = vec![(pinger.get_addr(), Statistics::new())];
+
+		return Self {
+			pinger,
+			mode,
+			interval,
+			scheduler: Scheduler::new(mode, interval),
+
+			count: None,
+			deadline: None,
+			reresolve: None,
+			tos_classes: Vec::new(),
+			preload: 0,
+
+			started: None,
+			last_resolve: Instant::now(),
+			sent: 0,
+			stats,
+			pending: VecDeque::new(),
+
+			cancel: None,
+			paused: false,
+			finished: false,
+		};
+	}
+
+	pub fn count(mut self, count: Option<u64>) -> Self {
+		self.count = count;
+		return self;
+	}
+
+	pub fn interval(mut self, interval: Duration) -> Self {
+		self.interval = interval;
+		self.scheduler = Scheduler::new(self.mode, interval);
+		return self;
+	}
+
+	pub fn mode(mut self, mode: Mode) -> Self {
+		self.mode = mode;
+		self.scheduler = Scheduler::new(mode, self.interval);
+		return self;
+	}
+
+	/// Stops the session once this much time has passed since the first probe.
+	pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+		self.deadline = deadline;
+		return self;
+	}
+
+	/// Resolves the hostname again on this schedule and follows address changes.
+	pub fn reresolve(mut self, every: Option<Duration>) -> Self {
+		self.reresolve = every;
+		return self;
+	}
+
+	/// Cycles through these TOS bytes, one per probe, so several QoS classes
+	/// can be compared in one run.
+	pub fn tos_classes(mut self, classes: Vec<u8>) -> Self {
+		self.tos_classes = classes;
+		return self;
+	}
+
+	/// Sends this many probes at once before settling into the interval,
+	/// in a single batch unless TOS classes are cycled.
+	pub fn preload(mut self, count: u64) -> Self {
+		self.preload = count;
+		return self;
+	}
+
+	/// Ends the session as soon as the token is cancelled.
+	pub fn cancel_token(mut self, cancel: CancelToken) -> Self {
+		self.cancel = Some(cancel);
+		return self;
+	}
+
+	pub fn get_mode(&self) -> Mode {
+		return self.mode;
+	}
+
+	pub fn get_interval(&self) -> Duration {
+		return self.interval;
+	}
+
+	/// Changes the interval of a running session, a shorter one applies right away.
+	pub fn set_interval(&mut self, interval: Duration) {
+		self.interval = interval;
+		self.scheduler.set_interval(interval);
+	}
+
+	/// Stops sending while paused, replies to probes already out still come in.
+	/// The deadline keeps running.
+	pub fn set_paused(&mut self, paused: bool) {
+		self.paused = paused;
+	}
+
+	pub fn is_paused(&self) -> bool {
+		return self.paused;
+	}
+
+	/// Starts the statistics over, probes still in flight are counted as received if they come back.
+	pub fn reset_statistics(&mut self) {
+		for (_, s) in self.stats.iter_mut() {
+			*s = Statistics::new();
+		}
+	}
+
+	/// Whether the session is over, after which `next` and `poll` only return what was still queued.
+	pub fn is_finished(&self) -> bool {
+		return self.finished && self.pending.is_empty();
+	}
+
+	pub fn pinger(&self) -> &Pinger {
+		return &self.pinger;
+	}
+
+	/// Statistics so far, one entry per address the target resolved to.
+	pub fn statistics(&self) -> &[(IpAddr, Statistics)] {
+		return &self.stats;
+	}
+
+	/// Like `next`, but gives up after `wait` so the caller can do other work
+	/// in between. `None` when nothing happened in time, see `is_finished`.
+	pub fn poll(&mut self, wait: Duration) -> Option<ProbeOutcome> {
+		if let Some(outcome) = self.pending.pop_front() {
+			return Some(outcome);
+		}
+		if self.finished {
+			return None;
+		}
+
+		let started = *self.started.get_or_insert_with(Instant::now);
+		if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+			self.finished = true;
+			return None;
+		}
+
+		let remaining = match self.deadline.map(|d| d.checked_sub(started.elapsed())) {
+			Some(Some(r)) if !r.is_zero() => r,
+			Some(_) => {
+				self.finished = true;
+				return None;
+			}
+			None => wait,
+		};
+
+		let done_sending = self.count.is_some_and(|c| self.sent >= c);
+		let sending = !done_sending && !self.paused;
+		if sending && self.scheduler.until_due().is_zero() {
+			let left = self.count.map_or(u64::MAX, |c| c - self.sent);
+			let burst = self.preload.saturating_sub(self.sent).min(left);
+			if burst > 1 && self.tos_classes.is_empty() {
+				self.send_burst(burst as usize);
+			} else {
+				for _ in 0..burst.max(1) {
+					self.send_probe();
+				}
+			}
+			return self.pending.pop_front();
+		}
+
+		let mut wait = wait.min(remaining);
+		if sending {
+			wait = wait.min(self.scheduler.until_due());
+		}
+
+		match self.pinger.recv_event(wait) {
+			Some(ProbeEvent::Reply(reply)) => {
+				self.stats_for(reply.from_addr.into()).add_reply(reply.elapsed);
+				self.scheduler.probe_done(true);
+				return Some(ProbeOutcome::Reply(reply));
+			}
+			Some(ProbeEvent::Late(reply)) => {
+				return Some(ProbeOutcome::Late(reply));
+			}
+			Some(ProbeEvent::Timeout { dest, sequence }) => {
+				debug!(sequence, dest = dest.to_string(), "probe timed out");
+				self.scheduler.probe_done(false);
+				return Some(ProbeOutcome::Timeout { dest, sequence });
+			}
+			None => {
+				if done_sending && self.pinger.in_flight() == 0 {
+					self.finished = true;
+				}
+				return None;
+			}
+		}
+	}
+
+	fn stats_for(&mut self, addr: IpAddr) -> &mut Statistics {
+		let i = match self.stats.iter().position(|(a, _)| *a == addr) {
+			Some(i) => i,
+			None => {
+				self.stats.push((addr, Statistics::new()));
+				self.stats.len() - 1
+			}
+		};
+
+		return &mut self.stats[i].1;
+	}
+
+	fn send_probe(&mut self) {
+		if let Some(every) = self.reresolve && self.last_resolve.elapsed() >= every {
+			self.last_resolve = Instant::now();
+
+			trace!("Pinger::reresolve");
+			match self.pinger.reresolve() {
+				Ok(Some(old)) => {
+					let new = self.pinger.get_addr();
+					self.stats_for(new);
+					self.pending.push_back(ProbeOutcome::AddressChanged { old, new });
+				}
+				Ok(None) => {}
+				Err(e) => warn!(desc = e, "re-resolving failed, keeping old address"),
+			}
+		}
+
+		let dest = self.pinger.get_addr();
+		let class = match self.tos_classes.len() {
+			0 => None,
+			n => Some(self.tos_classes[(self.sent % n as u64) as usize]),
+		};
+		self.sent += 1;
+		self.stats_for(dest).add_sent();
+		self.scheduler.sent();
+
+		if let Some(tos) = class && let Err(error) = self.pinger.set_tos(tos) {
+			self.pending.push_back(ProbeOutcome::SendFailed { dest, error });
+			return;
+		}
+
+		trace!("Pinger::send");
+		let outcome = match self.pinger.send() {
+			Ok(sequence) => ProbeOutcome::Sent { dest, sequence, tos: self.pinger.get_options().tos.unwrap_or(0) },
+			Err(error) => ProbeOutcome::SendFailed { dest, error },
+		};
+		self.pending.push_back(outcome);
+	}
+
+	/// Sends `size` probes in one batch. Probes the kernel did not take are
+	/// not counted, they go out later on the normal schedule.
+	fn send_burst(&mut self, size: usize) {
+		let dest = self.pinger.get_addr();
+		let tos = self.pinger.get_options().tos.unwrap_or(0);
+		self.scheduler.sent();
+
+		trace!("Pinger::send_many");
+		match self.pinger.send_many(size) {
+			Ok(sequences) => {
+				for sequence in sequences {
+					self.sent += 1;
+					self.stats_for(dest).add_sent();
+					self.pending.push_back(ProbeOutcome::Sent { dest, sequence, tos });
+				}
+			}
+			Err(error) => {
+				self.sent += 1;
+				self.stats_for(dest).add_sent();
+				self.pending.push_back(ProbeOutcome::SendFailed { dest, error });
+			}
+		}
+	}
+}
+
+impl Iterator for PingSession {
+	type Item = ProbeOutcome;
+
+	fn next(&mut self) -> Option<ProbeOutcome> {
+		loop {
+			if let Some(outcome) = self.poll(POLL_INTERVAL) {
+				return Some(outcome);
+			}
+			if self.finished {
+				return None;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::{
+		transport::{
+			FakeResponse,
+			FakeTransport
+		},
+		PingReply
+	};
+
+	use std::{
+		net::Ipv4Addr,
+		sync::Arc
+	};
+
+	/// What happened to which probe, without the timing details that differ between runs.
+	#[derive(PartialEq, Debug)]
+	pub(crate) enum Event {
+		Sent(IpAddr, u16),
+		SendFailed(IpAddr),
+		Reply(IpAddr, u16),
+		Late(IpAddr, u16),
+		Timeout(IpAddr, u16),
+		AddressChanged(IpAddr, IpAddr),
+	}
+
+	impl From<&ProbeOutcome> for Event {
+		fn from(outcome: &ProbeOutcome) -> Self {
+			return match outcome {
+				ProbeOutcome::Sent { dest, sequence, .. } => Event::Sent(*dest, *sequence),
+				ProbeOutcome::SendFailed { dest, .. } => Event::SendFailed(*dest),
+				ProbeOutcome::Reply(reply) => Event::Reply(reply.from_addr.into(), reply.sequence),
+				ProbeOutcome::Late(reply) => Event::Late(reply.from_addr.into(), reply.sequence),
+				ProbeOutcome::Timeout { dest, sequence } => Event::Timeout(*dest, *sequence),
+				ProbeOutcome::AddressChanged { old, new } => Event::AddressChanged(*old, *new),
+			};
+		}
+	}
+
+	const TARGET: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+	const TIMEOUT: Duration = Duration::from_millis(200);
+
+	fn pinger(fake: &Arc<FakeTransport>) -> Pinger {
+		return Pinger::builder(TARGET.to_string()).transport(fake.clone()).timeout(TIMEOUT).build().unwrap();
+	}
+
+	fn ping(fake: &Arc<FakeTransport>) -> Result<PingReply, String> {
+		return pinger(fake).ping(&CancelToken::new().unwrap());
+	}
+
+	/// Runs a session of `count` probes and returns what it reported, along with its statistics.
+	fn session(fake: &Arc<FakeTransport>, count: u64) -> (Vec<Event>, Statistics) {
+		let mut session = PingSession::new(pinger(fake)).count(Some(count)).interval(Duration::from_millis(10));
+		let events = session.by_ref().map(|o| Event::from(&o)).collect();
+
+		let stats = session.statistics().iter().find(|(a, _)| *a == TARGET).map(|(_, s)| s.clone()).unwrap();
+		return (events, stats);
+	}
+
+	#[test]
+	fn cancel_interrupts_ping() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::Drop);
+		let mut pinger = Pinger::builder(TARGET.to_string()).transport(fake.clone()).timeout(Duration::from_secs(5)).build().unwrap();
+
+		let cancel = CancelToken::new().unwrap();
+		let canceller = cancel.clone();
+		std::thread::spawn(move || {
+			std::thread::sleep(Duration::from_millis(10));
+			canceller.cancel();
+		});
+
+		let started = Instant::now();
+		assert_eq!(pinger.ping(&cancel).err(), Some("stop signal".to_string()));
+		// woken by the token itself, not by the next check of it
+		assert!(started.elapsed() < Duration::from_millis(40), "{:?}", started.elapsed());
+	}
+
+	#[test]
+	fn reply() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.respond(FakeResponse::Reply(Duration::from_millis(20)));
+
+		let reply = ping(&fake).unwrap();
+		assert_eq!(reply.sequence, 1);
+		assert_eq!(IpAddr::V4(reply.from_addr), TARGET);
+		assert_eq!(reply.local_addr, Ipv4Addr::LOCALHOST);
+		assert!(reply.elapsed >= Duration::from_millis(20));
+		assert_eq!(fake.sent().len(), 1);
+
+		let (events, stats) = session(&fake, 2);
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Reply(TARGET, 1), Event::Sent(TARGET, 2), Event::Reply(TARGET, 2)]);
+		assert_eq!((stats.transmitted, stats.received), (2, 2));
+	}
+
+	#[test]
+	fn drop_times_out() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::Drop);
+
+		assert_eq!(ping(&fake).err(), Some("timeout".to_string()));
+
+		let (events, stats) = session(&fake, 1);
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Timeout(TARGET, 1)]);
+		assert_eq!((stats.transmitted, stats.received), (1, 0));
+		assert_eq!(stats.loss_percent(), 100.0);
+	}
+
+	#[test]
+	fn duplicate_is_counted_once() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::Duplicate(Duration::ZERO, 3));
+
+		let (events, stats) = session(&fake, 2);
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Reply(TARGET, 1), Event::Sent(TARGET, 2), Event::Reply(TARGET, 2)]);
+		assert_eq!((stats.transmitted, stats.received), (2, 2));
+	}
+
+	#[test]
+	fn corrupt_checksum_is_discarded() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::Corrupt(Duration::ZERO));
+
+		assert_eq!(ping(&fake).err(), Some("timeout".to_string()));
+
+		let (events, stats) = session(&fake, 1);
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Timeout(TARGET, 1)]);
+		assert_eq!(stats.received, 0);
+	}
+
+	#[test]
+	fn unreachable_is_not_a_reply() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::Unreachable(Duration::ZERO, 1));
+
+		assert_eq!(ping(&fake).err(), Some("timeout".to_string()));
+
+		let (events, _) = session(&fake, 1);
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Timeout(TARGET, 1)]);
+	}
+
+	#[test]
+	fn time_exceeded_is_not_a_reply() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::TimeExceeded(Duration::ZERO, Ipv4Addr::new(10, 0, 0, 1)));
+
+		assert_eq!(ping(&fake).err(), Some("timeout".to_string()));
+
+		let (events, _) = session(&fake, 1);
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Timeout(TARGET, 1)]);
+	}
+
+	#[test]
+	fn late_reply() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.respond(FakeResponse::Reply(TIMEOUT * 2));
+		fake.set_default(FakeResponse::Drop);
+
+		// the second probe keeps the session around long enough to see the first reply come in
+		let mut session = PingSession::new(pinger(&fake)).count(Some(2)).interval(TIMEOUT * 3 / 2);
+		let events: Vec<Event> = session.by_ref().map(|o| Event::from(&o)).collect();
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Timeout(TARGET, 1), Event::Sent(TARGET, 2), Event::Late(TARGET, 1), Event::Timeout(TARGET, 2)]);
+
+		let stats = &session.statistics()[0].1;
+		assert_eq!((stats.transmitted, stats.received), (2, 0));
+	}
+
+	#[test]
+	fn send_failure() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.fail_sends(true);
+
+		assert!(ping(&fake).is_err());
+
+		let (events, stats) = session(&fake, 2);
+		assert_eq!(events, vec![Event::SendFailed(TARGET), Event::SendFailed(TARGET)]);
+		assert_eq!((stats.transmitted, stats.received), (2, 0));
+	}
+
+	#[test]
+	fn deadline_passes_with_a_probe_in_flight() {
+		let fake = Arc::new(FakeTransport::default());
+		fake.set_default(FakeResponse::Drop);
+
+		// the probe would only time out after a second, the deadline ends the session first
+		let pinger = Pinger::builder(TARGET.to_string()).transport(fake.clone()).timeout(Duration::from_secs(1)).build().unwrap();
+		let mut session = PingSession::new(pinger).interval(Duration::from_secs(1)).deadline(Some(Duration::from_millis(100)));
+
+		let started = Instant::now();
+		let events: Vec<Event> = session.by_ref().map(|o| Event::from(&o)).collect();
+
+		assert_eq!(events, vec![Event::Sent(TARGET, 1)]);
+		assert!(started.elapsed() < Duration::from_millis(500));
+		assert!(session.is_finished());
+	}
+
+	#[test]
+	fn preload_goes_out_at_once() {
+		let fake = Arc::new(FakeTransport::default());
+
+		let mut session = PingSession::new(pinger(&fake)).count(Some(4)).preload(3).interval(Duration::from_secs(60));
+		let events: Vec<Event> = session.by_ref().take(3).map(|o| Event::from(&o)).collect();
+
+		// the interval is a minute, only a burst gets three probes out right away
+		assert_eq!(events, vec![Event::Sent(TARGET, 1), Event::Sent(TARGET, 2), Event::Sent(TARGET, 3)]);
+		assert_eq!(fake.sent().len(), 3);
+	}
+}
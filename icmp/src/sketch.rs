@@ -0,0 +1,182 @@
+use std::{
+	collections::BTreeMap,
+	time::Duration
+};
+
+/// Relative error of the quantiles `QuantileSketch::default` returns.
+const DEFAULT_ACCURACY: f64 = 0.01;
+
+/// Streaming quantiles over durations in bounded memory (a DDSketch).
+/// Values are counted in logarithmic buckets, so every quantile is within
+/// the relative accuracy of the true one. At 1% a bucket covers 2% of its
+/// value, which keeps rtts between 1 µs and an hour in about 1100 buckets
+/// however long the run.
+#[derive(Clone, Debug)]
+pub struct QuantileSketch {
+	gamma: f64,
+	ln_gamma: f64,
+
+	// bucket index to count, index i holds values in (gamma^(i-1), gamma^i] nanoseconds
+	buckets: BTreeMap<i32, u64>,
+	zeros: u64,
+	count: u64,
+
+	min: Option<Duration>,
+	max: Option<Duration>,
+}
+
+impl Default for QuantileSketch {
+	fn default() -> Self {
+		return Self::new(DEFAULT_ACCURACY);
+	}
+}
+
+impl QuantileSketch {
+	/// `relative_accuracy` is the largest relative error a quantile may have, between 0 and 1.
+	pub fn new(relative_accuracy: f64) -> Self {
+		let accuracy = relative_accuracy.clamp(1e-6, 0.5);
+		let gamma = (1.0 + accuracy) / (1.0 - accuracy);
+
+		return Self {
+			gamma,
+			ln_gamma: gamma.ln(),
+
+			buckets: BTreeMap::new(),
+			zeros: 0,
+			count: 0,
+
+			min: None,
+			max: None,
+		};
+	}
+
+	pub fn add(&mut self, value: Duration) {
+		self.count += 1;
+		self.min = Some(self.min.map_or(value, |m| m.min(value)));
+		self.max = Some(self.max.map_or(value, |m| m.max(value)));
+
+		let nanos = value.as_nanos() as f64;
+		if nanos < 1.0 {
+			self.zeros += 1;
+			return;
+		}
+
+		let index = (nanos.ln() / self.ln_gamma).ceil() as i32;
+		*self.buckets.entry(index).or_insert(0) += 1;
+	}
+
+	pub fn count(&self) -> u64 {
+		return self.count;
+	}
+
+	pub fn min(&self) -> Option<Duration> {
+		return self.min;
+	}
+
+	pub fn max(&self) -> Option<Duration> {
+		return self.max;
+	}
+
+	/// The value at quantile `q` (0 is the minimum, 1 the maximum), None while empty.
+	/// The extremes are exact, everything between is within the relative accuracy.
+	pub fn quantile(&self, q: f64) -> Option<Duration> {
+		let (min, max) = (self.min?, self.max?);
+
+		let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+		if rank == 0 {
+			return Some(min);
+		}
+		if rank == self.count - 1 {
+			return Some(max);
+		}
+
+		let mut seen = 0;
+		for (value, count) in self.buckets() {
+			seen += count;
+			if seen > rank {
+				return Some(value.clamp(min, max));
+			}
+		}
+
+		return Some(max);
+	}
+
+	/// Every non-empty bucket in ascending order, as a value representing it and how many fell in.
+	pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+		let zeros = (self.zeros > 0).then_some((Duration::ZERO, self.zeros));
+
+		return zeros.into_iter().chain(self.buckets.iter().map(|(index, count)| {
+			// within the relative accuracy of every value in the bucket
+			let value = 2.0 * self.gamma.powi(*index) / (self.gamma + 1.0);
+			(Duration::from_nanos(value as u64), *count)
+		}));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sketch_of(values: &[Duration]) -> QuantileSketch {
+		let mut sketch = QuantileSketch::default();
+		for value in values {
+			sketch.add(*value);
+		}
+		return sketch;
+	}
+
+	#[test]
+	fn empty() {
+		let sketch = QuantileSketch::default();
+		assert_eq!(sketch.count(), 0);
+		assert_eq!(sketch.quantile(0.5), None);
+		assert_eq!(sketch.buckets().count(), 0);
+	}
+
+	#[test]
+	fn single_value() {
+		let value = Duration::from_micros(12345);
+		let sketch = sketch_of(&[value]);
+
+		// clamped to the only value seen, so exact rather than within 1%
+		for q in [0.0, 0.25, 0.5, 0.99, 1.0] {
+			assert_eq!(sketch.quantile(q), Some(value));
+		}
+	}
+
+	#[test]
+	fn extremes_are_exact() {
+		let sketch = sketch_of(&[Duration::from_micros(1001), Duration::from_micros(5003), Duration::from_micros(9007)]);
+		assert_eq!(sketch.quantile(0.0), Some(Duration::from_micros(1001)));
+		assert_eq!(sketch.quantile(1.0), Some(Duration::from_micros(9007)));
+
+		// out of range quantiles stick to the ends
+		assert_eq!(sketch.quantile(-1.0), Some(Duration::from_micros(1001)));
+		assert_eq!(sketch.quantile(2.0), Some(Duration::from_micros(9007)));
+	}
+
+	#[test]
+	fn zero_durations() {
+		let sketch = sketch_of(&[Duration::ZERO, Duration::ZERO, Duration::from_millis(1)]);
+		assert_eq!(sketch.buckets().next(), Some((Duration::ZERO, 2)));
+		assert_eq!(sketch.quantile(0.5), Some(Duration::ZERO));
+	}
+
+	#[test]
+	fn within_relative_accuracy() {
+		// log uniform between 1 µs and an hour, the range the buckets are sized for
+		let mut rand = fastrand::Rng::with_seed(3550);
+		let mut values: Vec<Duration> = (0..10000).map(|_| Duration::from_secs_f64(1e-6 * 3.6e9f64.powf(rand.f64()))).collect();
+		let sketch = sketch_of(&values);
+		values.sort();
+
+		assert_eq!(sketch.count(), 10000);
+		assert!(sketch.buckets().count() <= 1101);
+		for percent in 0..=100 {
+			let q = percent as f64 / 100.0;
+			let exact = values[(q * (values.len() - 1) as f64).round() as usize].as_secs_f64();
+			let estimate = sketch.quantile(q).unwrap().as_secs_f64();
+			assert!((estimate - exact).abs() <= exact * DEFAULT_ACCURACY, "p{percent}: {estimate} against {exact}");
+		}
+	}
+}
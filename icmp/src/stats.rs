@@ -0,0 +1,224 @@
+use crate::sketch::QuantileSketch;
+
+use std::time::Duration;
+
+#[derive(Default, Clone)]
+pub struct Statistics {
+	pub transmitted: u64,
+	pub received: u64,
+	
+	min: Option<Duration>,
+	max: Option<Duration>,
+	sum: Duration,
+	
+	// rfc 3550 interarrival jitter in seconds, and the sum of the differences it smooths
+	last: Option<Duration>,
+	jitter: f64,
+	differences: Duration,
+	
+	sketch: QuantileSketch,
+}
+
+impl Statistics {
+	pub fn new() -> Self {
+		return Self::default();
+	}
+	
+	pub fn add_sent(&mut self) {
+		self.transmitted += 1;
+	}
+	
+	pub fn add_reply(&mut self, elapsed: Duration) {
+		self.received += 1;
+		self.sum += elapsed;
+		
+		self.min = Some(self.min.map_or(elapsed, |m| m.min(elapsed)));
+		self.max = Some(self.max.map_or(elapsed, |m| m.max(elapsed)));
+		
+		if let Some(last) = self.last {
+			let difference = elapsed.abs_diff(last);
+			self.jitter += (difference.as_secs_f64() - self.jitter) / 16.0;
+			self.differences += difference;
+		}
+		self.last = Some(elapsed);
+		self.sketch.add(elapsed);
+	}
+	
+	pub fn loss_percent(&self) -> f32 {
+		if self.transmitted == 0 {
+			return 0.0;
+		}
+		
+		return self.transmitted.saturating_sub(self.received) as f32 / self.transmitted as f32 * 100.0;
+	}
+	
+	pub fn min(&self) -> Option<Duration> {
+		return self.min;
+	}
+	
+	pub fn max(&self) -> Option<Duration> {
+		return self.max;
+	}
+	
+	/// Total rtt of every reply, for averaging over several paths.
+	pub fn sum(&self) -> Duration {
+		return self.sum;
+	}
+	
+	pub fn avg(&self) -> Option<Duration> {
+		if self.received == 0 {
+			return None;
+		}
+		
+		return Some(Duration::from_secs_f64(self.sum.as_secs_f64() / self.received as f64));
+	}
+	
+	/// Interarrival jitter as in RFC 3550: the difference between consecutive
+	/// rtts, smoothed with a gain of 1/16. None before the second reply.
+	pub fn jitter(&self) -> Option<Duration> {
+		if self.received < 2 {
+			return None;
+		}
+		
+		return Some(Duration::from_secs_f64(self.jitter));
+	}
+	
+	/// Mean absolute deviation between consecutive rtts, the unsmoothed
+	/// counterpart of `jitter`.
+	pub fn mean_deviation(&self) -> Option<Duration> {
+		if self.received < 2 {
+			return None;
+		}
+		
+		return Some(Duration::from_secs_f64(self.differences.as_secs_f64() / (self.received - 1) as f64));
+	}
+	
+	/// The rtt below which `p` percent of the replies came back, within 1%.
+	pub fn percentile(&self, p: f64) -> Option<Duration> {
+		return self.sketch.quantile(p / 100.0);
+	}
+	
+	/// R-factor of a voice call over the path, from the simplified E-model
+	/// (ITU-T G.107). Half the average rtt stands in for the one-way delay.
+	pub fn r_factor(&self) -> Option<f64> {
+		let latency = self.avg()?.as_secs_f64() * 1000.0 / 2.0;
+		let jitter = self.jitter().unwrap_or_default().as_secs_f64() * 1000.0;
+		
+		// jitter counts double, a dejitter buffer has to absorb it; 10 ms for the codec
+		let effective = latency + 2.0 * jitter + 10.0;
+		let r = if effective < 160.0 {
+			93.2 - effective / 40.0
+		} else {
+			93.2 - (effective - 120.0) / 10.0
+		};
+		
+		return Some((r - 2.5 * self.loss_percent() as f64).clamp(0.0, 100.0));
+	}
+	
+	/// Mean opinion score from 1 (bad) to 4.5 (best), derived from `r_factor`.
+	pub fn mos(&self) -> Option<f64> {
+		let r = self.r_factor()?;
+		return Some((1.0 + 0.035 * r + 0.000007 * r * (r - 60.0) * (100.0 - r)).clamp(1.0, 4.5));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	fn statistics(transmitted: u64, rtts: &[u64]) -> Statistics {
+		let mut statistics = Statistics::new();
+		for _ in 0..transmitted {
+			statistics.add_sent();
+		}
+		for rtt in rtts {
+			statistics.add_reply(Duration::from_millis(*rtt));
+		}
+		return statistics;
+	}
+	
+	fn assert_ms(duration: Option<Duration>, ms: f64) {
+		let actual = duration.unwrap().as_secs_f64() * 1000.0;
+		assert!((actual - ms).abs() < 1e-6, "{actual} ms, expected {ms}");
+	}
+	
+	#[test]
+	fn summary() {
+		let s = statistics(4, &[10, 30, 20]);
+		assert_eq!(s.loss_percent(), 25.0);
+		assert_eq!(s.min(), Some(Duration::from_millis(10)));
+		assert_eq!(s.max(), Some(Duration::from_millis(30)));
+		assert_eq!(s.sum(), Duration::from_millis(60));
+		assert_ms(s.avg(), 20.0);
+		
+		let empty = Statistics::new();
+		assert_eq!(empty.loss_percent(), 0.0);
+		assert_eq!(empty.avg(), None);
+		assert_eq!(empty.percentile(50.0), None);
+	}
+	
+	#[test]
+	fn jitter_gain() {
+		assert_eq!(statistics(1, &[10]).jitter(), None);
+		
+		// each difference moves the estimate a sixteenth of the way towards it
+		assert_ms(statistics(2, &[10, 20]).jitter(), 10.0 / 16.0);
+		assert_ms(statistics(3, &[10, 20, 20]).jitter(), 10.0 / 16.0 * 15.0 / 16.0);
+		assert_ms(statistics(3, &[10, 20, 15]).jitter(), 10.0 / 16.0 + (5.0 - 10.0 / 16.0) / 16.0);
+		
+		// steady differences converge on the difference
+		let alternating: Vec<u64> = (0..500).map(|i| if i % 2 == 0 { 10 } else { 14 }).collect();
+		assert_ms(statistics(500, &alternating).jitter(), 4.0);
+	}
+	
+	#[test]
+	fn mean_deviation() {
+		assert_eq!(statistics(1, &[10]).mean_deviation(), None);
+		assert_ms(statistics(3, &[10, 20, 15]).mean_deviation(), 7.5);
+		assert_ms(statistics(4, &[10, 10, 10, 10]).mean_deviation(), 0.0);
+	}
+	
+	#[test]
+	fn percentile() {
+		let rtts: Vec<u64> = (1..=100).collect();
+		let s = statistics(100, &rtts);
+		assert_eq!(s.percentile(0.0), Some(Duration::from_millis(1)));
+		assert_eq!(s.percentile(100.0), Some(Duration::from_millis(100)));
+		
+		let median = s.percentile(50.0).unwrap().as_secs_f64() * 1000.0;
+		assert!((median - 51.0).abs() <= 51.0 * 0.01, "{median}");
+	}
+	
+	#[test]
+	fn r_factor_clamps() {
+		assert_eq!(Statistics::new().r_factor(), None);
+		
+		// no delay, jitter or loss leaves only the codec's 10 ms
+		let r = statistics(1, &[0]).r_factor().unwrap();
+		assert!((r - (93.2 - 10.0 / 40.0)).abs() < 1e-9, "{r}");
+		
+		// past 160 ms effective delay the penalty steepens
+		let r = statistics(1, &[400]).r_factor().unwrap();
+		assert!((r - (93.2 - (210.0 - 120.0) / 10.0)).abs() < 1e-9, "{r}");
+		
+		// heavy loss or a very slow path would go negative
+		assert_eq!(statistics(2, &[1]).r_factor(), Some(0.0));
+		assert_eq!(statistics(1, &[4000]).r_factor(), Some(0.0));
+	}
+	
+	#[test]
+	fn mos_bounds() {
+		assert_eq!(Statistics::new().mos(), None);
+		assert_eq!(statistics(2, &[1]).mos(), Some(1.0));
+		
+		let best = statistics(1, &[0]).mos().unwrap();
+		assert!(best > 4.4 && best <= 4.5, "{best}");
+		
+		let mut previous = 1.0;
+		for rtt in (0..=600).rev().step_by(50) {
+			let mos = statistics(1, &[rtt]).mos().unwrap();
+			assert!((1.0..=4.5).contains(&mos) && mos >= previous, "{rtt} ms: {mos}");
+			previous = mos;
+		}
+	}
+}
@@ -0,0 +1,519 @@
+use crate::{
+	batch::{
+		self,
+		RecvBatch,
+		BATCH_SIZE
+	},
+	ms_since_midnight,
+	pcap::PcapWriter,
+	packet::{
+		self,
+		icmp::{Family, Message},
+		ipv4
+	},
+};
+use logger::prelude::*;
+
+use std::{
+	collections::{
+		HashMap,
+		VecDeque
+	},
+	fs::File,
+	io::BufWriter,
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		SocketAddr,
+		UdpSocket
+	},
+	os::fd::AsRawFd,
+	sync::Arc,
+	sync::{
+		Condvar,
+		Mutex,
+		MutexGuard
+	},
+	time::{
+		Duration,
+		Instant,
+		SystemTime
+	}
+};
+
+use socket2::{
+	SockAddr,
+	Socket
+};
+use util::cancel::{
+	CancelToken,
+	Wait
+};
+
+/// Moves raw ICMP datagrams for the probe engine. Sent datagrams are ICMP
+/// messages, or full IPv4 datagrams when a raw header is used, and received
+/// ones always start with the IPv4 header, like on a raw socket.
+pub trait Transport: Send + Sync {
+	/// Sends one datagram and returns how many bytes went out.
+	fn send_to(&self, datagram: &[u8], dest: IpAddr) -> Result<usize, String>;
+
+	/// Sends the datagrams in order and returns how many went out.
+	fn send_batch(&self, datagrams: &[(&[u8], IpAddr)]) -> Result<usize, String> {
+		for (i, (datagram, dest)) in datagrams.iter().enumerate() {
+			if let Err(e) = self.send_to(datagram, *dest) {
+				if i == 0 {
+					return Err(e);
+				}
+				return Ok(i);
+			}
+		}
+		return Ok(datagrams.len());
+	}
+
+	/// Blocks until a datagram can be read, `cancel` fires or `timeout` passes.
+	fn wait_readable(&self, cancel: &CancelToken, timeout: Duration) -> Result<Wait, String>;
+
+	/// Fills `batch` with the datagrams already queued, without blocking.
+	/// Returns how many were read.
+	fn recv_batch(&self, batch: &mut RecvBatch) -> Result<usize, String>;
+
+	/// TOS byte outgoing datagrams are sent with.
+	fn tos(&self) -> u8;
+
+	fn set_tos(&self, tos: u8) -> Result<(), String>;
+}
+
+/// The real thing, a raw ICMP socket opened by `open_socket`.
+pub struct SocketTransport {
+	socket: Socket,
+}
+
+impl SocketTransport {
+	pub fn new(socket: Socket) -> Self {
+		return Self { socket };
+	}
+}
+
+impl Transport for SocketTransport {
+	fn send_to(&self, datagram: &[u8], dest: IpAddr) -> Result<usize, String> {
+		return match self.socket.send_to(datagram, &SocketAddr::new(dest, 0).into()) {
+			Ok(b) => Ok(b),
+			Err(e) => {
+				error!(desc = e.to_string(), "Socket::send_to");
+				Err("Socket::send_to".to_string())
+			}
+		};
+	}
+
+	fn send_batch(&self, datagrams: &[(&[u8], IpAddr)]) -> Result<usize, String> {
+		let dests: Vec<SockAddr> = datagrams.iter().map(|(_, addr)| SocketAddr::new(*addr, 0).into()).collect();
+		let datagrams: Vec<(&[u8], &SockAddr)> = datagrams.iter().zip(dests.iter()).map(|((d, _), a)| (*d, a)).collect();
+		return batch::send_batch(self.socket.as_raw_fd(), &datagrams);
+	}
+
+	fn wait_readable(&self, cancel: &CancelToken, timeout: Duration) -> Result<Wait, String> {
+		return cancel.wait_readable(self.socket.as_raw_fd(), Some(timeout));
+	}
+
+	fn recv_batch(&self, batch: &mut RecvBatch) -> Result<usize, String> {
+		return batch.recv(self.socket.as_raw_fd());
+	}
+
+	fn tos(&self) -> u8 {
+		return self.socket.tos().map_or(0, |t| t as u8);
+	}
+
+	fn set_tos(&self, tos: u8) -> Result<(), String> {
+		return crate::set_tos(&self.socket, tos);
+	}
+}
+
+/// How the fake answers a request.
+#[derive(PartialEq, Clone, Debug)]
+pub enum FakeResponse {
+	/// Answer after the delay.
+	Reply(Duration),
+	/// Never answer.
+	Drop,
+	/// Answer `count` times, all after the delay.
+	Duplicate(Duration, usize),
+	/// Answer after the delay with a damaged payload, so the checksum no longer matches.
+	Corrupt(Duration),
+	/// Destination unreachable with the given code from the target.
+	Unreachable(Duration, u8),
+	/// Time exceeded in transit from the given router.
+	TimeExceeded(Duration, Ipv4Addr),
+}
+
+struct FakeState {
+	responses: VecDeque<FakeResponse>,
+	default: FakeResponse,
+	/// Datagrams waiting to be received, ordered by when they become readable.
+	queue: VecDeque<(Instant, Vec<u8>)>,
+	sent: Vec<(IpAddr, Vec<u8>)>,
+	fail_sends: bool,
+	tos: u8,
+	ttl: u8,
+}
+
+/// In-memory transport for exercising the engine and `Pinger` without raw
+/// socket privileges. Every echo or timestamp request is answered according
+/// to the next queued `FakeResponse`, or the default one when none is queued,
+/// and arbitrary datagrams can be injected on top.
+pub struct FakeTransport {
+	local: Ipv4Addr,
+	state: Mutex<FakeState>,
+	ready: Condvar,
+}
+
+/// `CancelToken` cannot wake a condvar, so waits check it at least this often.
+const FAKE_CANCEL_CHECK: Duration = Duration::from_millis(10);
+
+impl Default for FakeTransport {
+	fn default() -> Self {
+		return Self::new(Ipv4Addr::LOCALHOST);
+	}
+}
+
+impl FakeTransport {
+	/// `local` is the address replies are delivered to.
+	pub fn new(local: Ipv4Addr) -> Self {
+		return Self {
+			local,
+			state: Mutex::new(FakeState {
+				responses: VecDeque::new(),
+				default: FakeResponse::Reply(Duration::ZERO),
+				queue: VecDeque::new(),
+				sent: Vec::new(),
+				fail_sends: false,
+				tos: 0,
+				ttl: 64,
+			}),
+			ready: Condvar::new(),
+		};
+	}
+
+	fn state(&self) -> Result<MutexGuard<'_, FakeState>, String> {
+		return self.state.lock().map_err(|_| "fake transport poisoned".to_string());
+	}
+
+	/// Queues how the next request is answered. Queued responses are used in order.
+	pub fn respond(&self, response: FakeResponse) {
+		if let Ok(mut state) = self.state() {
+			state.responses.push_back(response);
+		}
+	}
+
+	/// How requests are answered once the queued responses run out.
+	pub fn set_default(&self, response: FakeResponse) {
+		if let Ok(mut state) = self.state() {
+			state.default = response;
+		}
+	}
+
+	/// TTL of the datagrams built by the fake.
+	pub fn set_ttl(&self, ttl: u8) {
+		if let Ok(mut state) = self.state() {
+			state.ttl = ttl;
+		}
+	}
+
+	/// Makes every send fail until turned off again.
+	pub fn fail_sends(&self, fail: bool) {
+		if let Ok(mut state) = self.state() {
+			state.fail_sends = fail;
+		}
+	}
+
+	/// Delivers `datagram`, which has to start with an IPv4 header, after `delay`.
+	pub fn inject(&self, datagram: Vec<u8>, delay: Duration) {
+		if let Ok(mut state) = self.state() {
+			Self::enqueue(&mut state, Instant::now() + delay, datagram);
+			self.ready.notify_all();
+		}
+	}
+
+	/// Every datagram sent so far, with its destination.
+	pub fn sent(&self) -> Vec<(IpAddr, Vec<u8>)> {
+		return self.state().map_or(Vec::new(), |s| s.sent.clone());
+	}
+
+	fn enqueue(state: &mut FakeState, due: Instant, datagram: Vec<u8>) {
+		let at = state.queue.partition_point(|(d, _)| *d <= due);
+		state.queue.insert(at, (due, datagram));
+	}
+
+	/// Builds what the network would send back for `datagram`, if anything.
+	fn answer(&self, state: &FakeState, response: &FakeResponse, datagram: &[u8], dest: IpAddr) -> Result<Vec<(Duration, Vec<u8>)>, String> {
+		let target = match dest {
+			IpAddr::V4(a) => a,
+			IpAddr::V6(_) => return Ok(Vec::new()),
+		};
+
+		// with IP_HDRINCL the request comes with its own header
+		let (message, tos) = if datagram.first().is_some_and(|b| b >> 4 == 4) {
+			let packet = ipv4::Ipv4View::new(datagram)?;
+			(packet.payload(), packet.tos())
+		} else {
+			(datagram, state.tos)
+		};
+
+		let reply = match Message::decode(Family::V4, message)? {
+			Message::EchoRequest { ident, sequence, payload } => Message::EchoReply { ident, sequence, payload },
+			Message::TimestampRequest { ident, sequence, originate, trailing, .. } => {
+				let now = ms_since_midnight();
+				Message::TimestampReply { ident, sequence, originate, receive: now, transmit: now, trailing }
+			}
+			_ => return Ok(Vec::new()),
+		};
+
+		// errors quote the request's header and the first 8 bytes of its message
+		let original = || -> Result<Vec<u8>, String> {
+			let request = ipv4::IPV4Packet::new(self.local.to_bits(), target.to_bits(), Vec::from(message));
+			let mut bytes = vec![0; request.size()?];
+			let size = request.encode(&mut bytes)?;
+			bytes.truncate((size - message.len() + 8).min(size));
+			return Ok(bytes);
+		};
+
+		let (delay, from, reply, count) = match response {
+			FakeResponse::Drop => return Ok(Vec::new()),
+			FakeResponse::Reply(delay) | FakeResponse::Corrupt(delay) => (*delay, target, reply, 1),
+			FakeResponse::Duplicate(delay, count) => (*delay, target, reply, *count),
+			FakeResponse::Unreachable(delay, code) => (*delay, target, Message::DestUnreachable { code: *code, rest: [0; 4], original: original()? }, 1),
+			FakeResponse::TimeExceeded(delay, router) => (*delay, *router, Message::TimeExceeded { code: 0, rest: [0; 4], original: original()? }, 1),
+		};
+
+		let mut packet = ipv4::IPV4Packet::new(from.to_bits(), self.local.to_bits(), reply.to_bytes(Family::V4)?);
+		packet.tos = tos;
+		packet.ttl = state.ttl;
+		let mut bytes = vec![0; packet.size()?];
+		packet.encode(&mut bytes)?;
+
+		if matches!(response, FakeResponse::Corrupt(_)) && let Some(last) = bytes.last_mut() {
+			*last ^= 0xff;
+		}
+
+		return Ok(vec![(delay, bytes); count]);
+	}
+}
+
+impl Transport for FakeTransport {
+	fn send_to(&self, datagram: &[u8], dest: IpAddr) -> Result<usize, String> {
+		let mut state = self.state()?;
+		if state.fail_sends {
+			return Err("Socket::send_to".to_string());
+		}
+		state.sent.push((dest, Vec::from(datagram)));
+
+		let response = match state.responses.pop_front() {
+			Some(r) => r,
+			None => state.default.clone(),
+		};
+
+		let now = Instant::now();
+		let answers = match self.answer(&state, &response, datagram, dest) {
+			Ok(a) => a,
+			Err(e) => {
+				debug!(desc = e, "fake transport could not answer");
+				Vec::new()
+			}
+		};
+		for (delay, answer) in answers {
+			Self::enqueue(&mut state, now + delay, answer);
+		}
+		self.ready.notify_all();
+
+		return Ok(datagram.len());
+	}
+
+	fn wait_readable(&self, cancel: &CancelToken, timeout: Duration) -> Result<Wait, String> {
+		let deadline = Instant::now() + timeout;
+		let mut state = self.state()?;
+
+		loop {
+			if cancel.is_cancelled() {
+				return Ok(Wait::Cancelled);
+			}
+
+			let now = Instant::now();
+			let next = state.queue.front().map(|(due, _)| *due);
+			if next.is_some_and(|due| due <= now) {
+				return Ok(Wait::Ready);
+			}
+			if now >= deadline {
+				return Ok(Wait::TimedOut);
+			}
+
+			let wake = next.map_or(deadline, |due| due.min(deadline));
+			state = match self.ready.wait_timeout(state, (wake - now).min(FAKE_CANCEL_CHECK)) {
+				Ok((s, _)) => s,
+				Err(_) => return Err("fake transport poisoned".to_string()),
+			};
+		}
+	}
+
+	fn recv_batch(&self, batch: &mut RecvBatch) -> Result<usize, String> {
+		let mut state = self.state()?;
+		let now = Instant::now();
+
+		batch.clear();
+		let mut count = 0;
+		while count < BATCH_SIZE && state.queue.front().is_some_and(|(due, _)| *due <= now) {
+			if let Some((_, datagram)) = state.queue.pop_front() {
+				batch.push(&datagram);
+				count += 1;
+			}
+		}
+
+		return Ok(count);
+	}
+
+	fn tos(&self) -> u8 {
+		return self.state().map_or(0, |s| s.tos);
+	}
+
+	fn set_tos(&self, tos: u8) -> Result<(), String> {
+		self.state()?.tos = tos;
+		return Ok(());
+	}
+}
+
+/// Passes everything through to another transport and writes every datagram
+/// sent and received to a pcap file. Sent ICMP messages get the IPv4 header
+/// the kernel would put in front of them, rebuilt from the socket settings.
+pub struct CaptureTransport {
+	inner: Arc<dyn Transport>,
+	writer: Mutex<PcapWriter<BufWriter<File>>>,
+	/// Source, TTL and options of outgoing datagrams, everything else is filled in per packet.
+	template: ipv4::IPV4Packet,
+	/// Source address the kernel picks for each destination, when the socket is not bound.
+	routes: Mutex<HashMap<Ipv4Addr, Ipv4Addr>>,
+}
+
+impl CaptureTransport {
+	pub fn new(inner: Arc<dyn Transport>, writer: PcapWriter<BufWriter<File>>, source: Option<Ipv4Addr>, ttl: u8, options: Vec<ipv4::IPV4Option>) -> Self {
+		let mut template = ipv4::IPV4Packet::new(source.map_or(0, |s| s.to_bits()), 0, Vec::new());
+		template.ttl = ttl;
+		// path MTU discovery is on by default for raw sockets
+		template.frag_off = ipv4::FLAG_DONT_FRAGMENT;
+		template.options = options;
+
+		return Self {
+			inner,
+			writer: Mutex::new(writer),
+			template,
+			routes: Mutex::new(HashMap::new()),
+		};
+	}
+
+	/// Address a datagram to `dest` leaves from, the bound source or else the
+	/// one the routing table picks. Connecting a UDP socket sends nothing.
+	fn source_for(&self, dest: Ipv4Addr) -> Ipv4Addr {
+		if self.template.from_addr != 0 {
+			return Ipv4Addr::from_bits(self.template.from_addr);
+		}
+
+		let mut routes = match self.routes.lock() {
+			Ok(r) => r,
+			Err(_) => return Ipv4Addr::UNSPECIFIED,
+		};
+
+		return *routes.entry(dest).or_insert_with(|| {
+			let local = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+				.and_then(|s| s.connect((dest, 9)).map(|_| s))
+				.and_then(|s| s.local_addr());
+			return match local {
+				Ok(SocketAddr::V4(a)) => *a.ip(),
+				_ => Ipv4Addr::UNSPECIFIED,
+			};
+		});
+	}
+
+	fn record_sent(&self, writer: &mut PcapWriter<BufWriter<File>>, time: SystemTime, datagram: &[u8], dest: IpAddr) {
+		let IpAddr::V4(dest) = dest else { return };
+
+		// with IP_HDRINCL the header is already there, only a zero source is filled in by the kernel
+		if datagram.first().is_some_and(|b| b >> 4 == 4) {
+			let mut datagram = Vec::from(datagram);
+			if datagram.len() >= 20 && datagram[12..16] == [0; 4] {
+				datagram[12..16].copy_from_slice(&self.source_for(dest).octets());
+				datagram[10..12].copy_from_slice(&[0, 0]);
+				let header_size = ((datagram[0] & 0x0f) as usize * 4).min(datagram.len());
+				let check = packet::checksum(&datagram[..header_size]);
+				datagram[10..12].copy_from_slice(&check.to_be_bytes());
+			}
+			let _ = writer.write(time, &datagram);
+			return;
+		}
+
+		let mut packet = self.template.clone();
+		packet.from_addr = self.source_for(dest).to_bits();
+		packet.dest_addr = dest.to_bits();
+		packet.tos = self.inner.tos();
+		packet.data = Vec::from(datagram);
+
+		let mut bytes = vec![0; packet.size().unwrap_or(0)];
+		match packet.encode(&mut bytes) {
+			Ok(_) => {
+				let _ = writer.write(time, &bytes);
+			}
+			Err(e) => debug!(desc = e, "could not rebuild ip header for capture"),
+		}
+	}
+}
+
+impl Transport for CaptureTransport {
+	fn send_to(&self, datagram: &[u8], dest: IpAddr) -> Result<usize, String> {
+		// hold the file while sending, a fast reply must not be written before its request
+		let mut writer = self.writer.lock().ok();
+		let time = SystemTime::now();
+		let bytes = self.inner.send_to(datagram, dest)?;
+		if let Some(writer) = writer.as_mut() {
+			self.record_sent(writer, time, datagram, dest);
+		}
+		return Ok(bytes);
+	}
+
+	fn send_batch(&self, datagrams: &[(&[u8], IpAddr)]) -> Result<usize, String> {
+		let mut writer = self.writer.lock().ok();
+		let time = SystemTime::now();
+		let count = self.inner.send_batch(datagrams)?;
+		if let Some(writer) = writer.as_mut() {
+			for (datagram, dest) in datagrams.iter().take(count) {
+				self.record_sent(writer, time, datagram, *dest);
+			}
+		}
+		return Ok(count);
+	}
+
+	fn wait_readable(&self, cancel: &CancelToken, timeout: Duration) -> Result<Wait, String> {
+		return self.inner.wait_readable(cancel, timeout);
+	}
+
+	fn recv_batch(&self, batch: &mut RecvBatch) -> Result<usize, String> {
+		let count = self.inner.recv_batch(batch)?;
+		if count == 0 {
+			return Ok(0);
+		}
+
+		let time = SystemTime::now();
+		// errors are logged by the writer, a broken capture must not stop the pings
+		if let Ok(mut writer) = self.writer.lock() {
+			for datagram in batch.iter() {
+				let _ = writer.write(time, datagram);
+			}
+			// keep the file usable if sonar is killed
+			let _ = writer.flush();
+		}
+		return Ok(count);
+	}
+
+	fn tos(&self) -> u8 {
+		return self.inner.tos();
+	}
+
+	fn set_tos(&self, tos: u8) -> Result<(), String> {
+		return self.inner.set_tos(tos);
+	}
+}
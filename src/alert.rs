@@ -0,0 +1,407 @@
+use logger::prelude::*;
+use icmp::prelude::*;
+
+use std::{
+	collections::VecDeque,
+	fmt,
+	process::{
+		Command,
+		Stdio
+	},
+	sync::mpsc,
+	thread::JoinHandle,
+	time::{
+		Duration,
+		Instant
+	}
+};
+
+/// A metric that alerted only recovers once it drops below this share of its
+/// threshold, so a value hovering around the threshold does not flap.
+const RECOVER_RATIO: f64 = 0.8;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `finish` waits for hooks that are still running.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Metric {
+	Loss,
+	Rtt,
+}
+
+impl Metric {
+	fn name(&self) -> &'static str {
+		return match self {
+			Metric::Loss => "loss",
+			Metric::Rtt => "rtt",
+		};
+	}
+	
+	fn unit(&self) -> &'static str {
+		return match self {
+			Metric::Loss => "%",
+			Metric::Rtt => " ms",
+		};
+	}
+}
+
+/// A metric crossing its threshold, or coming back below it.
+#[derive(Clone, Debug)]
+pub struct Transition {
+	pub metric: Metric,
+	pub firing: bool,
+	pub value: f64,
+	pub threshold: f64,
+	pub window: usize,
+}
+
+impl fmt::Display for Transition {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return write!(f, "{}: {} {:.1}{} over the last {} probes (threshold {:.1}{})",
+			if self.firing { "alert" } else { "recovered" },
+			self.metric.name(),
+			self.value,
+			self.metric.unit(),
+			self.window,
+			self.threshold,
+			self.metric.unit(),
+		);
+	}
+}
+
+struct Threshold {
+	metric: Metric,
+	limit: f64,
+	firing: bool,
+}
+
+/// Watches loss and average rtt over the last probes and runs the hook
+/// command or webhook whenever one of them crosses its threshold, and again
+/// when it recovers.
+pub struct Alerts {
+	target: String,
+	size: usize,
+	
+	// the last finished probes, with their rtt or None when lost
+	window: VecDeque<Option<Duration>>,
+	thresholds: Vec<Threshold>,
+	
+	command: Option<String>,
+	webhook: Option<String>,
+	
+	// transitions are handed to one worker so the hooks see them in order
+	queue: Option<mpsc::Sender<Transition>>,
+	worker: Option<JoinHandle<()>>,
+}
+
+impl Alerts {
+	/// Evaluates over the last `size` probes, and only once that many finished.
+	pub fn new(target: String, size: usize) -> Self {
+		return Self {
+			target,
+			size: size.max(1),
+			
+			window: VecDeque::new(),
+			thresholds: Vec::new(),
+			
+			command: None,
+			webhook: None,
+			
+			queue: None,
+			worker: None,
+		};
+	}
+	
+	/// Alerts when at least `percent` of the probes in the window were lost.
+	pub fn loss(mut self, percent: Option<f64>) -> Self {
+		if let Some(limit) = percent {
+			self.thresholds.push(Threshold { metric: Metric::Loss, limit, firing: false });
+		}
+		return self;
+	}
+	
+	/// Alerts when the average rtt of the replies in the window reaches `rtt`.
+	pub fn rtt(mut self, rtt: Option<Duration>) -> Self {
+		if let Some(rtt) = rtt {
+			self.thresholds.push(Threshold { metric: Metric::Rtt, limit: rtt.as_secs_f64() * 1000.0, firing: false });
+		}
+		return self;
+	}
+	
+	/// Shell command to run on every transition, with the details in `SONAR_*` environment variables.
+	pub fn command(mut self, command: Option<String>) -> Self {
+		self.command = command;
+		return self;
+	}
+	
+	/// URL to POST every transition to as json.
+	pub fn webhook(mut self, url: Option<String>) -> Self {
+		self.webhook = url;
+		return self;
+	}
+	
+	/// Whether any metric is past its threshold right now.
+	pub fn firing(&self) -> bool {
+		return self.thresholds.iter().any(|t| t.firing);
+	}
+	
+	/// Waits a while for hooks still running, so the last transition is not lost on exit.
+	pub fn finish(mut self) {
+		self.queue.take();
+		let Some(worker) = self.worker.take() else {
+			return;
+		};
+		
+		let started = Instant::now();
+		while !worker.is_finished() {
+			if started.elapsed() >= FINISH_TIMEOUT {
+				warn!("alert hooks still running, not waiting for them");
+				return;
+			}
+			std::thread::sleep(Duration::from_millis(50));
+		}
+		let _ = worker.join();
+	}
+	
+	/// Feeds a probe outcome in and returns the transitions it caused, after
+	/// starting their hooks.
+	pub fn outcome(&mut self, outcome: &ProbeOutcome) -> Vec<Transition> {
+		let finished = match outcome {
+			ProbeOutcome::Reply(reply) => Some(reply.elapsed),
+			ProbeOutcome::Timeout { .. } | ProbeOutcome::SendFailed { .. } => None,
+			_ => return Vec::new(),
+		};
+		
+		if self.window.len() == self.size {
+			self.window.pop_front();
+		}
+		self.window.push_back(finished);
+		if self.window.len() < self.size {
+			return Vec::new();
+		}
+		
+		let lost = self.window.iter().filter(|p| p.is_none()).count();
+		let rtts: Vec<Duration> = self.window.iter().flatten().copied().collect();
+		let loss = lost as f64 / self.window.len() as f64 * 100.0;
+		let rtt = if rtts.is_empty() {
+			None
+		} else {
+			Some(rtts.iter().sum::<Duration>().as_secs_f64() * 1000.0 / rtts.len() as f64)
+		};
+		
+		let mut transitions = Vec::new();
+		for threshold in self.thresholds.iter_mut() {
+			// without replies there is no rtt to judge, loss covers that case
+			let Some(value) = (match threshold.metric { Metric::Loss => Some(loss), Metric::Rtt => rtt }) else {
+				continue;
+			};
+			
+			let firing = if threshold.firing { value >= threshold.limit * RECOVER_RATIO } else { value >= threshold.limit };
+			if firing != threshold.firing {
+				threshold.firing = firing;
+				transitions.push(Transition { metric: threshold.metric, firing, value, threshold: threshold.limit, window: self.size });
+			}
+		}
+		
+		for transition in &transitions {
+			self.notify(transition.clone());
+		}
+		return transitions;
+	}
+	
+	/// Queues the transition for the hooks, a slow hook must not hold up probing.
+	fn notify(&mut self, transition: Transition) {
+		if self.command.is_none() && self.webhook.is_none() {
+			return;
+		}
+		
+		if self.queue.is_none() {
+			let (queue, transitions) = mpsc::channel::<Transition>();
+			let (target, command, webhook) = (self.target.clone(), self.command.clone(), self.webhook.clone());
+			self.worker = Some(std::thread::spawn(move || {
+				for transition in transitions {
+					if let Some(command) = &command {
+						run_command(command, &target, &transition);
+					}
+					if let Some(url) = &webhook {
+						send_webhook(url, &target, &transition);
+					}
+				}
+			}));
+			self.queue = Some(queue);
+		}
+		
+		if let Some(queue) = &self.queue {
+			let _ = queue.send(transition);
+		}
+	}
+}
+
+fn state(transition: &Transition) -> &'static str {
+	return if transition.firing { "alert" } else { "recovered" };
+}
+
+/// Runs `command` with the transition in `SONAR_*` environment variables and
+/// waits for it. Its output is discarded, it would end up in the middle of
+/// the reply lines or the dashboard.
+fn run_command(command: &str, target: &str, transition: &Transition) {
+	let status = Command::new("sh")
+		.arg("-c")
+		.arg(command)
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.env("SONAR_TARGET", target)
+		.env("SONAR_METRIC", transition.metric.name())
+		.env("SONAR_STATE", state(transition))
+		.env("SONAR_VALUE", format!("{:.3}", transition.value))
+		.env("SONAR_THRESHOLD", format!("{:.3}", transition.threshold))
+		.env("SONAR_WINDOW", transition.window.to_string())
+		.status();
+	
+	match status {
+		Ok(s) if !s.success() => warn!(status = s.to_string(), command, "alert command failed"),
+		Ok(_) => {}
+		Err(e) => error!(desc = e.to_string(), command, "could not run alert command"),
+	}
+}
+
+/// POSTs the transition to `url` as json.
+fn send_webhook(url: &str, target: &str, transition: &Transition) {
+	let body = serde_json::json!({
+		"target": target,
+		"metric": transition.metric.name(),
+		"state": state(transition),
+		"value": transition.value,
+		"threshold": transition.threshold,
+		"window": transition.window,
+	});
+	
+	let client = match reqwest::blocking::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+		Ok(c) => c,
+		Err(e) => {
+			warn!(desc = e.to_string(), "could not build client");
+			return;
+		}
+	};
+	
+	match client.post(url).header("content-type", "application/json").body(body.to_string()).send() {
+		Ok(r) if !r.status().is_success() => warn!(status = r.status().as_u16(), url, "webhook refused the alert"),
+		Ok(_) => {}
+		Err(e) => warn!(desc = e.to_string(), url, "could not send alert webhook"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use icmp::PingReply;
+	
+	use std::net::Ipv4Addr;
+	
+	fn reply(ms: u64) -> ProbeOutcome {
+		return ProbeOutcome::Reply(PingReply {
+			elapsed: Duration::from_millis(ms),
+			sequence: 0,
+			from_addr: Ipv4Addr::LOCALHOST,
+			dest_addr: Ipv4Addr::LOCALHOST,
+			local_addr: Ipv4Addr::LOCALHOST,
+			sent_tos: 0,
+			tos: 0,
+			ttl: 64,
+			tot_len: 84,
+			id: 0,
+			bytes: 64,
+			timestamps: None,
+			ip_options: Vec::new(),
+		});
+	}
+	
+	fn lost() -> ProbeOutcome {
+		return ProbeOutcome::Timeout { dest: Ipv4Addr::LOCALHOST.into(), sequence: 0 };
+	}
+	
+	/// Feeds the outcomes in and returns every transition as (firing, value).
+	fn feed(alerts: &mut Alerts, outcomes: &[ProbeOutcome]) -> Vec<(bool, f64)> {
+		return outcomes.iter().flat_map(|o| alerts.outcome(o)).map(|t| (t.firing, t.value)).collect();
+	}
+	
+	#[test]
+	fn waits_for_a_full_window() {
+		let mut alerts = Alerts::new("test".to_string(), 4).loss(Some(50.0));
+		assert!(feed(&mut alerts, &[lost(), lost(), lost()]).is_empty());
+		assert!(!alerts.firing());
+		
+		let transitions = alerts.outcome(&lost());
+		assert_eq!(transitions.len(), 1);
+		assert_eq!((transitions[0].metric, transitions[0].firing, transitions[0].value), (Metric::Loss, true, 100.0));
+		assert_eq!((transitions[0].threshold, transitions[0].window), (50.0, 4));
+		assert!(alerts.firing());
+	}
+	
+	#[test]
+	fn fires_once() {
+		let mut alerts = Alerts::new("test".to_string(), 2).loss(Some(50.0));
+		assert_eq!(feed(&mut alerts, &[reply(1), lost()]), vec![(true, 50.0)]);
+		
+		// still past the threshold, nothing new to report
+		assert!(feed(&mut alerts, &[lost(), lost(), lost()]).is_empty());
+		assert!(alerts.firing());
+	}
+	
+	#[test]
+	fn loss_recovers_below_the_ratio() {
+		let mut alerts = Alerts::new("test".to_string(), 10).loss(Some(50.0));
+		let mut outcomes: Vec<ProbeOutcome> = (0..5).map(|_| lost()).collect();
+		outcomes.extend((0..5).map(|_| reply(1)));
+		assert_eq!(feed(&mut alerts, &outcomes), vec![(true, 50.0)]);
+		
+		// 40% is below the threshold but not below 80% of it
+		assert!(feed(&mut alerts, &[reply(1)]).is_empty());
+		assert_eq!(feed(&mut alerts, &[reply(1)]), vec![(false, 30.0)]);
+		assert!(!alerts.firing());
+		
+		// and firing again takes the full threshold, the first losses only replace older ones
+		assert!(feed(&mut alerts, &[lost(), lost(), lost(), lost()]).is_empty());
+		assert_eq!(feed(&mut alerts, &[lost()]), vec![(true, 50.0)]);
+	}
+	
+	#[test]
+	fn rtt_hysteresis() {
+		let mut alerts = Alerts::new("test".to_string(), 1).rtt(Some(Duration::from_millis(100)));
+		assert!(feed(&mut alerts, &[reply(99)]).is_empty());
+		assert_eq!(feed(&mut alerts, &[reply(100)]), vec![(true, 100.0)]);
+		assert!(feed(&mut alerts, &[reply(90), reply(80)]).is_empty());
+		assert_eq!(feed(&mut alerts, &[reply(79)]), vec![(false, 79.0)]);
+		assert!(feed(&mut alerts, &[reply(99)]).is_empty());
+		assert_eq!(feed(&mut alerts, &[reply(150)]), vec![(true, 150.0)]);
+	}
+	
+	#[test]
+	fn metrics_are_independent() {
+		let mut alerts = Alerts::new("test".to_string(), 2).loss(Some(50.0)).rtt(Some(Duration::from_millis(100)));
+		
+		// no replies leaves rtt alone rather than judging it
+		let transitions = [lost(), lost()].iter().flat_map(|o| alerts.outcome(o)).collect::<Vec<_>>();
+		assert_eq!(transitions.iter().map(|t| (t.metric, t.firing)).collect::<Vec<_>>(), vec![(Metric::Loss, true)]);
+		
+		let transitions = [reply(200), reply(200)].iter().flat_map(|o| alerts.outcome(o)).collect::<Vec<_>>();
+		assert_eq!(transitions.iter().map(|t| (t.metric, t.firing)).collect::<Vec<_>>(), vec![(Metric::Rtt, true), (Metric::Loss, false)]);
+	}
+	
+	#[test]
+	fn only_finished_probes_count() {
+		let mut alerts = Alerts::new("test".to_string(), 2).loss(Some(50.0));
+		let dest = Ipv4Addr::LOCALHOST.into();
+		let ignored = [
+			ProbeOutcome::Sent { dest, sequence: 0, tos: 0 },
+			ProbeOutcome::AddressChanged { old: dest, new: dest },
+		];
+		assert!(feed(&mut alerts, &ignored).is_empty());
+		assert!(feed(&mut alerts, &[ProbeOutcome::SendFailed { dest, error: "unreachable".to_string() }]).is_empty());
+		assert_eq!(feed(&mut alerts, &[reply(1)]), vec![(true, 50.0)]);
+	}
+}
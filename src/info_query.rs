@@ -5,9 +5,17 @@ use colored::Colorize;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use serde_json::Value;
 use std::time::Duration;
-use std::sync::mpsc::Receiver;
+use util::cancel::CancelToken;
 
-pub fn whois<S: Into<String> + Clone, T>(addr: S, rx: &Receiver<T>) -> Result<(), ()> {
+/// What `lookup` found out about a host.
+#[derive(Clone)]
+pub struct Info {
+	pub netname: String,
+	pub organization: String,
+	pub location: String,
+}
+
+pub fn whois<S: Into<String> + Clone>(addr: S, cancel: &CancelToken) -> Result<(), ()> {
 	let spinner_style = ProgressStyle::with_template("{prefix:.bold.dim} {spinner:.cyan} {wide_msg}")
 		.unwrap()
 		.tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
@@ -18,8 +26,19 @@ pub fn whois<S: Into<String> + Clone, T>(addr: S, rx: &Receiver<T>) -> Result<()
 	pb.set_message("Getting whois");
 	pb.enable_steady_tick(Duration::from_millis(150));
 	
+	if let Some(info) = lookup(addr, cancel, &pb)? {
+		pb.finish_and_clear();
+		println!("NetName: {}\nOrganization: {}\nLocation: {}", info.netname, info.organization, info.location);
+	}
+	
+	return Ok(());
+}
+
+/// Queries whois and the geolocation of `addr`, reporting progress on `pb`.
+/// `None` when the whois server had no answer.
+pub fn lookup<S: Into<String> + Clone>(addr: S, cancel: &CancelToken, pb: &ProgressBar) -> Result<Option<Info>, ()> {
 	trace!("WhoIs::new");
-	let mut whois = match WhoIs::new(addr.clone()) {
+	let mut whois = match WhoIs::new(addr.clone(), cancel) {
 		Ok(p) => p,
 		Err(e) => {
 			pb.finish_with_message("Whois failed".truecolor(255, 0, 0).to_string());
@@ -29,12 +48,12 @@ pub fn whois<S: Into<String> + Clone, T>(addr: S, rx: &Receiver<T>) -> Result<()
 	};
 	
 	trace!("WhoIs::get_whois");
-	let whois_response = match whois.get_whois(rx) {
+	let whois_response = match whois.get_whois(cancel) {
 		Ok(r) => r,
 		Err(e) => {
 			pb.finish_with_message("Whois failed".truecolor(255, 0, 0).to_string());
 			warn!(desc = e.to_string());
-			return Ok(());
+			return Ok(None);
 		}
 	};
 	
@@ -48,7 +67,7 @@ pub fn whois<S: Into<String> + Clone, T>(addr: S, rx: &Receiver<T>) -> Result<()
 		}
 	};
 	
-	let ip = match util::dns_lookup(addr.into() + ":0") {
+	let ip = match util::dns_lookup(addr.into() + ":0", cancel) {
 		Ok(i) => i,
 		Err(e) => {
 			warn!(desc = e.to_string(), "dns lookup failed");
@@ -80,14 +99,13 @@ pub fn whois<S: Into<String> + Clone, T>(addr: S, rx: &Receiver<T>) -> Result<()
 		}
 	};
 	
-	pb.finish_and_clear();
-	println!("NetName: {}\nOrganization: {}\nLocation: {}, {}, {}",
-		whois_response.netname.unwrap_or("Unknown".to_string()),
-		json["as"].as_str().unwrap_or("Unknown"),
-		json["country_name"].as_str().unwrap_or("Unknown"),
-		json["region_name"].as_str().unwrap_or("Unknown"),
-		json["city_name"].as_str().unwrap_or("Unknown")
-	);
-	
-	return Ok(());
+	return Ok(Some(Info {
+		netname: whois_response.netname.unwrap_or("Unknown".to_string()),
+		organization: json["as"].as_str().unwrap_or("Unknown").to_string(),
+		location: format!("{}, {}, {}",
+			json["country_name"].as_str().unwrap_or("Unknown"),
+			json["region_name"].as_str().unwrap_or("Unknown"),
+			json["city_name"].as_str().unwrap_or("Unknown")
+		),
+	}));
 }
\ No newline at end of file
@@ -1,115 +1,435 @@
 use logger::prelude::*;
 use icmp::prelude::*;
 
-use std::sync::mpsc::{
-	channel,
-	Receiver,
-	Sender
+use std::{
+	sync::{
+		Arc,
+		Mutex
+	},
+	time::Duration
 };
-use clap::Parser;
-use tracing_subscriber::prelude::*;
-use colored::Colorize;
+use util::cancel::CancelToken;
+use clap::{Parser, Subcommand};
+use tracing_subscriber::{fmt::writer::MakeWriterExt, prelude::*};
+use std::path::{Path, PathBuf};
+use report::{Grading, Report};
 
 #[derive(Parser, Debug)]
 #[command(name = "sonar", version = version::version)]
 #[command(about = "Modern ping", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(group(clap::ArgGroup::new("alert_thresholds").args(["alert_loss", "alert_rtt"]).multiple(true)))]
 struct Args {
-	#[arg(short, long, help = "increase verbosity (-v: warnings, -vv: info, -vvv: debug, -vvvv: trace)", action = clap::ArgAction::Count)]
+	#[command(subcommand)]
+	command: Option<Command>,
+	
+	#[arg(short, long, global = true, help = "increase verbosity (-v: warnings, -vv: info, -vvv: debug, -vvvv: trace)", action = clap::ArgAction::Count)]
 	verbose: u8,
 	
 	#[arg(required = true, help = "ip address to ping")]
-	ip: String,
+	ip: Option<String>,
 	
 	#[arg(short, long, help = "amount to attempt pinging")]
 	count: Option<u64>,
 	
-	#[arg(short, long, help = "seconds to wait between sending packets", default_value_t = 1.0)]
-	interval: f32,
+	#[arg(short, long, help = "seconds to wait between sending packets [default: 1, 0.01 when flooding]")]
+	interval: Option<f32>,
+	
+	#[arg(short = 'A', long, help = "adaptive mode, send the next packet as soon as a reply arrives", default_value_t = false, conflicts_with = "flood")]
+	adaptive: bool,
+	
+	#[arg(short, long, help = "flood mode, print a dot per packet sent and erase it on reply (root only)", default_value_t = false)]
+	flood: bool,
+	
+	#[arg(short = 'l', long, value_name = "COUNT", help = "send COUNT packets at once before settling into the interval, in a single syscall where possible", default_value_t = 0)]
+	preload: u64,
 	
 	#[arg(short = 'x', long, help = "enable querying for extra information (uses IP2Location)", default_value_t = false)]
 	extra: bool,
+	
+	#[arg(short = 'n', long, global = true, help = "numeric output only, do not resolve ptr names of reply sources", default_value_t = false)]
+	numeric: bool,
+	
+	#[arg(long, help = "dns server to query instead of the ones in /etc/resolv.conf (ip or ip:port)")]
+	resolver: Option<String>,
+	
+	#[arg(long, value_name = "SECS", help = "resolve the hostname again every SECS seconds and follow address changes")]
+	reresolve: Option<f32>,
+	
+	#[arg(short = 'I', long, value_name = "ADDR|IFACE", help = "send from this source address, or bind to this interface")]
+	interface: Option<String>,
+	
+	#[arg(long, value_name = "MARK", help = "set SO_MARK on outgoing packets, for policy routing (needs CAP_NET_ADMIN)")]
+	mark: Option<u32>,
+	
+	#[arg(short = 'Q', long, value_name = "TOS", value_delimiter = ',', value_parser = parse_tos, help = "set the tos byte on outgoing packets (number, or dscp name like ef, af41, cs1); a comma separated list cycles through the classes")]
+	tos: Vec<u8>,
+	
+	#[arg(long, help = "send icmp timestamp requests instead of echo, and report one-way delays and the remote clock offset", default_value_t = false)]
+	timestamp: bool,
+	
+	#[arg(short = 'R', long = "record-route", help = "set the record route ip option and show the route of every reply", default_value_t = false)]
+	record_route: bool,
+	
+	#[arg(short = 'T', long = "ip-timestamp", value_name = "tsonly|tsandaddr", value_parser = ["tsonly", "tsandaddr"], conflicts_with = "record_route", help = "set the timestamp ip option and show the recorded timestamps of every reply")]
+	ip_timestamp: Option<String>,
+	
+	#[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u16).range(8..), conflicts_with_all = ["record_route", "ip_timestamp"], help = "build the ip header ourselves (IP_HDRINCL) and split every packet into fragments of at most BYTES bytes")]
+	fragment: Option<u16>,
+	
+	#[arg(long, value_name = "FILE", help = "write every probe sent and every packet received to a pcap file, for wireshark or tcpdump -r")]
+	pcap: Option<std::path::PathBuf>,
+	
+	#[arg(short = 'w', long, value_name = "SECS", help = "stop after SECS seconds, regardless of how many packets were sent")]
+	deadline: Option<f32>,
+	
+	#[arg(long, global = true, value_name = "WHEN", value_parser = ["auto", "always", "never"], default_value = "auto", help = "color the output; auto colors when stdout is a terminal and NO_COLOR is not set")]
+	color: String,
+	
+	#[arg(long, global = true, value_name = "MS,MS", value_delimiter = ',', help = "color replies green below the first rtt, yellow below the second and red above, instead of by deviation from the running median")]
+	color_thresholds: Vec<f32>,
+	
+	#[arg(long, global = true, help = "print an rtt histogram and the p50/p90/p99/p99.9 percentiles with the statistics", default_value_t = false)]
+	histogram: bool,
+	
+	#[arg(long, global = true, value_name = "PATH", help = "write the final statistics, jitter and MOS included, to PATH as json")]
+	json: Option<PathBuf>,
+	
+	#[arg(long, value_name = "PERCENT", value_parser = parse_percent, help = "alert when at least PERCENT of the probes in the alert window were lost (e.g. 5%)")]
+	alert_loss: Option<f64>,
+	
+	#[arg(long, value_name = "RTT", value_parser = parse_millis, help = "alert when the average rtt over the alert window reaches RTT (e.g. 200ms, 0.2s; plain numbers are ms)")]
+	alert_rtt: Option<Duration>,
+	
+	#[arg(long, value_name = "PROBES", default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..), help = "how many of the latest probes the alerts are evaluated over")]
+	alert_window: u64,
+	
+	#[arg(long, value_name = "CMD", requires = "alert_thresholds", help = "shell command to run when an alert fires or recovers, details are in SONAR_TARGET, SONAR_METRIC, SONAR_STATE, SONAR_VALUE, SONAR_THRESHOLD and SONAR_WINDOW")]
+	on_alert: Option<String>,
+	
+	#[arg(long, value_name = "URL", requires = "alert_thresholds", help = "POST every alert and recovery to URL as json")]
+	alert_webhook: Option<String>,
+	
+	#[arg(long, help = "show a full screen dashboard with an rtt graph and rolling statistics instead of a line per reply", default_value_t = false)]
+	tui: bool,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Replay a pcap capture, printing the lines and statistics a live run would have
+	Replay {
+		#[arg(help = "capture to read, written by --pcap, tcpdump or wireshark")]
+		file: PathBuf,
+		
+		#[arg(short = 'W', long, value_name = "SECS", default_value_t = 2.0, help = "seconds after which a request counts as lost")]
+		timeout: f32,
+	},
+}
+
+mod alert;
 mod info_query;
+mod report;
+mod tui;
+
+/// Parses a tos byte given as a number (decimal or 0x hex) or a dscp class name.
+fn parse_tos(s: &str) -> Result<u8, String> {
+	let s = s.trim().to_lowercase();
+	if let Some(hex) = s.strip_prefix("0x") {
+		return u8::from_str_radix(hex, 16).map_err(|e| e.to_string());
+	}
+	if let Ok(tos) = s.parse::<u8>() {
+		return Ok(tos);
+	}
+	
+	let dscp = match s.as_str() {
+		"be" | "df" => 0,
+		"ef" => 46,
+		"va" => 44,
+		_ => {
+			if let Some(cs) = s.strip_prefix("cs") && let Ok(n @ 0..=7) = cs.parse::<u8>() {
+				n << 3
+			} else if let Some(af) = s.strip_prefix("af") && let [class @ b'1'..=b'4', drop @ b'1'..=b'3'] = af.as_bytes() {
+				((class - b'0') << 3) | ((drop - b'0') << 1)
+			} else {
+				return Err(format!("unknown tos or dscp class: {}", s));
+			}
+		}
+	};
+	
+	return Ok(dscp << 2);
+}
+
+/// Parses a percentage, with or without the percent sign.
+fn parse_percent(s: &str) -> Result<f64, String> {
+	let percent = s.trim().trim_end_matches('%').parse::<f64>().map_err(|e| e.to_string())?;
+	// at 0 nothing could ever recover, every window has at least 0% loss
+	if !(percent > 0.0 && percent <= 100.0) {
+		return Err("percentage must be above 0 and at most 100".to_string());
+	}
+	return Ok(percent);
+}
+
+/// Parses a duration given in ms or s, a plain number is taken as ms.
+fn parse_millis(s: &str) -> Result<Duration, String> {
+	let s = s.trim().to_lowercase();
+	let (number, scale) = if let Some(ms) = s.strip_suffix("ms") {
+		(ms, 0.001)
+	} else if let Some(secs) = s.strip_suffix('s') {
+		(secs, 1.0)
+	} else {
+		(s.as_str(), 0.001)
+	};
+	
+	let value = number.trim().parse::<f64>().map_err(|e| e.to_string())?;
+	let duration = Duration::try_from_secs_f64(value * scale).map_err(|e| e.to_string())?;
+	if duration.is_zero() {
+		return Err("duration must be above 0".to_string());
+	}
+	return Ok(duration);
+}
 
 fn main() -> std::process::ExitCode {
 	let args = Args::parse();
 	
+	// colored already honours NO_COLOR, CLICOLOR_FORCE and whether stdout is a terminal
+	match args.color.as_str() {
+		"always" => colored::control::set_override(true),
+		"never" => colored::control::set_override(false),
+		_ => {}
+	}
+	let ansi = colored::control::SHOULD_COLORIZE.should_colorize();
+	
 	let layer = tracing_indicatif::IndicatifLayer::new();
 	logger::register(Level::from(args.verbose))
-		.with_writer(layer.get_stdout_writer())
+		.with_ansi(ansi)
+		.with_writer(layer.get_stdout_writer().with_filter(|_| !tui::is_active()))
 		.finish()
 		.with(layer)
 		.init();
 	
-	let (tx, rx): (Sender<()>, Receiver<()>) = channel();
-	if let Err(e) = ctrlc::set_handler(move || {
-		println!();
+	let grading = match args.color_thresholds.as_slice() {
+		[] => Grading::Median,
+		[green, yellow] if let (Ok(green), Ok(yellow)) = (Duration::try_from_secs_f32(green / 1000.0), Duration::try_from_secs_f32(yellow / 1000.0)) && green <= yellow => {
+			Grading::Thresholds(green, yellow)
+		}
+		_ => {
+			error!("color thresholds must be two increasing rtts in ms");
+			return 1.into();
+		}
+	};
+	
+	let cancel = match CancelToken::new() {
+		Ok(c) => c,
+		Err(e) => {
+			error!(desc = e, "could not create cancel token");
+			return 1.into();
+		}
+	};
+	
+	if let Some(Command::Replay { file, timeout }) = &args.command {
+		return replay(file, *timeout, args.numeric, grading, args.histogram, args.json.as_deref(), cancel);
+	}
+	
+	// clap makes sure the ip is there when no subcommand is given
+	let Some(ip) = args.ip.clone() else {
+		return 1.into();
+	};
+	
+	if let Some(server) = &args.resolver {
+		let server = match server.parse::<std::net::SocketAddr>() {
+			Ok(s) => s,
+			Err(_) => match server.parse::<std::net::IpAddr>() {
+				Ok(ip) => std::net::SocketAddr::new(ip, 53),
+				Err(e) => {
+					error!(desc = e.to_string(), server, "invalid resolver address");
+					return 1.into();
+				}
+			}
+		};
 		
-		if let Err(e) = tx.send(()) {
-			error!(desc = e.to_string(), "could not send signal on channel");
+		let mut config = util::dns::ResolverConfig::from_system();
+		config.nameservers = vec![server];
+		if let Err(e) = util::dns::init(config) {
+			error!(desc = e, "could not set resolver");
+			return 1.into();
 		}
+	}
+	
+	let handler_cancel = cancel.clone();
+	if let Err(e) = ctrlc::set_handler(move || {
+		println!();
+		handler_cancel.cancel();
 	}) {
 		error!(desc = e.to_string(), "could not set ctrlc handler");
 	}
 	
-	if args.extra && info_query::whois(&args.ip, &rx).is_err() {
-		error!("querying for extra info failed");
-	}
+	// the dashboard shows the lookup in a panel once it is done, instead of waiting for it
+	let lookup = if args.extra && args.tui {
+		let lookup = Arc::new(Mutex::new(tui::Lookup::Pending));
+		let (result, ip, cancel) = (lookup.clone(), ip.clone(), cancel.clone());
+		std::thread::spawn(move || {
+			let found = match info_query::lookup(ip, &cancel, &indicatif::ProgressBar::hidden()) {
+				Ok(info) => tui::Lookup::Done(info),
+				Err(_) => tui::Lookup::Failed,
+			};
+			*result.lock().unwrap_or_else(|e| e.into_inner()) = found;
+		});
+		Some(lookup)
+	} else {
+		if args.extra && info_query::whois(&ip, &cancel).is_err() {
+			error!("querying for extra info failed");
+		}
+		None
+	};
+	
+	let (source, interface) = match &args.interface {
+		Some(i) => match i.parse::<std::net::IpAddr>() {
+			Ok(addr) => (Some(addr), None),
+			Err(_) => (None, Some(i.clone())),
+		},
+		None => (None, None),
+	};
 	
-	trace!("Pinger::new");
-	let mut pinger = match Pinger::new(&args.ip) {
+	trace!("PingerBuilder::build");
+	let pinger = match Pinger::builder(&ip).source(source).interface(interface).mark(args.mark).tos(args.tos.first().copied())
+		.kind(if args.timestamp { ProbeKind::Timestamp } else { ProbeKind::Echo })
+		.record(match (args.record_route, args.ip_timestamp.as_deref()) {
+			(true, _) => Some(RecordOption::Route),
+			(_, Some("tsonly")) => Some(RecordOption::Timestamp),
+			(_, Some(_)) => Some(RecordOption::TimestampAndAddress),
+			_ => None,
+		})
+		.raw_header(args.fragment.map(|size| RawHeader { fragment_size: Some(size as usize), ..Default::default() }))
+		.pcap(args.pcap.clone())
+		.cancel_token(cancel.clone())
+		.build()
+	{
 		Ok(p) => p,
 		Err(e) => {
-			error!(desc = e.to_string());
+			error!(desc = e.to_string(), "could not create pinger");
 			return 1.into()
 		}
 	};
 	
-	trace!("Pinger::init_socket");
-	if let Err(e) = pinger.init_socket() {
-		error!(desc = e.to_string(), "could not init socket");
-		return 1.into();
+	let mode = if args.flood {
+		if unsafe { libc::geteuid() } != 0 {
+			error!("flood mode is only available to root");
+			return 1.into();
+		}
+		Mode::Flood
+	} else if args.adaptive {
+		Mode::Adaptive
+	} else {
+		Mode::Fixed
+	};
+	
+	let rdns = if args.numeric { None } else { Some(util::rdns::ReverseResolver::new(cancel.clone())) };
+	if let Some(r) = &rdns {
+		// queue the target right away, its name is usually cached before the first reply
+		r.lookup(pinger.get_addr());
 	}
 	
-	let ping = |pinger: &mut Pinger, wait_time: f32| -> bool {
-		std::thread::sleep(std::time::Duration::from_secs_f32(wait_time));
-		
-		if rx.try_recv().is_ok() {
-			return false;
-		}
-		
-		trace!("Pinger::ping");
-		let reply = match pinger.ping(&rx) {
-			Ok(r) => r,
-			Err(e) => {
-				if e == "stop signal" {
-					return false;
-				}
-				// error!(desc = e.to_string(), "ping failed");
-				return true;
-			}
+	if !args.tui {
+		println!("Pinging {} ({}){}{}",
+			ip,
+			pinger.get_dest(),
+			match &args.interface { Some(i) => format!(" from {}", i), None => "".to_string() },
+			if let Some(c) = args.count { format!(" {} times", c) } else { "".to_string() },
+		);
+	}
+	let mut session = PingSession::new(pinger)
+		.mode(mode)
+		.interval(Duration::from_secs_f32(args.interval.unwrap_or(if args.flood { 0.01 } else { 1.0 })))
+		.count(args.count)
+		.deadline(args.deadline.map(Duration::from_secs_f32))
+		.reresolve(args.reresolve.map(Duration::from_secs_f32))
+		.tos_classes(args.tos.clone())
+		.preload(args.preload)
+		.cancel_token(cancel);
+	
+	let mut alerts = alert::Alerts::new(ip.clone(), args.alert_window as usize)
+		.loss(args.alert_loss)
+		.rtt(args.alert_rtt)
+		.command(args.on_alert.clone())
+		.webhook(args.alert_webhook.clone());
+	
+	if args.tui {
+		let alerts = match tui::Dashboard::new(ip.clone(), lookup).alerts(alerts).run(&mut session) {
+			Ok(a) => a,
+			Err(_) => return 1.into(),
 		};
+		// per class and per reply details are not collected by the dashboard, only the totals are printed
+		Report::new(ip.clone(), false, None, &[]).summary(session.statistics());
+		if let Some(alerts) = alerts {
+			alerts.finish();
+		}
+		if let Some(path) = &args.json && report::write_json(path, &ip, session.statistics()).is_err() {
+			return 1.into();
+		}
+		return 0.into();
+	}
+	
+	let mut report = Report::new(ip.clone(), mode == Mode::Flood, rdns, &args.tos).grading(grading).histogram(args.histogram);
+	for outcome in session.by_ref() {
+		for transition in alerts.outcome(&outcome) {
+			report.alert(&transition);
+		}
+		report.outcome(outcome);
+	}
+	report.summary(session.statistics());
+	alerts.finish();
+	
+	if let Some(path) = &args.json && report::write_json(path, &ip, session.statistics()).is_err() {
+		return 1.into();
+	}
+	return 0.into();
+}
 
-		println!("{}", format!("[{}] | {} -> {} | {:.2} ms",
-			reply.sequence,
-			reply.from_addr,
-			reply.dest_addr,
-			(reply.elapsed.as_secs_f32() * 100000.0).round() / 100.0,
-		).truecolor(0, 255, 0));
-		return true;
+/// Runs a capture through the decoders and prints what a live run would have.
+fn replay(file: &Path, timeout: f32, numeric: bool, grading: Grading, histogram: bool, json: Option<&Path>, cancel: CancelToken) -> std::process::ExitCode {
+	let timeout = match Duration::try_from_secs_f32(timeout) {
+		Ok(t) => t,
+		Err(e) => {
+			error!(desc = e.to_string(), "invalid timeout");
+			return 1.into();
+		}
 	};
-
-	println!("Pinging {} ({}){}", args.ip, pinger.get_dest(), if let Some(c) = args.count { format!(" {} times", c) } else { "".to_string() });
-	if let Some(count) = args.count {
-		for _ in 0..count {
-			if !ping(&mut pinger, args.interval) { break; }
+	
+	let open = || match icmp::pcap::PcapReader::open(file) {
+		Ok(r) => Ok(icmp::replay::Replay::new(r).timeout(timeout)),
+		Err(e) => {
+			error!(desc = e, "could not read capture");
+			Err(std::process::ExitCode::from(1))
 		}
-	} else {
-		loop {
-			if !ping(&mut pinger, args.interval) { break; }
+	};
+	
+	// a first pass finds the tos classes, so a capture of a -Q run is reported like the run itself
+	let mut classes: Vec<u8> = Vec::new();
+	for outcome in match open() { Ok(r) => r, Err(code) => return code } {
+		if let ProbeOutcome::Sent { tos, .. } = outcome && !classes.contains(&tos) {
+			classes.push(tos);
 		}
 	}
+	if classes == [0] {
+		classes.clear();
+	}
 	
+	let mut replay = match open() {
+		Ok(r) => r,
+		Err(code) => return code,
+	};
+	
+	let rdns = if numeric { None } else { Some(util::rdns::ReverseResolver::new(cancel)) };
+	
+	println!("Replaying {}", file.display());
+	let mut report = Report::new(file.display().to_string(), false, rdns, &classes).grading(grading).histogram(histogram);
+	for outcome in replay.by_ref() {
+		report.outcome(outcome);
+	}
+	report.summary(replay.statistics());
+	
+	if let Some(path) = json && report::write_json(path, &file.display().to_string(), replay.statistics()).is_err() {
+		return 1.into();
+	}
 	return 0.into();
 }
@@ -0,0 +1,412 @@
+use icmp::{prelude::*, sketch::QuantileSketch, PingReply};
+use logger::prelude::*;
+
+use std::{
+	collections::VecDeque,
+	io::Write,
+	net::{
+		IpAddr,
+		Ipv4Addr
+	},
+	path::Path,
+	time::Duration
+};
+use colored::Colorize;
+use util::rdns::ReverseResolver;
+use crate::alert::Transition;
+
+/// How many of the latest replies the running median is taken over.
+const MEDIAN_WINDOW: usize = 100;
+
+const HISTOGRAM_BINS: usize = 10;
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// How reply lines are colored by their rtt.
+pub enum Grading {
+	/// Green up to the running median plus half of it (at least 1 ms more),
+	/// yellow up to twice that, red beyond.
+	Median,
+	/// Green below the first, yellow below the second, red from there on.
+	Thresholds(Duration, Duration),
+}
+
+/// Prints every probe outcome as it happens and the summary at the end. Live
+/// runs and replays of a capture go through the same report, so both print
+/// the same lines.
+pub struct Report {
+	target: String,
+	flood: bool,
+	rdns: Option<ReverseResolver>,
+	
+	// last reply ttl seen from each address, a change hints at a route change
+	ttls: Vec<(Ipv4Addr, u8, u8, &'static str)>,
+	
+	// the last recorded route, only changes are printed in full
+	last_route: Option<Vec<Ipv4Addr>>,
+	
+	// sum and count of the remote clock offsets seen per address
+	offsets: Vec<(Ipv4Addr, f64, u64)>,
+	
+	// per tos class statistics and how many replies came back with a different tos
+	classes: Vec<(u8, Statistics, u64)>,
+	
+	grading: Grading,
+	recent: VecDeque<Duration>,
+	
+	// rtts of all addresses, only kept when the histogram is wanted
+	rtts: Option<QuantileSketch>,
+}
+
+impl Report {
+	/// `tos_classes` get their own statistics, and every reply line shows its tos when there are any.
+	pub fn new(target: String, flood: bool, rdns: Option<ReverseResolver>, tos_classes: &[u8]) -> Self {
+		let mut classes: Vec<(u8, Statistics, u64)> = Vec::new();
+		for tos in tos_classes {
+			if !classes.iter().any(|(t, _, _)| t == tos) {
+				classes.push((*tos, Statistics::new(), 0));
+			}
+		}
+		
+		return Self {
+			target,
+			flood,
+			rdns,
+			ttls: Vec::new(),
+			last_route: None,
+			offsets: Vec::new(),
+			classes,
+			
+			grading: Grading::Median,
+			recent: VecDeque::new(),
+			
+			rtts: None,
+		};
+	}
+	
+	pub fn grading(mut self, grading: Grading) -> Self {
+		self.grading = grading;
+		return self;
+	}
+	
+	/// Prints an rtt histogram and percentiles with the summary.
+	pub fn histogram(mut self, histogram: bool) -> Self {
+		self.rtts = if histogram { Some(QuantileSketch::default()) } else { None };
+		return self;
+	}
+	
+	/// Color of a reply line, judged against the replies before it.
+	fn grade(&self, rtt: Duration) -> (u8, u8, u8) {
+		let (green, yellow) = match self.grading {
+			Grading::Thresholds(green, yellow) => (green, yellow),
+			Grading::Median => {
+				let mut sorted: Vec<Duration> = self.recent.iter().copied().collect();
+				sorted.sort();
+				let Some(median) = sorted.get(sorted.len() / 2) else {
+					return (0, 255, 0);
+				};
+				
+				let slack = (*median / 2).max(Duration::from_millis(1));
+				(*median + slack, *median + slack * 2)
+			}
+		};
+		
+		return if rtt < green {
+			(0, 255, 0)
+		} else if rtt < yellow {
+			(255, 255, 0)
+		} else {
+			(255, 0, 0)
+		};
+	}
+	
+	pub fn outcome(&mut self, outcome: ProbeOutcome) {
+		match outcome {
+			ProbeOutcome::Sent { tos, .. } => {
+				if let Some((_, s, _)) = self.classes.iter_mut().find(|(t, _, _)| *t == tos) {
+					s.add_sent();
+				}
+				
+				if self.flood {
+					print!(".");
+					let _ = std::io::stdout().flush();
+				}
+			}
+			ProbeOutcome::SendFailed { .. } => {}
+			ProbeOutcome::AddressChanged { old, new } => {
+				println!("{}", format!("{} changed address: {} -> {}", self.target, old, new).truecolor(255, 255, 0));
+			}
+			ProbeOutcome::Reply(reply) => self.reply(reply),
+			ProbeOutcome::Late(reply) => {
+				if !self.flood {
+					println!("{}", format!("[{}] | {} -> {} | {:.2} ms | ttl={} bytes={} (late)",
+						reply.sequence,
+						reply.from_addr,
+						reply.local_addr,
+						(reply.elapsed.as_secs_f32() * 100000.0).round() / 100.0,
+						reply.ttl,
+						reply.bytes,
+					).truecolor(128, 128, 128));
+				}
+			}
+			ProbeOutcome::Timeout { .. } => {}
+		}
+	}
+	
+	fn reply(&mut self, reply: PingReply) {
+		let color = self.grade(reply.elapsed);
+		if self.recent.len() == MEDIAN_WINDOW {
+			self.recent.pop_front();
+		}
+		self.recent.push_back(reply.elapsed);
+		if let Some(rtts) = self.rtts.as_mut() {
+			rtts.add(reply.elapsed);
+		}
+		
+		if let Some((_, s, rewritten)) = self.classes.iter_mut().find(|(t, _, _)| *t == reply.sent_tos) {
+			s.add_reply(reply.elapsed);
+			if reply.tos != reply.sent_tos {
+				*rewritten += 1;
+			}
+		}
+		
+		if let Some(ts) = reply.timestamps && ts.is_standard() {
+			match self.offsets.iter_mut().find(|(a, _, _)| *a == reply.from_addr) {
+				Some((_, sum, n)) => {
+					*sum += ts.offset();
+					*n += 1;
+				}
+				None => self.offsets.push((reply.from_addr, ts.offset(), 1)),
+			}
+		}
+		
+		let ttl_change = match self.ttls.iter_mut().find(|(a, _, _, _)| *a == reply.from_addr) {
+			Some((_, ttl, hops, os)) => {
+				let old = (*ttl, *hops);
+				(*ttl, *hops, *os) = (reply.ttl, reply.hops(), reply.os_guess());
+				if old.0 != reply.ttl { Some(old) } else { None }
+			}
+			None => {
+				self.ttls.push((reply.from_addr, reply.ttl, reply.hops(), reply.os_guess()));
+				None
+			}
+		};
+		
+		if self.flood {
+			print!("\x08 \x08");
+			let _ = std::io::stdout().flush();
+			return;
+		}
+		
+		if let Some((old_ttl, old_hops)) = ttl_change {
+			println!("{}", format!("{} ttl changed: {} -> {} (~{} -> ~{} hops), possible route change",
+				reply.from_addr,
+				old_ttl,
+				reply.ttl,
+				old_hops,
+				reply.hops(),
+			).truecolor(255, 255, 0));
+		}
+		
+		println!("{}", format!("[{}] | {} -> {} | {:.2} ms | ttl={} bytes={}{}{}",
+			reply.sequence,
+			self.display(reply.from_addr),
+			reply.local_addr,
+			(reply.elapsed.as_secs_f32() * 100000.0).round() / 100.0,
+			reply.ttl,
+			reply.bytes,
+			match reply.timestamps {
+				Some(ts) if ts.is_standard() => format!(" | out={:+} ms in={:+} ms offset={:+.1} ms", ts.outbound(), ts.inbound(), ts.offset()),
+				Some(_) => " | remote clock is not standard time".to_string(),
+				None => "".to_string(),
+			},
+			if self.classes.is_empty() {
+				"".to_string()
+			} else if reply.tos == reply.sent_tos {
+				format!(" | tos 0x{:02x}", reply.tos)
+			} else {
+				format!(" | tos 0x{:02x} -> 0x{:02x} (rewritten)", reply.sent_tos, reply.tos)
+			},
+		).truecolor(color.0, color.1, color.2));
+		
+		for option in &reply.ip_options {
+			match option {
+				IpOption::RecordRoute(route) => {
+					if self.last_route.as_ref() == Some(route) {
+						println!("RR: same route");
+						continue;
+					}
+					
+					println!("RR:");
+					for addr in route {
+						println!("\t{}", self.display(*addr));
+					}
+					self.last_route = Some(route.clone());
+				}
+				IpOption::Timestamp { entries, overflow } => {
+					println!("TS:");
+					let mut previous: Option<u32> = None;
+					for (addr, ms) in entries {
+						let addr = addr.map_or("".to_string(), |a| format!("{}\t", self.display(a)));
+						match previous {
+							Some(p) => println!("\t{}{:+} ms", addr, *ms as i64 - p as i64),
+							None => println!("\t{}{} ms since midnight utc", addr, ms),
+						}
+						previous = Some(*ms);
+					}
+					if *overflow > 0 {
+						println!("\t{} hops not recorded", overflow);
+					}
+				}
+				IpOption::Other { .. } => {}
+			}
+		}
+	}
+	
+	/// `addr` with its ptr name when one is known, unless names are turned off.
+	fn display(&self, addr: Ipv4Addr) -> String {
+		return match &self.rdns {
+			Some(r) => r.display(addr.into()),
+			None => addr.to_string(),
+		};
+	}
+	
+	pub fn alert(&self, transition: &Transition) {
+		if self.flood {
+			println!();
+		}
+		
+		let line = transition.to_string();
+		if transition.firing {
+			println!("{}", line.truecolor(255, 0, 0));
+		} else {
+			println!("{}", line.truecolor(0, 255, 0));
+		}
+	}
+	
+	pub fn summary(&self, statistics: &[(IpAddr, Statistics)]) {
+		if self.flood {
+			println!();
+		}
+		println!("--- {} statistics ---", self.target);
+		for (addr, s) in statistics {
+			let ms = |d: Option<Duration>| d.map_or(0.0, |d| d.as_secs_f32() * 1000.0);
+			println!("{}: {} transmitted, {} received, {:.1}% loss, rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+				addr,
+				s.transmitted,
+				s.received,
+				s.loss_percent(),
+				ms(s.min()),
+				ms(s.avg()),
+				ms(s.max()),
+			);
+			
+			if s.received > 0 {
+				println!("{}: jitter {:.2} ms, mean deviation {:.2} ms, rtt p50/p90/p99 = {:.2}/{:.2}/{:.2} ms, R-factor {:.1}, MOS {:.2}",
+					addr,
+					ms(s.jitter()),
+					ms(s.mean_deviation()),
+					ms(s.percentile(50.0)),
+					ms(s.percentile(90.0)),
+					ms(s.percentile(99.0)),
+					s.r_factor().unwrap_or_default(),
+					s.mos().unwrap_or_default(),
+				);
+			}
+		}
+		
+		for (addr, ttl, hops, os) in &self.ttls {
+			println!("{}: ttl {}, ~{} hops away, initial ttl {} ({})", addr, ttl, hops, *ttl as u16 + *hops as u16, os);
+		}
+		
+		for (addr, sum, n) in &self.offsets {
+			println!("{}: remote clock offset {:+.1} ms (average of {})", addr, sum / *n as f64, n);
+		}
+		
+		for (tos, s, rewritten) in &self.classes {
+			let ms = |d: Option<Duration>| d.map_or(0.0, |d| d.as_secs_f32() * 1000.0);
+			println!("tos 0x{:02x} (dscp {}): {} transmitted, {} received, {} rewritten, {:.1}% loss, rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+				tos,
+				tos >> 2,
+				s.transmitted,
+				s.received,
+				rewritten,
+				s.loss_percent(),
+				ms(s.min()),
+				ms(s.avg()),
+				ms(s.max()),
+			);
+		}
+		
+		if let Some(rtts) = &self.rtts && rtts.count() > 0 {
+			print_histogram(rtts);
+		}
+	}
+}
+
+/// Histogram and percentiles from the sketch, so a long run is not kept in memory.
+fn print_histogram(rtts: &QuantileSketch) {
+	let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+	
+	let (Some(min), Some(max)) = (rtts.min(), rtts.max()) else {
+		return;
+	};
+	let (min, max) = (ms(min), ms(max));
+	let bins = if max > min { HISTOGRAM_BINS } else { 1 };
+	let width = (max - min) / bins as f64;
+	
+	let mut counts = vec![0u64; bins];
+	for (rtt, count) in rtts.buckets() {
+		let rtt = ms(rtt).clamp(min, max);
+		let bin = if width > 0.0 { ((rtt - min) / width) as usize } else { 0 };
+		counts[bin.min(bins - 1)] += count;
+	}
+	
+	// narrow bins, like on a lan, need more digits to tell apart
+	let precision = if width < 0.1 { 3 } else { 2 };
+	
+	println!("rtt histogram (ms):");
+	let most = counts.iter().copied().max().unwrap_or(1);
+	for (i, count) in counts.iter().enumerate() {
+		let bar = "#".repeat((count * HISTOGRAM_WIDTH as u64).div_ceil(most) as usize);
+		println!("{:>9.precision$} - {:<9.precision$} | {:<width$} {}", min + width * i as f64, min + width * (i + 1) as f64, bar, count, width = HISTOGRAM_WIDTH);
+	}
+	
+	println!("rtt percentiles (ms):");
+	for p in [50.0, 90.0, 99.0, 99.9] {
+		println!("{:>9} {:.precision$}", format!("p{}", p), rtts.quantile(p / 100.0).map_or(0.0, ms));
+	}
+}
+
+/// Writes the final statistics of every address to `path` as one json object,
+/// for scripts that want the numbers rather than the summary lines. Times are
+/// in ms, and null when there were no replies to measure them on.
+pub fn write_json(path: &Path, target: &str, statistics: &[(IpAddr, Statistics)]) -> Result<(), String> {
+	let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+	let addresses: Vec<serde_json::Value> = statistics.iter().map(|(addr, s)| serde_json::json!({
+		"address": addr.to_string(),
+		"transmitted": s.transmitted,
+		"received": s.received,
+		"loss_percent": s.loss_percent(),
+		"rtt_min": ms(s.min()),
+		"rtt_avg": ms(s.avg()),
+		"rtt_max": ms(s.max()),
+		"rtt_p50": ms(s.percentile(50.0)),
+		"rtt_p90": ms(s.percentile(90.0)),
+		"rtt_p99": ms(s.percentile(99.0)),
+		"jitter": ms(s.jitter()),
+		"mean_deviation": ms(s.mean_deviation()),
+		"r_factor": s.r_factor(),
+		"mos": s.mos(),
+	})).collect();
+	
+	let body = serde_json::json!({
+		"target": target,
+		"addresses": addresses,
+	});
+	
+	if let Err(e) = std::fs::write(path, body.to_string() + "\n") {
+		error!(desc = e.to_string(), path = path.display().to_string(), "could not write statistics");
+		return Err("could not write statistics".to_string());
+	}
+	return Ok(());
+}
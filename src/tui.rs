@@ -0,0 +1,417 @@
+use logger::prelude::*;
+use icmp::prelude::*;
+
+use std::{
+	collections::VecDeque,
+	net::IpAddr,
+	sync::{
+		atomic::{
+			AtomicBool,
+			Ordering
+		},
+		Arc,
+		Mutex
+	},
+	time::{
+		Duration,
+		Instant
+	}
+};
+use ratatui::{
+	crossterm::event::{
+		self,
+		Event,
+		KeyCode,
+		KeyEventKind,
+		KeyModifiers
+	},
+	layout::{
+		Constraint,
+		Layout,
+		Rect
+	},
+	style::{
+		Color,
+		Style,
+		Stylize
+	},
+	symbols,
+	text::{
+		Line,
+		Span
+	},
+	widgets::{
+		Axis,
+		Block,
+		Chart,
+		Dataset,
+		GraphType,
+		Paragraph
+	},
+	DefaultTerminal,
+	Frame
+};
+use crate::{
+	alert::Alerts,
+	info_query::Info
+};
+
+/// Probes kept for the graph and the loss timeline.
+const HISTORY: usize = 2000;
+
+/// The rolling statistics look at this many of the most recent probes.
+const ROLLING: usize = 60;
+
+const REDRAW: Duration = Duration::from_millis(100);
+
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the dashboard owns the terminal. Log lines are dropped meanwhile,
+/// they would only scribble over the screen.
+pub fn is_active() -> bool {
+	return ACTIVE.load(Ordering::Relaxed);
+}
+
+/// Result of the whois and geolocation lookup, filled in by a background thread.
+pub enum Lookup {
+	Pending,
+	Done(Option<Info>),
+	Failed,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+	Waiting,
+	Replied(f64),
+	Lost,
+}
+
+struct Probe {
+	number: u64,
+	dest: IpAddr,
+	sequence: u16,
+	state: State,
+}
+
+/// Full screen view of a running session: an rtt graph, a loss timeline,
+/// overall and rolling statistics and the whois panel.
+pub struct Dashboard {
+	target: String,
+	lookup: Option<Arc<Mutex<Lookup>>>,
+	alerts: Option<Alerts>,
+	
+	started: Instant,
+	sent: u64,
+	probes: VecDeque<Probe>,
+	events: VecDeque<String>,
+	quit: bool,
+}
+
+impl Dashboard {
+	/// `lookup` is shown in the whois panel, there is no panel without it.
+	pub fn new(target: String, lookup: Option<Arc<Mutex<Lookup>>>) -> Self {
+		return Self {
+			target,
+			lookup,
+			alerts: None,
+			
+			started: Instant::now(),
+			sent: 0,
+			probes: VecDeque::new(),
+			events: VecDeque::new(),
+			quit: false,
+		};
+	}
+	
+	/// Alert transitions show up as events, and the header while one is firing.
+	pub fn alerts(mut self, alerts: Alerts) -> Self {
+		self.alerts = Some(alerts);
+		return self;
+	}
+	
+	/// Takes over the terminal until the user quits, then gives it back. The
+	/// alerts are handed back to wait for their webhooks.
+	pub fn run(mut self, session: &mut PingSession) -> Result<Option<Alerts>, String> {
+		let mut terminal = match ratatui::try_init() {
+			Ok(t) => t,
+			Err(e) => {
+				error!(desc = e.to_string(), "could not set up the terminal");
+				return Err("could not set up the terminal".to_string());
+			}
+		};
+		
+		// a panic must get the terminal back before anything is logged
+		ACTIVE.store(true, Ordering::Relaxed);
+		let hook = std::panic::take_hook();
+		std::panic::set_hook(Box::new(move |panic| {
+			ACTIVE.store(false, Ordering::Relaxed);
+			hook(panic);
+		}));
+		
+		let result = self.event_loop(&mut terminal, session).map(|_| self.alerts.take());
+		
+		let restored = ratatui::try_restore();
+		ACTIVE.store(false, Ordering::Relaxed);
+		if let Err(e) = restored {
+			error!(desc = e.to_string(), "could not restore the terminal");
+		}
+		
+		return result;
+	}
+	
+	fn event_loop(&mut self, terminal: &mut DefaultTerminal, session: &mut PingSession) -> Result<(), String> {
+		let mut last_draw: Option<Instant> = None;
+		
+		while !self.quit {
+			if let Err(e) = self.handle_keys(session) {
+				error!(desc = e.to_string(), "could not read terminal input");
+				return Err("could not read terminal input".to_string());
+			}
+			
+			if last_draw.is_none_or(|d| d.elapsed() >= REDRAW) {
+				if let Err(e) = terminal.draw(|frame| self.draw(frame, session)) {
+					error!(desc = e.to_string(), "could not draw");
+					return Err("could not draw".to_string());
+				}
+				last_draw = Some(Instant::now());
+			}
+			
+			let wait = last_draw.map_or(Duration::ZERO, |d| REDRAW.saturating_sub(d.elapsed()));
+			if session.is_finished() {
+				// nothing left to do but wait for the user
+				let _ = event::poll(wait);
+				continue;
+			}
+			if let Some(outcome) = session.poll(wait) {
+				self.outcome(outcome);
+			}
+		}
+		
+		return Ok(());
+	}
+	
+	fn handle_keys(&mut self, session: &mut PingSession) -> std::io::Result<()> {
+		while event::poll(Duration::ZERO)? {
+			let Event::Key(key) = event::read()? else {
+				continue;
+			};
+			if key.kind != KeyEventKind::Press {
+				continue;
+			}
+			
+			match key.code {
+				KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+				KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+				KeyCode::Char('p') | KeyCode::Char(' ') => session.set_paused(!session.is_paused()),
+				KeyCode::Char('r') => {
+					session.reset_statistics();
+					self.started = Instant::now();
+					self.probes.clear();
+					self.events.clear();
+				}
+				KeyCode::Char('+') | KeyCode::Char('=') => {
+					session.set_interval((session.get_interval() * 2).min(MAX_INTERVAL));
+				}
+				KeyCode::Char('-') => {
+					session.set_interval((session.get_interval() / 2).max(MIN_INTERVAL));
+				}
+				_ => {}
+			}
+		}
+		
+		return Ok(());
+	}
+	
+	fn event(&mut self, line: String) {
+		if self.events.len() == 100 {
+			self.events.pop_front();
+		}
+		self.events.push_back(line);
+	}
+	
+	fn outcome(&mut self, outcome: ProbeOutcome) {
+		let transitions = self.alerts.as_mut().map(|a| a.outcome(&outcome)).unwrap_or_default();
+		for transition in transitions {
+			self.event(transition.to_string());
+		}
+		
+		match outcome {
+			ProbeOutcome::Sent { dest, sequence, .. } => {
+				self.sent += 1;
+				if self.probes.len() == HISTORY {
+					self.probes.pop_front();
+				}
+				self.probes.push_back(Probe { number: self.sent, dest, sequence, state: State::Waiting });
+			}
+			ProbeOutcome::SendFailed { dest, error } => {
+				self.event(format!("sending to {} failed: {}", dest, error));
+			}
+			ProbeOutcome::AddressChanged { old, new } => {
+				self.event(format!("{} changed address: {} -> {}", self.target, old, new));
+			}
+			ProbeOutcome::Reply(reply) => {
+				let ms = reply.elapsed.as_secs_f64() * 1000.0;
+				self.set_state(reply.from_addr.into(), reply.sequence, State::Replied(ms));
+			}
+			ProbeOutcome::Late(reply) => {
+				self.event(format!("[{}] late reply from {} after {:.2} ms", reply.sequence, reply.from_addr, reply.elapsed.as_secs_f64() * 1000.0));
+			}
+			ProbeOutcome::Timeout { dest, sequence } => self.set_state(dest, sequence, State::Lost),
+		}
+	}
+	
+	fn set_state(&mut self, dest: IpAddr, sequence: u16, state: State) {
+		if let Some(probe) = self.probes.iter_mut().rev().find(|p| p.dest == dest && p.sequence == sequence && p.state == State::Waiting) {
+			probe.state = state;
+		}
+	}
+	
+	fn draw(&self, frame: &mut Frame, session: &PingSession) {
+		let [header, top, graph, timeline, events, footer] = Layout::vertical([
+			Constraint::Length(1),
+			Constraint::Length(6),
+			Constraint::Min(8),
+			Constraint::Length(3),
+			Constraint::Length(6),
+			Constraint::Length(1),
+		]).areas(frame.area());
+		
+		let state = if self.alerts.as_ref().is_some_and(|a| a.firing()) {
+			"alert".red().bold()
+		} else if session.is_finished() {
+			"finished".yellow()
+		} else if session.is_paused() {
+			"paused".yellow()
+		} else {
+			"running".green()
+		};
+		let elapsed = self.started.elapsed().as_secs();
+		frame.render_widget(Line::from(vec![
+			format!(" sonar {} ({}) ", self.target, session.pinger().get_dest()).bold(),
+			format!("| interval {:.2} s | ", session.get_interval().as_secs_f32()).into(),
+			state,
+			format!(" | {:02}:{:02}:{:02}", elapsed / 3600, elapsed / 60 % 60, elapsed % 60).into(),
+		]), header);
+		
+		match &self.lookup {
+			Some(lookup) => {
+				let [stats, info] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(top);
+				self.draw_statistics(frame, stats, session);
+				draw_lookup(frame, info, lookup);
+			}
+			None => self.draw_statistics(frame, top, session),
+		}
+		
+		self.draw_graph(frame, graph);
+		self.draw_timeline(frame, timeline);
+		
+		let lines: Vec<Line> = self.events.iter().rev().take(events.height.saturating_sub(2) as usize).rev().map(|e| Line::from(e.as_str())).collect();
+		frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Events ")), events);
+		
+		frame.render_widget(Line::from(" q quit | p pause | r reset statistics | +/- interval").dark_gray(), footer);
+	}
+	
+	fn draw_statistics(&self, frame: &mut Frame, area: Rect, session: &PingSession) {
+		let ms = |d: Option<Duration>| d.map_or(0.0, |d| d.as_secs_f64() * 1000.0);
+		
+		let mut total = Statistics::new();
+		let (mut min, mut max, mut sum) = (None::<Duration>, None::<Duration>, Duration::ZERO);
+		for (_, s) in session.statistics() {
+			total.transmitted += s.transmitted;
+			total.received += s.received;
+			min = match (min, s.min()) {
+				(Some(a), Some(b)) => Some(a.min(b)),
+				(a, b) => a.or(b),
+			};
+			max = max.max(s.max());
+			sum += s.sum();
+		}
+		let avg = if total.received > 0 { Some(Duration::from_secs_f64(sum.as_secs_f64() / total.received as f64)) } else { None };
+		
+		// jitter and mos only make sense for one path, take the address probes go to now
+		let dest = session.pinger().get_addr();
+		let current = session.statistics().iter().find(|(a, _)| *a == dest).map(|(_, s)| s);
+		
+		// the rolling window only counts probes that are no longer waiting
+		let recent: Vec<State> = self.probes.iter().rev().map(|p| p.state).filter(|s| *s != State::Waiting).take(ROLLING).collect();
+		let rtts: Vec<f64> = recent.iter().filter_map(|s| match s { State::Replied(ms) => Some(*ms), _ => None }).collect();
+		let lost = recent.len() - rtts.len();
+		let (r_min, r_max) = rtts.iter().fold((f64::MAX, 0.0f64), |(lo, hi), r| (lo.min(*r), hi.max(*r)));
+		let r_avg = if rtts.is_empty() { 0.0 } else { rtts.iter().sum::<f64>() / rtts.len() as f64 };
+		
+		let lines = vec![
+			Line::from(format!("{} transmitted, {} received, {:.1}% loss", total.transmitted, total.received, total.loss_percent())),
+			Line::from(format!("rtt min/avg/max = {:.2}/{:.2}/{:.2} ms", ms(min), ms(avg), ms(max))),
+			Line::from(format!("last {}: {:.1}% loss, rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+				recent.len(),
+				if recent.is_empty() { 0.0 } else { lost as f64 / recent.len() as f64 * 100.0 },
+				if rtts.is_empty() { 0.0 } else { r_min },
+				r_avg,
+				r_max,
+			)),
+			Line::from(match (rtts.first(), current) {
+				(Some(last), Some(s)) => format!("last rtt {:.2} ms, jitter {:.2} ms, MOS {:.2}", last, ms(s.jitter()), s.mos().unwrap_or_default()),
+				(Some(last), None) => format!("last rtt {:.2} ms", last),
+				(None, _) => "no replies yet".to_string(),
+			}),
+		];
+		frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Statistics ")), area);
+	}
+	
+	fn draw_graph(&self, frame: &mut Frame, area: Rect) {
+		// braille gives two points per cell, leave room for the axis labels
+		let width = (area.width.saturating_sub(10) as usize * 2).max(1);
+		let shown: Vec<&Probe> = self.probes.iter().rev().take(width).collect();
+		
+		let points: Vec<(f64, f64)> = shown.iter().rev().filter_map(|p| match p.state {
+			State::Replied(ms) => Some((p.number as f64, ms)),
+			_ => None,
+		}).collect();
+		let top = points.iter().map(|(_, ms)| *ms).fold(1.0, f64::max) * 1.2;
+		let first = shown.last().map_or(0, |p| p.number) as f64;
+		let last = (first + width as f64).max(shown.first().map_or(0, |p| p.number) as f64);
+		
+		let dataset = Dataset::default()
+			.marker(symbols::Marker::Braille)
+			.graph_type(GraphType::Line)
+			.style(Style::default().fg(Color::Green))
+			.data(&points);
+		
+		let chart = Chart::new(vec![dataset])
+			.block(Block::bordered().title(" RTT (ms) "))
+			.x_axis(Axis::default().bounds([first, last]))
+			.y_axis(Axis::default()
+				.bounds([0.0, top])
+				.labels([Span::from("0"), Span::from(format!("{:.1}", top / 2.0)), Span::from(format!("{:.1}", top))]));
+		frame.render_widget(chart, area);
+	}
+	
+	fn draw_timeline(&self, frame: &mut Frame, area: Rect) {
+		let width = area.width.saturating_sub(2) as usize;
+		let mut cells: Vec<Span> = self.probes.iter().rev().take(width).map(|p| match p.state {
+			State::Replied(_) => "█".green(),
+			State::Lost => "█".red(),
+			State::Waiting => "░".dark_gray(),
+		}).collect();
+		cells.reverse();
+		
+		frame.render_widget(Paragraph::new(Line::from(cells)).block(Block::bordered().title(" Loss ")), area);
+	}
+}
+
+fn draw_lookup(frame: &mut Frame, area: Rect, lookup: &Mutex<Lookup>) {
+	let lines = match &*lookup.lock().unwrap_or_else(|e| e.into_inner()) {
+		Lookup::Pending => vec![Line::from("looking up...").dark_gray()],
+		Lookup::Done(Some(info)) => vec![
+			Line::from(format!("NetName: {}", info.netname)),
+			Line::from(format!("Organization: {}", info.organization)),
+			Line::from(format!("Location: {}", info.location)),
+		],
+		Lookup::Done(None) => vec![Line::from("no whois answer").dark_gray()],
+		Lookup::Failed => vec![Line::from("whois failed").red()],
+	};
+	frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Whois ")), area);
+}
@@ -0,0 +1,139 @@
+use logger::prelude::*;
+
+use std::{
+	os::fd::{
+		AsRawFd,
+		FromRawFd,
+		OwnedFd,
+		RawFd
+	},
+	sync::{
+		atomic::{
+			AtomicBool,
+			Ordering
+		},
+		Arc
+	},
+	time::{
+		Duration,
+		Instant
+	}
+};
+
+#[derive(PartialEq, Debug)]
+pub enum Wait {
+	Ready,
+	Cancelled,
+	TimedOut,
+}
+
+struct Inner {
+	cancelled: AtomicBool,
+	read_fd: OwnedFd,
+	write_fd: OwnedFd,
+}
+
+/// Cloneable cancellation signal. Every clone observes the same cancellation,
+/// and blocking waits through `wait_readable`/`wait_writable` poll a self-pipe
+/// next to the socket, so they return as soon as `cancel` is called.
+#[derive(Clone)]
+pub struct CancelToken {
+	inner: Arc<Inner>,
+}
+
+impl CancelToken {
+	pub fn new() -> Result<Self, String> {
+		let mut fds: [libc::c_int; 2] = [0; 2];
+		if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+			let e = std::io::Error::last_os_error();
+			error!(desc = e.to_string(), "could not create pipe");
+			return Err("could not create pipe".to_string());
+		}
+
+		return Ok(Self {
+			inner: Arc::new(Inner {
+				cancelled: AtomicBool::new(false),
+				read_fd: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+				write_fd: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+			}),
+		});
+	}
+
+	pub fn cancel(&self) {
+		if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+			return;
+		}
+
+		// the byte is never read back, so the pipe stays readable and wakes every current and future waiter
+		let byte = 1u8;
+		if unsafe { libc::write(self.inner.write_fd.as_raw_fd(), &byte as *const u8 as *const libc::c_void, 1) } != 1 {
+			error!(desc = std::io::Error::last_os_error().to_string(), "could not write to cancel pipe");
+		}
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		return self.inner.cancelled.load(Ordering::SeqCst);
+	}
+
+	/// Blocks until `fd` is readable, the token is cancelled or `timeout` passes.
+	pub fn wait_readable(&self, fd: RawFd, timeout: Option<Duration>) -> Result<Wait, String> {
+		return self.wait(fd, libc::POLLIN, timeout);
+	}
+
+	/// Blocks until `fd` is writable, the token is cancelled or `timeout` passes.
+	pub fn wait_writable(&self, fd: RawFd, timeout: Option<Duration>) -> Result<Wait, String> {
+		return self.wait(fd, libc::POLLOUT, timeout);
+	}
+
+	/// Sleeps for `duration`, returning false early if the token is cancelled.
+	pub fn sleep(&self, duration: Duration) -> bool {
+		return !matches!(self.wait(-1, 0, Some(duration)), Ok(Wait::Cancelled));
+	}
+
+	fn wait(&self, fd: RawFd, events: libc::c_short, timeout: Option<Duration>) -> Result<Wait, String> {
+		let deadline = timeout.map(|t| Instant::now() + t);
+
+		loop {
+			if self.is_cancelled() {
+				return Ok(Wait::Cancelled);
+			}
+
+			let ms = match deadline {
+				Some(d) => {
+					let remaining = d.saturating_duration_since(Instant::now());
+					// round up so a sub-millisecond remainder does not turn into a busy loop
+					remaining.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int
+				}
+				None => -1,
+			};
+
+			let mut fds = [
+				libc::pollfd { fd, events, revents: 0 },
+				libc::pollfd { fd: self.inner.read_fd.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+			];
+
+			let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, ms) };
+			if ret < 0 {
+				let e = std::io::Error::last_os_error();
+				if e.kind() == std::io::ErrorKind::Interrupted {
+					continue;
+				}
+
+				error!(desc = e.to_string(), "poll failed");
+				return Err("poll failed".to_string());
+			}
+
+			if fds[1].revents != 0 || self.is_cancelled() {
+				return Ok(Wait::Cancelled);
+			}
+
+			if fds[0].revents != 0 {
+				return Ok(Wait::Ready);
+			}
+
+			if deadline.is_some_and(|d| Instant::now() >= d) {
+				return Ok(Wait::TimedOut);
+			}
+		}
+	}
+}
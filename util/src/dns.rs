@@ -0,0 +1,984 @@
+use logger::prelude::*;
+use crate::cancel::{
+	CancelToken,
+	Wait
+};
+
+use std::{
+	collections::VecDeque,
+	io::{
+		ErrorKind,
+		Read,
+		Write
+	},
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		Ipv6Addr,
+		SocketAddr,
+		TcpStream,
+		UdpSocket
+	},
+	os::fd::AsRawFd,
+	str::FromStr,
+	sync::OnceLock,
+	time::{
+		Duration,
+		Instant
+	}
+};
+
+use socket2::{
+	Domain,
+	Protocol,
+	Socket,
+	Type
+};
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u8 = 3;
+
+#[cfg(feature = "tokio")]
+mod nonblocking;
+
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+/// Installs the process wide resolver used by `dns_lookup` and `reverse_lookup`.
+/// Must be called before the first lookup, otherwise the system configuration is already in use.
+pub fn init(config: ResolverConfig) -> Result<(), String> {
+	if RESOLVER.set(Resolver::new(config)).is_err() {
+		return Err("resolver already initialized".to_string());
+	}
+
+	return Ok(());
+}
+
+pub fn resolver() -> &'static Resolver {
+	return RESOLVER.get_or_init(|| Resolver::new(ResolverConfig::from_system()));
+}
+
+pub struct ResolverConfig {
+	pub nameservers: Vec<SocketAddr>,
+	pub search: Vec<String>,
+	/// Names with at least this many dots are tried as they are before the search domains.
+	pub ndots: usize,
+	pub timeout: Duration,
+	pub attempts: u32,
+	pub use_hosts: bool,
+}
+
+impl Default for ResolverConfig {
+	fn default() -> Self {
+		return Self {
+			nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)],
+			search: Vec::new(),
+			ndots: 1,
+			timeout: Duration::from_secs(2),
+			attempts: 2,
+			use_hosts: true,
+		};
+	}
+}
+
+impl ResolverConfig {
+	pub fn from_system() -> Self {
+		return match std::fs::read_to_string("/etc/resolv.conf") {
+			Ok(s) => Self::parse_resolv_conf(&s),
+			Err(e) => {
+				warn!(desc = e.to_string(), "could not read /etc/resolv.conf");
+				Self::default()
+			}
+		};
+	}
+
+	pub fn parse_resolv_conf(s: &str) -> Self {
+		let mut config = Self::default();
+		let mut nameservers = Vec::new();
+
+		for line in s.lines() {
+			let line = line.split(['#', ';']).next().unwrap_or("");
+			let mut words = line.split_whitespace();
+
+			match words.next() {
+				Some("nameserver") => {
+					if let Some(addr) = words.next().and_then(|w| IpAddr::from_str(w.split('%').next().unwrap_or(w)).ok()) {
+						nameservers.push(SocketAddr::new(addr, 53));
+					}
+				}
+				Some("search") | Some("domain") => {
+					config.search = words.map(|w| w.trim_end_matches('.').to_string()).collect();
+				}
+				Some("options") => {
+					for opt in words {
+						if let Some(v) = opt.strip_prefix("timeout:").and_then(|v| v.parse().ok()) {
+							config.timeout = Duration::from_secs(v);
+						} else if let Some(v) = opt.strip_prefix("attempts:").and_then(|v| v.parse().ok()) {
+							config.attempts = v;
+						} else if let Some(v) = opt.strip_prefix("ndots:").and_then(|v| v.parse().ok()) {
+							config.ndots = v;
+						}
+					}
+				}
+				_ => {}
+			}
+		}
+
+		if !nameservers.is_empty() {
+			config.nameservers = nameservers;
+		}
+
+		trace!(nameservers = format!("{:?}", config.nameservers), search = format!("{:?}", config.search));
+		return config;
+	}
+}
+
+enum RecordData {
+	Addr(IpAddr),
+	Name(String),
+	Other,
+}
+
+struct Record {
+	typ: u16,
+	data: RecordData,
+}
+
+enum Plan {
+	Done(Vec<IpAddr>),
+	Query(Lookup),
+}
+
+/// The queries a lookup still has to make: A and AAAA for each candidate name
+/// in turn, until one of them has addresses. `lookup` and `lookup_async` only
+/// run the queries it hands out.
+struct Lookup {
+	candidates: VecDeque<String>,
+	current: Option<String>,
+	qtypes: Vec<u16>,
+	addrs: Vec<IpAddr>,
+	last_err: String,
+}
+
+impl Lookup {
+	fn new(candidates: Vec<String>) -> Self {
+		return Self {
+			candidates: candidates.into(),
+			current: None,
+			qtypes: Vec::new(),
+			addrs: Vec::new(),
+			last_err: "could not resolve dns".to_string(),
+		};
+	}
+
+	/// The next name and type to query, or None once there is an answer or nothing left to try.
+	fn next_query(&mut self) -> Option<(String, u16)> {
+		loop {
+			if let Some(candidate) = &self.current && let Some(qtype) = self.qtypes.pop() {
+				return Some((candidate.clone(), qtype));
+			}
+			if !self.addrs.is_empty() {
+				return None;
+			}
+
+			self.current = Some(self.candidates.pop_front()?);
+			self.qtypes = vec![TYPE_AAAA, TYPE_A];
+		}
+	}
+
+	fn answered(&mut self, records: Vec<Record>, qtype: u16) {
+		self.addrs.extend(addresses(records, qtype));
+	}
+
+	fn failed(&mut self, error: String) {
+		self.last_err = error;
+	}
+
+	fn finish(self) -> Result<Vec<IpAddr>, String> {
+		if self.addrs.is_empty() {
+			return Err(self.last_err);
+		}
+		return Ok(self.addrs);
+	}
+}
+
+/// One query, asked of the nameservers in turn for `attempts` rounds.
+/// Decides what each response means; the caller only does the exchanges.
+struct Query {
+	id: u16,
+	request: Vec<u8>,
+	servers: VecDeque<SocketAddr>,
+	last_err: String,
+}
+
+impl Query {
+	fn new(config: &ResolverConfig, name: &str, qtype: u16) -> Result<Self, String> {
+		let id = fastrand::u16(..);
+		trace!(name, qtype, "dns query");
+
+		let mut servers = VecDeque::new();
+		for _ in 0..config.attempts.max(1) {
+			servers.extend(config.nameservers.iter().copied());
+		}
+
+		return Ok(Self {
+			id,
+			request: build_query(id, name, qtype)?,
+			servers,
+			last_err: "no nameservers".to_string(),
+		});
+	}
+
+	fn next_server(&mut self) -> Option<SocketAddr> {
+		return self.servers.pop_front();
+	}
+
+	/// The exchange with `server` did not produce a response.
+	fn failed(&mut self, server: SocketAddr, error: String) {
+		debug!(desc = error, server = server.to_string(), "dns exchange failed");
+		self.last_err = error;
+	}
+
+	/// Whether the udp response was cut short and has to be asked for again over tcp.
+	fn truncated(response: &[u8]) -> bool {
+		let truncated = response.len() > 2 && response[2] & 0x02 != 0;
+		if truncated {
+			debug!("truncated response, retrying over tcp");
+		}
+		return truncated;
+	}
+
+	/// The result of the query if `response` settles it. Only a definite
+	/// answer does, a failing server leaves it to the next one.
+	fn response(&mut self, server: SocketAddr, response: &[u8]) -> Option<Result<Vec<Record>, String>> {
+		return match parse_response(self.id, response) {
+			Ok(records) => Some(Ok(records)),
+			Err(e) if rcode(response) == Some(RCODE_NXDOMAIN) => Some(Err(e)),
+			Err(e) => {
+				debug!(desc = e, server = server.to_string(), "dns query failed");
+				self.last_err = e;
+				None
+			}
+		};
+	}
+
+	fn finish(self) -> Result<Vec<Record>, String> {
+		return Err(self.last_err);
+	}
+}
+
+fn addresses(records: Vec<Record>, qtype: u16) -> impl Iterator<Item = IpAddr> {
+	return records.into_iter().filter_map(move |r| match r.data {
+		RecordData::Addr(a) if r.typ == qtype => Some(a),
+		_ => None,
+	});
+}
+
+pub struct Resolver {
+	config: ResolverConfig,
+	hosts: Vec<(IpAddr, Vec<String>)>,
+}
+
+impl Resolver {
+	pub fn new(config: ResolverConfig) -> Self {
+		let hosts = if config.use_hosts {
+			match std::fs::read_to_string("/etc/hosts") {
+				Ok(s) => parse_hosts(&s),
+				Err(e) => {
+					warn!(desc = e.to_string(), "could not read /etc/hosts");
+					Vec::new()
+				}
+			}
+		} else {
+			Vec::new()
+		};
+
+		return Self::with_hosts(config, hosts);
+	}
+
+	fn with_hosts(config: ResolverConfig, hosts: Vec<(IpAddr, Vec<String>)>) -> Self {
+		return Self {
+			config,
+			hosts,
+		};
+	}
+
+	/// Answers `name` from literals and /etc/hosts, or lists the names that have to be queried for it.
+	fn plan(&self, name: &str) -> Plan {
+		if let Ok(addr) = IpAddr::from_str(name) {
+			return Plan::Done(vec![addr]);
+		}
+
+		let name = name.to_lowercase();
+		let from_hosts: Vec<IpAddr> = self.hosts.iter()
+			.filter(|(_, names)| names.iter().any(|n| *n == name.trim_end_matches('.')))
+			.map(|(addr, _)| *addr)
+			.collect();
+		if !from_hosts.is_empty() {
+			debug!(name, "resolved from /etc/hosts");
+			return Plan::Done(from_hosts);
+		}
+
+		let mut candidates = Vec::new();
+		if name.ends_with('.') {
+			candidates.push(name.trim_end_matches('.').to_string());
+		} else {
+			let qualified = name.matches('.').count() >= self.config.ndots;
+			if qualified {
+				candidates.push(name.clone());
+			}
+			candidates.extend(self.config.search.iter().map(|s| format!("{}.{}", name, s)));
+			if !qualified {
+				candidates.push(name.clone());
+			}
+		}
+
+		return Plan::Query(Lookup::new(candidates));
+	}
+
+	/// Resolves `name` to its addresses, giving up as soon as `cancel` fires.
+	pub fn lookup(&self, name: &str, cancel: &CancelToken) -> Result<Vec<IpAddr>, String> {
+		let mut lookup = match self.plan(name) {
+			Plan::Done(addrs) => return Ok(addrs),
+			Plan::Query(l) => l,
+		};
+
+		while let Some((candidate, qtype)) = lookup.next_query() {
+			match self.query(&candidate, qtype, cancel) {
+				Ok(records) => lookup.answered(records, qtype),
+				Err(e) if cancel.is_cancelled() => return Err(e),
+				Err(e) => lookup.failed(e),
+			}
+		}
+
+		return lookup.finish();
+	}
+
+	pub fn reverse(&self, addr: IpAddr, cancel: &CancelToken) -> Result<String, String> {
+		if let Some(name) = self.hosts.iter().find(|(a, _)| *a == addr).and_then(|(_, names)| names.first()) {
+			return Ok(name.clone());
+		}
+
+		let records = self.query(&reverse_name(addr), TYPE_PTR, cancel)?;
+		for record in records {
+			if let (TYPE_PTR, RecordData::Name(name)) = (record.typ, record.data) {
+				return Ok(name);
+			}
+		}
+
+		return Err("no ptr record".to_string());
+	}
+
+	fn query(&self, name: &str, qtype: u16, cancel: &CancelToken) -> Result<Vec<Record>, String> {
+		let mut query = Query::new(&self.config, name, qtype)?;
+		while let Some(server) = query.next_server() {
+			if cancel.is_cancelled() {
+				return Err("stop signal".to_string());
+			}
+
+			let mut response = match self.exchange_udp(server, &query.request, cancel) {
+				Ok(r) => r,
+				Err(e) => {
+					query.failed(server, e);
+					continue;
+				}
+			};
+
+			if Query::truncated(&response) {
+				response = match self.exchange_tcp(server, &query.request, cancel) {
+					Ok(r) => r,
+					Err(e) => {
+						query.failed(server, e);
+						continue;
+					}
+				};
+			}
+
+			if let Some(result) = query.response(server, &response) {
+				return result;
+			}
+		}
+
+		return query.finish();
+	}
+
+	fn exchange_udp(&self, server: SocketAddr, request: &[u8], cancel: &CancelToken) -> Result<Vec<u8>, String> {
+		let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+		let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+		socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+		socket.connect(server).map_err(|e| e.to_string())?;
+		socket.send(request).map_err(|e| e.to_string())?;
+
+		let deadline = Instant::now() + self.config.timeout;
+		let mut buffer = [0; 1232];
+		loop {
+			wait(cancel.wait_readable(socket.as_raw_fd(), Some(deadline.saturating_duration_since(Instant::now())))?)?;
+
+			let bytes = match socket.recv(&mut buffer) {
+				Ok(b) => b,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+				Err(e) => return Err(e.to_string()),
+			};
+			// ignore stray datagrams that do not answer our id
+			if bytes >= 2 && buffer[0..2] == request[0..2] {
+				return Ok(Vec::from(&buffer[..bytes]));
+			}
+		}
+	}
+
+	fn exchange_tcp(&self, server: SocketAddr, request: &[u8], cancel: &CancelToken) -> Result<Vec<u8>, String> {
+		let deadline = Instant::now() + self.config.timeout;
+		let remaining = || deadline.saturating_duration_since(Instant::now());
+
+		// connect without blocking so a cancel does not have to wait for the connect timeout
+		let socket = Socket::new(Domain::for_address(server), Type::STREAM, Some(Protocol::TCP)).map_err(|e| e.to_string())?;
+		socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+		match socket.connect(&server.into()) {
+			Ok(()) => {}
+			Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) || e.kind() == ErrorKind::WouldBlock => {
+				wait(cancel.wait_writable(socket.as_raw_fd(), Some(remaining()))?)?;
+				if let Ok(Some(e)) | Err(e) = socket.take_error() {
+					return Err(e.to_string());
+				}
+			}
+			Err(e) => return Err(e.to_string()),
+		}
+		let mut stream = TcpStream::from(socket);
+
+		let mut framed = Vec::with_capacity(request.len() + 2);
+		framed.extend_from_slice(&(request.len() as u16).to_be_bytes());
+		framed.extend_from_slice(request);
+
+		let mut written = 0;
+		while written < framed.len() {
+			wait(cancel.wait_writable(stream.as_raw_fd(), Some(remaining()))?)?;
+			match stream.write(&framed[written..]) {
+				Ok(b) => written += b,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+				Err(e) => return Err(e.to_string()),
+			}
+		}
+
+		let mut len = [0; 2];
+		read_full(&mut stream, &mut len, cancel, deadline)?;
+		let mut response = vec![0; u16::from_be_bytes(len) as usize];
+		read_full(&mut stream, &mut response, cancel, deadline)?;
+
+		return Ok(response);
+	}
+}
+
+/// Turns the result of a wait on a socket into an error unless it became ready.
+fn wait(wait: Wait) -> Result<(), String> {
+	return match wait {
+		Wait::Ready => Ok(()),
+		Wait::Cancelled => Err("stop signal".to_string()),
+		Wait::TimedOut => Err("dns query timed out".to_string()),
+	};
+}
+
+/// Fills `buffer` from a nonblocking stream, waiting for it between reads.
+fn read_full(stream: &mut TcpStream, buffer: &mut [u8], cancel: &CancelToken, deadline: Instant) -> Result<(), String> {
+	let mut filled = 0;
+	while filled < buffer.len() {
+		wait(cancel.wait_readable(stream.as_raw_fd(), Some(deadline.saturating_duration_since(Instant::now())))?)?;
+		match stream.read(&mut buffer[filled..]) {
+			Ok(0) => return Err("connection closed".to_string()),
+			Ok(b) => filled += b,
+			Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+			Err(e) => return Err(e.to_string()),
+		}
+	}
+
+	return Ok(());
+}
+
+fn parse_hosts(s: &str) -> Vec<(IpAddr, Vec<String>)> {
+	let mut hosts = Vec::new();
+	for line in s.lines() {
+		let line = line.split('#').next().unwrap_or("");
+		let mut words = line.split_whitespace();
+
+		let addr = match words.next().and_then(|w| IpAddr::from_str(w).ok()) {
+			Some(a) => a,
+			None => continue,
+		};
+
+		let names: Vec<String> = words.map(|w| w.to_lowercase()).collect();
+		if !names.is_empty() {
+			hosts.push((addr, names));
+		}
+	}
+
+	return hosts;
+}
+
+fn reverse_name(addr: IpAddr) -> String {
+	return match addr {
+		IpAddr::V4(v4) => {
+			let o = v4.octets();
+			format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+		}
+		IpAddr::V6(v6) => {
+			let mut name = String::with_capacity(72);
+			for byte in v6.octets().iter().rev() {
+				name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+			}
+			name + "ip6.arpa"
+		}
+	};
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, String> {
+	let mut query = Vec::with_capacity(name.len() + 18);
+	query.extend_from_slice(&id.to_be_bytes());
+	// recursion desired, one question
+	query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+	for label in name.trim_end_matches('.').split('.') {
+		if label.is_empty() || label.len() > 63 {
+			return Err("invalid domain name".to_string());
+		}
+		query.push(label.len() as u8);
+		query.extend_from_slice(label.as_bytes());
+	}
+	query.push(0);
+
+	query.extend_from_slice(&qtype.to_be_bytes());
+	query.extend_from_slice(&CLASS_IN.to_be_bytes());
+	return Ok(query);
+}
+
+fn read_u16(buffer: &[u8], pos: usize) -> Result<u16, String> {
+	return match buffer.get(pos..pos + 2) {
+		Some(b) => Ok(((b[0] as u16) << 8) | (b[1] as u16)),
+		None => Err("truncated dns message".to_string()),
+	};
+}
+
+/// Reads a possibly compressed name starting at `pos`, returning it and the position after it.
+fn read_name(buffer: &[u8], mut pos: usize) -> Result<(String, usize), String> {
+	let mut labels: Vec<String> = Vec::new();
+	let mut end = None;
+	let mut jumps = 0;
+
+	loop {
+		let len = match buffer.get(pos) {
+			Some(l) => *l as usize,
+			None => return Err("truncated dns message".to_string()),
+		};
+
+		if len & 0xc0 == 0xc0 {
+			let ptr = (read_u16(buffer, pos)? & 0x3fff) as usize;
+			if end.is_none() {
+				end = Some(pos + 2);
+			}
+
+			jumps += 1;
+			if jumps > 16 {
+				return Err("dns compression loop".to_string());
+			}
+			pos = ptr;
+			continue;
+		}
+
+		if len == 0 {
+			return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+		}
+
+		let label = match buffer.get(pos + 1..pos + 1 + len) {
+			Some(l) => l,
+			None => return Err("truncated dns message".to_string()),
+		};
+		labels.push(String::from_utf8_lossy(label).into_owned());
+		pos += 1 + len;
+	}
+}
+
+fn rcode(buffer: &[u8]) -> Option<u8> {
+	return buffer.get(3).map(|b| b & 0x0f);
+}
+
+fn parse_response(id: u16, buffer: &[u8]) -> Result<Vec<Record>, String> {
+	if buffer.len() < 12 {
+		return Err("truncated dns message".to_string());
+	}
+
+	if read_u16(buffer, 0)? != id || buffer[2] & 0x80 == 0 {
+		return Err("unexpected dns response".to_string());
+	}
+
+	let code = buffer[3] & 0x0f;
+	if code == RCODE_NXDOMAIN {
+		return Err("no such host".to_string());
+	} else if code != 0 {
+		return Err(format!("dns server returned rcode {}", code));
+	}
+
+	let questions = read_u16(buffer, 4)?;
+	let answers = read_u16(buffer, 6)?;
+
+	let mut pos = 12;
+	for _ in 0..questions {
+		pos = read_name(buffer, pos)?.1 + 4;
+	}
+
+	let mut records = Vec::with_capacity(answers as usize);
+	for _ in 0..answers {
+		pos = read_name(buffer, pos)?.1;
+		let typ = read_u16(buffer, pos)?;
+		let class = read_u16(buffer, pos + 2)?;
+		let len = read_u16(buffer, pos + 8)? as usize;
+		let start = pos + 10;
+
+		let rdata = match buffer.get(start..start + len) {
+			Some(r) => r,
+			None => return Err("truncated dns message".to_string()),
+		};
+
+		let data = match (class, typ, len) {
+			(CLASS_IN, TYPE_A, 4) => RecordData::Addr(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+			(CLASS_IN, TYPE_AAAA, 16) => {
+				let mut octets = [0; 16];
+				octets.copy_from_slice(rdata);
+				RecordData::Addr(IpAddr::V6(Ipv6Addr::from(octets)))
+			}
+			(CLASS_IN, TYPE_CNAME, _) | (CLASS_IN, TYPE_PTR, _) => RecordData::Name(read_name(buffer, start)?.0),
+			_ => RecordData::Other,
+		};
+
+		records.push(Record {
+			typ,
+			data,
+		});
+		pos = start + len;
+	}
+
+	return Ok(records);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::{
+		net::TcpListener,
+		thread::JoinHandle,
+		time::Instant
+	};
+
+	pub(super) const RCODE_SERVFAIL: u8 = 2;
+
+	pub(super) fn resolver(nameservers: Vec<SocketAddr>, timeout: Duration) -> Resolver {
+		return Resolver::new(ResolverConfig {
+			nameservers,
+			search: Vec::new(),
+			ndots: 1,
+			timeout,
+			attempts: 1,
+			use_hosts: false,
+		});
+	}
+
+	/// The question type of a query built by `build_query`.
+	pub(super) fn qtype(request: &[u8]) -> u16 {
+		let end = read_name(request, 12).unwrap().1;
+		return read_u16(request, end).unwrap();
+	}
+
+	/// Answers `request` with `rcode`, the `tc` bit and one record per entry of `answers`, as (type, rdata).
+	pub(super) fn response(request: &[u8], rcode: u8, tc: bool, answers: &[(u16, Vec<u8>)]) -> Vec<u8> {
+		let question_end = read_name(request, 12).unwrap().1 + 4;
+
+		let mut response = Vec::from(&request[..2]);
+		response.push(0x81 | if tc { 0x02 } else { 0 });
+		response.push(0x80 | rcode);
+		response.extend_from_slice(&[0, 1]);
+		response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+		response.extend_from_slice(&[0, 0, 0, 0]);
+		response.extend_from_slice(&request[12..question_end]);
+
+		for (typ, rdata) in answers {
+			// the name points back at the question
+			response.extend_from_slice(&[0xc0, 0x0c]);
+			response.extend_from_slice(&typ.to_be_bytes());
+			response.extend_from_slice(&CLASS_IN.to_be_bytes());
+			response.extend_from_slice(&[0, 0, 0, 60]);
+			response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+			response.extend_from_slice(rdata);
+		}
+
+		return response;
+	}
+
+	/// Encodes `name` the way it appears on the wire.
+	pub(super) fn wire_name(name: &str) -> Vec<u8> {
+		let mut wire = Vec::new();
+		for label in name.split('.') {
+			wire.push(label.len() as u8);
+			wire.extend_from_slice(label.as_bytes());
+		}
+		wire.push(0);
+		return wire;
+	}
+
+	/// A nameserver on localhost answering `count` udp queries with whatever `answer` sends back for them.
+	pub(super) fn udp_server<F>(count: usize, answer: F) -> (SocketAddr, JoinHandle<()>)
+	where
+		F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+	{
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let addr = socket.local_addr().unwrap();
+
+		let handle = std::thread::spawn(move || {
+			let mut buffer = [0; 512];
+			for _ in 0..count {
+				let (bytes, from) = socket.recv_from(&mut buffer).unwrap();
+				for datagram in answer(&buffer[..bytes]) {
+					socket.send_to(&datagram, from).unwrap();
+				}
+			}
+		});
+
+		return (addr, handle);
+	}
+
+	pub(super) fn answer_addresses(request: &[u8]) -> Vec<Vec<u8>> {
+		return match qtype(request) {
+			TYPE_A => vec![response(request, 0, false, &[(TYPE_A, vec![192, 0, 2, 1])])],
+			TYPE_AAAA => vec![response(request, 0, false, &[(TYPE_AAAA, Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets().to_vec())])],
+			_ => vec![response(request, RCODE_NXDOMAIN, false, &[])],
+		};
+	}
+
+	#[test]
+	fn a_and_aaaa_answers() {
+		let (server, handle) = udp_server(2, answer_addresses);
+
+		let addrs = resolver(vec![server], Duration::from_secs(2)).lookup("host.example", &CancelToken::new().unwrap()).unwrap();
+		handle.join().unwrap();
+
+		assert_eq!(addrs, vec![
+			IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+			IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+		]);
+	}
+
+	#[test]
+	fn ptr_answer() {
+		let (server, handle) = udp_server(1, |request| {
+			let end = read_name(request, 12).unwrap().1;
+			assert_eq!(read_name(request, 12).unwrap().0, "1.2.0.192.in-addr.arpa");
+			assert_eq!(read_u16(request, end).unwrap(), TYPE_PTR);
+			return vec![response(request, 0, false, &[(TYPE_PTR, wire_name("host.example"))])];
+		});
+
+		let name = resolver(vec![server], Duration::from_secs(2)).reverse(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), &CancelToken::new().unwrap()).unwrap();
+		handle.join().unwrap();
+
+		assert_eq!(name, "host.example");
+	}
+
+	#[test]
+	fn nxdomain_is_final() {
+		let (server, handle) = udp_server(1, |request| vec![response(request, RCODE_NXDOMAIN, false, &[])]);
+
+		let result = resolver(vec![server], Duration::from_secs(2)).query("missing.example", TYPE_A, &CancelToken::new().unwrap());
+		handle.join().unwrap();
+
+		assert_eq!(result.err(), Some("no such host".to_string()));
+	}
+
+	#[test]
+	fn truncated_reply_falls_back_to_tcp() {
+		// the tcp listener has to share the port of the udp server
+		let (server, listener) = loop {
+			let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+			if let Ok(listener) = TcpListener::bind(socket.local_addr().unwrap()) {
+				break (socket, listener);
+			}
+		};
+		let addr = server.local_addr().unwrap();
+
+		let udp = std::thread::spawn(move || {
+			let mut buffer = [0; 512];
+			let (bytes, from) = server.recv_from(&mut buffer).unwrap();
+			server.send_to(&response(&buffer[..bytes], 0, true, &[]), from).unwrap();
+		});
+		let tcp = std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut len = [0; 2];
+			stream.read_exact(&mut len).unwrap();
+			let mut request = vec![0; u16::from_be_bytes(len) as usize];
+			stream.read_exact(&mut request).unwrap();
+
+			let response = response(&request, 0, false, &[(TYPE_A, vec![192, 0, 2, 7])]);
+			stream.write_all(&(response.len() as u16).to_be_bytes()).unwrap();
+			stream.write_all(&response).unwrap();
+		});
+
+		let records = resolver(vec![addr], Duration::from_secs(2)).query("host.example", TYPE_A, &CancelToken::new().unwrap()).unwrap();
+		udp.join().unwrap();
+		tcp.join().unwrap();
+
+		assert_eq!(addresses(records, TYPE_A).collect::<Vec<_>>(), vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7))]);
+	}
+
+	#[test]
+	fn mismatched_id_is_ignored() {
+		let (server, handle) = udp_server(1, |request| {
+			let mut stray = response(request, 0, false, &[(TYPE_A, vec![203, 0, 113, 1])]);
+			stray[0] ^= 0xff;
+			return vec![stray, response(request, 0, false, &[(TYPE_A, vec![192, 0, 2, 1])])];
+		});
+
+		let records = resolver(vec![server], Duration::from_secs(2)).query("host.example", TYPE_A, &CancelToken::new().unwrap()).unwrap();
+		handle.join().unwrap();
+
+		assert_eq!(addresses(records, TYPE_A).collect::<Vec<_>>(), vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+	}
+
+	#[test]
+	fn timeout() {
+		// bound but never answering
+		let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+		let started = Instant::now();
+		let result = resolver(vec![silent.local_addr().unwrap()], Duration::from_millis(200)).query("host.example", TYPE_A, &CancelToken::new().unwrap());
+
+		assert!(result.is_err());
+		assert!(started.elapsed() >= Duration::from_millis(200));
+		assert!(started.elapsed() < Duration::from_secs(2));
+	}
+
+	#[test]
+	fn servfail_tries_the_next_nameserver() {
+		let (failing, failing_handle) = udp_server(1, |request| vec![response(request, RCODE_SERVFAIL, false, &[])]);
+		let (working, working_handle) = udp_server(1, |request| vec![response(request, 0, false, &[(TYPE_A, vec![192, 0, 2, 1])])]);
+
+		let records = resolver(vec![failing, working], Duration::from_secs(2)).query("host.example", TYPE_A, &CancelToken::new().unwrap()).unwrap();
+		failing_handle.join().unwrap();
+		working_handle.join().unwrap();
+
+		assert_eq!(addresses(records, TYPE_A).collect::<Vec<_>>(), vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+	}
+
+	#[test]
+	fn cancel_interrupts_a_query() {
+		let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let cancel = CancelToken::new().unwrap();
+
+		let canceller = cancel.clone();
+		std::thread::spawn(move || {
+			std::thread::sleep(Duration::from_millis(100));
+			canceller.cancel();
+		});
+
+		// waiting out both silent nameservers would take 10 s
+		let started = Instant::now();
+		let result = resolver(vec![silent.local_addr().unwrap(); 2], Duration::from_secs(5)).query("host.example", TYPE_A, &cancel);
+
+		assert_eq!(result.err(), Some("stop signal".to_string()));
+		assert!(started.elapsed() < Duration::from_secs(1));
+	}
+
+	#[test]
+	fn resolv_conf() {
+		let config = ResolverConfig::parse_resolv_conf("\
+# written by hand
+nameserver 192.0.2.53 ; the office one
+nameserver 2001:db8::53
+nameserver fe80::1%eth0
+nameserver not-an-address
+;nameserver 192.0.2.99
+search example.com. corp.example.com
+options timeout:1 attempts:3 ndots:2 rotate
+");
+
+		assert_eq!(config.nameservers, vec![
+			"192.0.2.53:53".parse().unwrap(),
+			"[2001:db8::53]:53".parse().unwrap(),
+			"[fe80::1]:53".parse().unwrap(),
+		]);
+		assert_eq!(config.search, vec!["example.com", "corp.example.com"]);
+		assert_eq!(config.timeout, Duration::from_secs(1));
+		assert_eq!(config.attempts, 3);
+		assert_eq!(config.ndots, 2);
+	}
+
+	#[test]
+	fn resolv_conf_defaults() {
+		let config = ResolverConfig::parse_resolv_conf("# nothing configured\n\ndomain lan\nsearch example.com\n");
+		let default = ResolverConfig::default();
+
+		assert_eq!(config.nameservers, default.nameservers);
+		// the last search or domain line wins
+		assert_eq!(config.search, vec!["example.com"]);
+		assert_eq!((config.timeout, config.attempts, config.ndots), (default.timeout, default.attempts, default.ndots));
+	}
+
+	#[test]
+	fn hosts_file() {
+		let hosts = parse_hosts("\
+127.0.0.1\tlocalhost # loopback
+::1 localhost ip6-localhost
+# 192.0.2.7 commented.example
+192.0.2.8 Gateway.Example gw
+192.0.2.9
+bogus entry
+");
+
+		assert_eq!(hosts, vec![
+			(IpAddr::V4(Ipv4Addr::LOCALHOST), vec!["localhost".to_string()]),
+			(IpAddr::V6(Ipv6Addr::LOCALHOST), vec!["localhost".to_string(), "ip6-localhost".to_string()]),
+			("192.0.2.8".parse().unwrap(), vec!["gateway.example".to_string(), "gw".to_string()]),
+		]);
+	}
+
+	fn candidates(resolver: &Resolver, name: &str) -> Vec<String> {
+		return match resolver.plan(name) {
+			Plan::Query(l) => l.candidates.into(),
+			Plan::Done(addrs) => panic!("{} answered locally with {:?}", name, addrs),
+		};
+	}
+
+	#[test]
+	fn search_domains_and_ndots() {
+		let mut config = ResolverConfig::parse_resolv_conf("search example.com corp.example.com\n");
+		config.use_hosts = false;
+		let resolver = Resolver::new(config);
+
+		assert_eq!(candidates(&resolver, "www"), vec!["www.example.com", "www.corp.example.com", "www"]);
+		assert_eq!(candidates(&resolver, "www.lab"), vec!["www.lab", "www.lab.example.com", "www.lab.corp.example.com"]);
+		assert_eq!(candidates(&resolver, "www.lab."), vec!["www.lab"]);
+
+		let mut config = ResolverConfig::parse_resolv_conf("search example.com\noptions ndots:2\n");
+		config.use_hosts = false;
+		let resolver = Resolver::new(config);
+
+		assert_eq!(candidates(&resolver, "www.lab"), vec!["www.lab.example.com", "www.lab"]);
+		assert_eq!(candidates(&resolver, "www.lab.net"), vec!["www.lab.net", "www.lab.net.example.com"]);
+	}
+
+	#[test]
+	fn hosts_take_priority_over_dns() {
+		let hosts = parse_hosts("192.0.2.8 gateway.example gw\n2001:db8::8 gateway.example\n");
+		// no nameservers, anything not answered locally fails
+		let config = ResolverConfig { nameservers: Vec::new(), ..ResolverConfig::default() };
+		let resolver = Resolver::with_hosts(config, hosts);
+		let cancel = CancelToken::new().unwrap();
+
+		assert_eq!(resolver.lookup("Gateway.Example.", &cancel).unwrap(), vec![
+			"192.0.2.8".parse::<IpAddr>().unwrap(),
+			"2001:db8::8".parse().unwrap(),
+		]);
+		assert_eq!(resolver.lookup("gw", &cancel).unwrap(), vec!["192.0.2.8".parse::<IpAddr>().unwrap()]);
+		assert_eq!(resolver.lookup("192.0.2.200", &cancel).unwrap(), vec!["192.0.2.200".parse::<IpAddr>().unwrap()]);
+		assert_eq!(resolver.reverse("2001:db8::8".parse().unwrap(), &cancel).unwrap(), "gateway.example");
+		assert_eq!(resolver.lookup("elsewhere.example", &cancel).err(), Some("no nameservers".to_string()));
+	}
+}
@@ -0,0 +1,149 @@
+use super::*;
+
+use tokio::{
+	io::{
+		AsyncReadExt,
+		AsyncWriteExt
+	},
+	net::{
+		TcpStream,
+		UdpSocket
+	},
+	time::timeout
+};
+
+impl Resolver {
+	/// Same as `lookup`, but all network io goes through the tokio reactor.
+	pub async fn lookup_async(&self, name: &str) -> Result<Vec<IpAddr>, String> {
+		let mut lookup = match self.plan(name) {
+			Plan::Done(addrs) => return Ok(addrs),
+			Plan::Query(l) => l,
+		};
+
+		while let Some((candidate, qtype)) = lookup.next_query() {
+			match self.query_async(&candidate, qtype).await {
+				Ok(records) => lookup.answered(records, qtype),
+				Err(e) => lookup.failed(e),
+			}
+		}
+
+		return lookup.finish();
+	}
+
+	async fn query_async(&self, name: &str, qtype: u16) -> Result<Vec<Record>, String> {
+		let mut query = Query::new(&self.config, name, qtype)?;
+		while let Some(server) = query.next_server() {
+			let mut response = match timeout(self.config.timeout, exchange_udp(server, &query.request)).await {
+				Ok(Ok(r)) => r,
+				Ok(Err(e)) => {
+					query.failed(server, e);
+					continue;
+				}
+				Err(_) => {
+					query.failed(server, "dns query timed out".to_string());
+					continue;
+				}
+			};
+
+			if Query::truncated(&response) {
+				response = match timeout(self.config.timeout, exchange_tcp(server, &query.request)).await {
+					Ok(Ok(r)) => r,
+					Ok(Err(e)) => {
+						query.failed(server, e);
+						continue;
+					}
+					Err(_) => {
+						query.failed(server, "dns query timed out".to_string());
+						continue;
+					}
+				};
+			}
+
+			if let Some(result) = query.response(server, &response) {
+				return result;
+			}
+		}
+
+		return query.finish();
+	}
+}
+
+async fn exchange_udp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>, String> {
+	let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+	let socket = UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+	socket.connect(server).await.map_err(|e| e.to_string())?;
+	socket.send(request).await.map_err(|e| e.to_string())?;
+
+	let mut buffer = [0; 1232];
+	loop {
+		let bytes = socket.recv(&mut buffer).await.map_err(|e| e.to_string())?;
+		// ignore stray datagrams that do not answer our id
+		if bytes >= 2 && buffer[0..2] == request[0..2] {
+			return Ok(Vec::from(&buffer[..bytes]));
+		}
+	}
+}
+
+async fn exchange_tcp(server: SocketAddr, request: &[u8]) -> Result<Vec<u8>, String> {
+	let mut stream = TcpStream::connect(server).await.map_err(|e| e.to_string())?;
+
+	let mut framed = Vec::with_capacity(request.len() + 2);
+	framed.extend_from_slice(&(request.len() as u16).to_be_bytes());
+	framed.extend_from_slice(request);
+	stream.write_all(&framed).await.map_err(|e| e.to_string())?;
+
+	let mut len = [0; 2];
+	stream.read_exact(&mut len).await.map_err(|e| e.to_string())?;
+	let mut response = vec![0; u16::from_be_bytes(len) as usize];
+	stream.read_exact(&mut response).await.map_err(|e| e.to_string())?;
+
+	return Ok(response);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dns::tests::{
+		answer_addresses,
+		resolver,
+		response,
+		udp_server,
+		RCODE_SERVFAIL
+	};
+
+	#[tokio::test]
+	async fn lookup_async() {
+		let (server, handle) = udp_server(2, answer_addresses);
+
+		let addrs = resolver(vec![server], Duration::from_secs(2)).lookup_async("host.example").await.unwrap();
+		handle.join().unwrap();
+
+		assert_eq!(addrs, vec![
+			IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+			IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+		]);
+	}
+
+	#[tokio::test]
+	async fn servfail_and_nxdomain() {
+		let (failing, failing_handle) = udp_server(1, |request| vec![response(request, RCODE_SERVFAIL, false, &[])]);
+		let (working, working_handle) = udp_server(1, |request| vec![response(request, RCODE_NXDOMAIN, false, &[])]);
+
+		let result = resolver(vec![failing, working], Duration::from_secs(2)).query_async("missing.example", TYPE_A).await;
+		failing_handle.join().unwrap();
+		working_handle.join().unwrap();
+
+		assert!(result.is_err());
+	}
+
+	#[tokio::test]
+	async fn timeout() {
+		let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+		let started = Instant::now();
+		let result = resolver(vec![silent.local_addr().unwrap()], Duration::from_millis(100)).lookup_async("host.example").await;
+
+		assert_eq!(result.err(), Some("dns query timed out".to_string()));
+		assert!(started.elapsed() < Duration::from_secs(1));
+	}
+}
@@ -1,27 +1,85 @@
 use logger::prelude::*;
+use cancel::CancelToken;
 
-use std::net::{
-	ToSocketAddrs,
-	IpAddr,
+use std::{
+	net::IpAddr,
+	str::FromStr,
 };
 
-pub fn dns_lookup<S: Into<String>>(url: S) -> Result<IpAddr, String> {
+pub mod cancel;
+pub mod dns;
+pub mod rdns;
+
+/// Strips an optional `:port` suffix, e.g. `example.com:0` or `[::1]:0`.
+fn strip_port(url: &str) -> &str {
+	if let Some(rest) = url.strip_prefix('[') {
+		return rest.split(']').next().unwrap_or(rest);
+	}
+	
+	return match url.rsplit_once(':') {
+		Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+		_ => url,
+	};
+}
+
+/// Resolves `url` to its first address, giving up as soon as `cancel` fires.
+pub fn dns_lookup<S: Into<String>>(url: S, cancel: &CancelToken) -> Result<IpAddr, String> {
 	let url: String = url.into();
-	let mut iter = match url.to_socket_addrs() {
-		Ok(i) => i,
+	let host = strip_port(&url);
+	if let Ok(addr) = IpAddr::from_str(host) {
+		return Ok(addr);
+	}
+	
+	let addrs = match dns::resolver().lookup(host, cancel) {
+		Ok(a) => a,
 		Err(e) => {
-			error!(desc = e.to_string(), "could not resolve dns");
+			error!(desc = e, "could not resolve dns");
 			return Err("could not resolve dns".to_string());
 		}
 	};
 	
-	let addr = match iter.next() {
-		Some(a) => a,
+	let addr = match addrs.first() {
+		Some(a) => *a,
 		None => {
 			error!("could not resolve dns");
 			return Err("could not resolve dns".to_string());
 		}
 	};
 	
-	return Ok(addr.ip());
+	return Ok(addr);
+}
+
+#[cfg(feature = "tokio")]
+pub async fn dns_lookup_async<S: Into<String>>(url: S) -> Result<IpAddr, String> {
+	let url: String = url.into();
+	let host = strip_port(&url);
+	if let Ok(addr) = IpAddr::from_str(host) {
+		return Ok(addr);
+	}
+	
+	let addrs = match dns::resolver().lookup_async(host).await {
+		Ok(a) => a,
+		Err(e) => {
+			error!(desc = e, "could not resolve dns");
+			return Err("could not resolve dns".to_string());
+		}
+	};
+	
+	return match addrs.first() {
+		Some(a) => Ok(*a),
+		None => {
+			error!("could not resolve dns");
+			Err("could not resolve dns".to_string())
+		}
+	};
+}
+
+pub fn reverse_lookup(addr: IpAddr, cancel: &CancelToken) -> Result<String, String> {
+	return match dns::resolver().reverse(addr, cancel) {
+		Ok(name) => Ok(name),
+		Err(e) => {
+			debug!(addr = addr.to_string(), desc = e, "reverse lookup failed");
+			Err("could not resolve ptr".to_string())
+		}
+	};
 }
\ No newline at end of file
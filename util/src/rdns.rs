@@ -0,0 +1,91 @@
+use logger::prelude::*;
+use crate::cancel::CancelToken;
+
+use std::{
+	collections::HashMap,
+	net::IpAddr,
+	sync::{
+		mpsc::{
+			channel,
+			Sender
+		},
+		Arc,
+		Mutex,
+	},
+};
+
+#[derive(Clone)]
+enum Entry {
+	Pending,
+	Resolved(Option<String>),
+}
+
+/// Caching PTR resolver. Lookups never wait: a new address is queued for a
+/// single background worker and shows up bare until its name is in the
+/// cache, so a slow server or a flood of new hops never holds up output.
+#[derive(Clone)]
+pub struct ReverseResolver {
+	cache: Arc<Mutex<HashMap<IpAddr, Entry>>>,
+	queue: Sender<IpAddr>,
+}
+
+impl ReverseResolver {
+	/// Lookups still queued or running when `cancel` fires are abandoned.
+	pub fn new(cancel: CancelToken) -> Self {
+		let cache = Arc::new(Mutex::new(HashMap::new()));
+		let (queue, addrs) = channel::<IpAddr>();
+
+		let worker_cache = Arc::clone(&cache);
+		std::thread::spawn(move || {
+			// ends once every clone of the resolver is gone
+			for addr in addrs {
+				if cancel.is_cancelled() {
+					return;
+				}
+
+				let name = crate::reverse_lookup(addr, &cancel).ok();
+				debug!(addr = addr.to_string(), name, "reverse lookup done");
+				if let Ok(mut c) = worker_cache.lock() {
+					c.insert(addr, Entry::Resolved(name));
+				}
+			}
+		});
+
+		return Self {
+			cache,
+			queue,
+		};
+	}
+
+	/// The cached name of `addr`. An address seen for the first time is
+	/// queued for lookup and gives None until the worker got to it.
+	pub fn lookup(&self, addr: IpAddr) -> Option<String> {
+		let mut cache = match self.cache.lock() {
+			Ok(c) => c,
+			Err(e) => {
+				error!(desc = e.to_string(), "rdns cache poisoned");
+				return None;
+			}
+		};
+
+		return match cache.get(&addr) {
+			Some(Entry::Resolved(name)) => name.clone(),
+			Some(Entry::Pending) => None,
+			None => {
+				cache.insert(addr, Entry::Pending);
+				if self.queue.send(addr).is_err() {
+					debug!(addr = addr.to_string(), "rdns worker is gone");
+				}
+				None
+			}
+		};
+	}
+
+	/// Formats `addr` as `name (addr)` if a PTR name is known, or just the address otherwise.
+	pub fn display(&self, addr: IpAddr) -> String {
+		return match self.lookup(addr) {
+			Some(name) => format!("{} ({})", name, addr),
+			None => addr.to_string(),
+		};
+	}
+}
@@ -6,14 +6,18 @@ use std::{
 		IpAddr,
 		SocketAddr,
 	},
+	os::fd::AsRawFd,
 	str::FromStr,
-	sync::mpsc::Receiver,
 	time::{
 		Duration,
 		Instant
 	}
 };
 
+use util::cancel::{
+	CancelToken,
+	Wait
+};
 use socket2::{
 	Socket,
 	Domain,
@@ -22,6 +26,8 @@ use socket2::{
 };
 
 pub mod prelude;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
 
 #[derive(Default)]
 pub struct WhoIsResponse {
@@ -48,12 +54,13 @@ pub struct WhoIs {
 }
 
 impl WhoIs {
-	pub fn new<S: Into<String>>(addr: S) -> Result<Self, String> {
+	/// Resolves `addr` if it is a hostname, giving up as soon as `cancel` fires.
+	pub fn new<S: Into<String>>(addr: S, cancel: &CancelToken) -> Result<Self, String> {
 		let addr = addr.into();
 		let addr = match IpAddr::from_str(&addr) {
 			Ok(a) => a,
 			Err(e) => {
-				if let Ok(o) = util::dns_lookup(addr + ":0") {
+				if let Ok(o) = util::dns_lookup(addr + ":0", cancel) {
 					o
 				} else {
 					return Err(e.to_string());
@@ -68,8 +75,8 @@ impl WhoIs {
 		});
 	}
 	
-	pub fn get_whois<T>(&mut self, rx: &Receiver<T>) -> Result<WhoIsResponse, String> {
-		let first = self.send_query("whois.iana.org", &rx)?;
+	pub fn get_whois(&mut self, cancel: &CancelToken) -> Result<WhoIsResponse, String> {
+		let first = self.send_query("whois.iana.org", cancel)?;
 		let server = if let Some(s) = first.split_whitespace().find(|s| s.starts_with("whois.")) {
 			s
 		} else {
@@ -85,11 +92,11 @@ impl WhoIs {
 		// 	return Err("invalid response".to_string());
 		// };
 		
-		let result = self.send_query(server, &rx)?;
+		let result = self.send_query(server, cancel)?;
 		return Ok(parse_whois(result));
 	}
 	
-	fn send_query<S: Into<String>, T>(&mut self, server: S, rx: &Receiver<T>) -> Result<String, String> {
+	fn send_query<S: Into<String>>(&mut self, server: S, cancel: &CancelToken) -> Result<String, String> {
 		let mut socket = match Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)) {
 			Ok(s) => s,
 			Err(e) => {
@@ -106,14 +113,39 @@ impl WhoIs {
 		trace!(ttl = socket.ttl().unwrap());
 		
 		let server: String = server.into();
-		let iana_addr = util::dns_lookup(server.clone() + ":0")?;
-		if let Err(e) = socket.connect_timeout(&SocketAddr::new(iana_addr.into(), 43).into(), self.timeout) {
-			error!(desc = e.to_string(), addr = self.addr.to_string(), server = server, "could not connect");
-			return Err("could not connect".to_string());
+		let iana_addr = util::dns_lookup(server.clone() + ":0", cancel)?;
+		
+		// connect without blocking so a cancel does not have to wait for the connect timeout
+		if let Err(e) = socket.set_nonblocking(true) {
+			error!(desc = e.to_string(), "could not set socket nonblocking");
+			return Err("could not set socket nonblocking".to_string());
+		}
+		
+		match socket.connect(&SocketAddr::new(iana_addr, 43).into()) {
+			Ok(()) => {}
+			Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) || e.kind() == std::io::ErrorKind::WouldBlock => {
+				match cancel.wait_writable(socket.as_raw_fd(), Some(self.timeout))? {
+					Wait::Ready => {}
+					Wait::Cancelled => return Err("stop signal".to_string()),
+					Wait::TimedOut => {
+						error!(addr = self.addr.to_string(), server = server, "connect timed out");
+						return Err("could not connect".to_string());
+					}
+				}
+				
+				if let Ok(Some(e)) | Err(e) = socket.take_error() {
+					error!(desc = e.to_string(), addr = self.addr.to_string(), server = server, "could not connect");
+					return Err("could not connect".to_string());
+				}
+			}
+			Err(e) => {
+				error!(desc = e.to_string(), addr = self.addr.to_string(), server = server, "could not connect");
+				return Err("could not connect".to_string());
+			}
 		}
 		
 		let start_time = Instant::now();
-		let bytes = match socket.send(format!("{}\r\n", self.addr.to_string()).as_bytes()) {
+		let bytes = match socket.send(format!("{}\r\n", self.addr).as_bytes()) {
 			Ok(b) => b,
 			Err(e) => {
 				error!(desc = e.to_string(), "Socket::send");
@@ -122,21 +154,19 @@ impl WhoIs {
 		};
 		debug!(bytes_sent = bytes);
 		
-		let mut elapsed = Duration::from_secs(0);
 		let mut result: Vec<u8> = Vec::new();
 		loop {
-			if rx.try_recv().is_ok() {
-				return Err("stop signal".to_string());
-			}
-			
-			if let Err(e) = socket.set_read_timeout(Some(self.timeout - elapsed)) {
-				error!(desc = e.to_string(), "could not set socket read timeout");
-				return Err("could not set socket read timeout".to_string());
+			let remaining = self.timeout.saturating_sub(start_time.elapsed());
+			match cancel.wait_readable(socket.as_raw_fd(), Some(remaining))? {
+				Wait::Ready => {}
+				Wait::Cancelled => return Err("stop signal".to_string()),
+				Wait::TimedOut => return Err("timeout".to_string()),
 			}
 			
 			let mut buffer: [u8; 2048] = [0; 2048];
 			let bytes = match socket.read(&mut buffer) {
 				Ok(b) => b,
+				Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
 				Err(e) => {
 					error!(desc = e.to_string(), "could not read from socket");
 					return Err("could not read from socket".to_string());
@@ -145,11 +175,6 @@ impl WhoIs {
 			
 			debug!(bytes_recv = bytes);
 			
-			elapsed = start_time.elapsed();
-			if elapsed >= self.timeout {
-				return Err("timeout".to_string());
-			}
-			
 			if bytes != 0 {
 				result.extend_from_slice(&buffer[..bytes]);
 				continue;
@@ -0,0 +1,178 @@
+use crate::{
+	parse_whois,
+	WhoIsResponse,
+};
+use logger::prelude::*;
+
+use std::{
+	net::{
+		IpAddr,
+		SocketAddr,
+	},
+	str::FromStr,
+	time::{
+		Duration,
+		Instant
+	}
+};
+
+use tokio::{
+	io::{
+		AsyncReadExt,
+		AsyncWriteExt
+	},
+	net::TcpStream,
+	time::timeout
+};
+
+/// Async counterpart of `crate::WhoIs`. Queries run on the tokio reactor and
+/// are cancelled by dropping the `get_whois` future.
+pub struct WhoIs {
+	addr: IpAddr,
+	timeout: Duration,
+	port: u16,
+}
+
+impl WhoIs {
+	pub async fn new<S: Into<String>>(addr: S) -> Result<Self, String> {
+		let addr = addr.into();
+		let addr = match IpAddr::from_str(&addr) {
+			Ok(a) => a,
+			Err(e) => {
+				if let Ok(o) = util::dns_lookup_async(addr + ":0").await {
+					o
+				} else {
+					return Err(e.to_string());
+				}
+			}
+		};
+		
+		return Ok(Self {
+			timeout: Duration::from_secs(2),
+			port: 43,
+			addr,
+		});
+	}
+	
+	pub async fn get_whois(&mut self) -> Result<WhoIsResponse, String> {
+		let first = self.send_query("whois.iana.org").await?;
+		let server = if let Some(s) = first.split_whitespace().find(|s| s.starts_with("whois.")) {
+			s
+		} else {
+			return Err("invalid response".to_string());
+		};
+		
+		let result = self.send_query(server).await?;
+		return Ok(parse_whois(result));
+	}
+	
+	async fn send_query<S: Into<String>>(&mut self, server: S) -> Result<String, String> {
+		let server: String = server.into();
+		let server_addr = util::dns_lookup_async(server.clone() + ":0").await?;
+		
+		let mut stream = match timeout(self.timeout, TcpStream::connect(SocketAddr::new(server_addr, self.port))).await {
+			Ok(Ok(s)) => s,
+			Ok(Err(e)) => {
+				error!(desc = e.to_string(), addr = self.addr.to_string(), server = server, "could not connect");
+				return Err("could not connect".to_string());
+			}
+			Err(_) => {
+				error!(addr = self.addr.to_string(), server = server, "connect timed out");
+				return Err("could not connect".to_string());
+			}
+		};
+		
+		let start_time = Instant::now();
+		if let Err(e) = stream.write_all(format!("{}\r\n", self.addr).as_bytes()).await {
+			error!(desc = e.to_string(), "TcpStream::write_all");
+			return Err("TcpStream::write_all".to_string());
+		}
+		
+		let mut result: Vec<u8> = Vec::new();
+		let remaining = self.timeout.saturating_sub(start_time.elapsed());
+		match timeout(remaining, stream.read_to_end(&mut result)).await {
+			Ok(Ok(bytes)) => debug!(bytes_recv = bytes),
+			Ok(Err(e)) => {
+				error!(desc = e.to_string(), "could not read from socket");
+				return Err("could not read from socket".to_string());
+			}
+			Err(_) => return Err("timeout".to_string()),
+		}
+		
+		let str = match String::from_utf8(result) {
+			Ok(s) => s,
+			Err(e) => {
+				error!(desc = e.to_string(), "invalid utf8");
+				return Err("invalid utf8".to_string());
+			}
+		};
+		
+		return Ok(str);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use util::dns::ResolverConfig;
+	
+	use tokio::net::{
+		TcpListener,
+		UdpSocket
+	};
+	
+	/// A nameserver answering every A query with 127.0.0.1 and everything else with no records.
+	async fn dns_server() -> SocketAddr {
+		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = socket.local_addr().unwrap();
+		
+		tokio::spawn(async move {
+			let mut buffer = [0; 512];
+			while let Ok((bytes, from)) = socket.recv_from(&mut buffer).await {
+				let question_end = 12 + buffer[12..bytes].iter().position(|b| *b == 0).unwrap() + 5;
+				let is_a = buffer[question_end - 3] == 1;
+				
+				let mut response = Vec::from(&buffer[..2]);
+				response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+				response.extend_from_slice(&buffer[12..question_end]);
+				if is_a {
+					response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+				}
+				socket.send_to(&response, from).await.unwrap();
+			}
+		});
+		
+		return addr;
+	}
+	
+	/// Answers the first query with a referral and the second with a record, checking what was asked.
+	async fn whois_server() -> u16 {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		
+		tokio::spawn(async move {
+			for answer in ["refer:        whois.example.net\n", "inetnum: 192.0.2.0 - 192.0.2.255\nnetname:        TEST-NET-1\n"] {
+				let (mut stream, _) = listener.accept().await.unwrap();
+				
+				let mut query = [0; 64];
+				let bytes = stream.read(&mut query).await.unwrap();
+				assert_eq!(&query[..bytes], b"192.0.2.1\r\n");
+				
+				stream.write_all(answer.as_bytes()).await.unwrap();
+			}
+		});
+		
+		return port;
+	}
+	
+	#[tokio::test]
+	async fn get_whois() {
+		util::dns::init(ResolverConfig { nameservers: vec![dns_server().await], use_hosts: false, ..ResolverConfig::default() }).unwrap();
+		
+		let mut whois = WhoIs::new("192.0.2.1").await.unwrap();
+		whois.port = whois_server().await;
+		
+		let response = whois.get_whois().await.unwrap();
+		assert_eq!(response.netname.as_deref(), Some("TEST-NET-1"));
+	}
+}